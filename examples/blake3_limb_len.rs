@@ -0,0 +1,82 @@
+//! Total vsize of a finalized k=2 BLAKE3 flow for every message limb length,
+//! and the lightest one.
+//!
+//! ```bash
+//! cargo run --release --example blake3_limb_len [x]
+//! ```
+
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, Network, OutPoint, Txid};
+use collidervm_toy::core::{
+    F1_THRESHOLD, F2_THRESHOLD, GreaterThan, LessThan, find_valid_nonce_in_set,
+    flow_id_to_prefix_bytes,
+};
+use collidervm_toy::flow::FlowBuilder;
+use collidervm_toy::flow_set::LowRange;
+use collidervm_toy::hash::Blake3Hash;
+use collidervm_toy::input::Input;
+use collidervm_toy::musig2::{generate_keys, simulate_musig2};
+use collidervm_toy::utils::inner_from;
+use std::str::FromStr;
+
+const B: usize = 16;
+const L: usize = 4;
+
+fn main() {
+    let x = std::env::args()
+        .nth(1)
+        .map(|arg| arg.parse::<u32>().expect("u32 input"))
+        .unwrap_or(114);
+    let x = Input::try_from(x).expect("input in range");
+    let secp = Secp256k1::new();
+    let sk_signers = generate_keys::<2>();
+    let pk_signer: musig2::secp256k1::PublicKey = musig2::KeyAggContext::new(
+        sk_signers.iter().map(|key| key.1).collect::<Vec<_>>(),
+    )
+    .unwrap()
+    .aggregated_pubkey();
+    let receiver_addr =
+        Address::from_str("bcrt1qz3fps2lxvrp5rqj8ucsqrzjx2c3md9gawqr3l6")
+            .unwrap()
+            .require_network(Network::Regtest)
+            .unwrap();
+    let funding_outpoint = OutPoint {
+        txid: Txid::all_zeros(),
+        vout: 0,
+    };
+
+    let mut vsizes = vec![];
+    for limb_len in [4, 5, 6, 7, 8, 10, 12, 16, 20, 24, 29] {
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .hash(Blake3Hash::with_limb_len(limb_len).unwrap())
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+        let (nonce, flow_id) = find_valid_nonce_in_set(
+            &x,
+            &LowRange::new(B, L),
+            builder.collision_hash(),
+        )
+        .unwrap();
+        let mut flow = builder
+            .build(
+                &secp,
+                &inner_from(pk_signer),
+                &funding_outpoint,
+                &200_000,
+                &flow_id_to_prefix_bytes(flow_id, B),
+                &receiver_addr,
+            )
+            .unwrap();
+        let signatures = flow
+            .sighashes()
+            .iter()
+            .map(|sighash| simulate_musig2(&sk_signers, sighash).unwrap())
+            .collect::<Vec<_>>();
+        flow.finalize(&signatures, &x, &nonce).unwrap();
+        println!("limb length {limb_len}: {} vbytes", flow.total_vsize());
+        vsizes.push((flow.total_vsize(), limb_len));
+    }
+    let (vsize, limb_len) = vsizes.into_iter().min().unwrap();
+    println!("lightest flow: limb length {limb_len}, {vsize} vbytes");
+}
@@ -0,0 +1,57 @@
+//! Per-flow build time of a BLAKE3 step script, compiling the BLAKE3 compute
+//! script for every flow as before the cache, and taking it from the cache.
+//!
+//! ```bash
+//! cargo run --release --example step_script_build [flows]
+//! ```
+
+use bitcoin::PublicKey;
+use bitcoin_script_stack::optimizer;
+use bitvm::hash::blake3::blake3_compute_script_with_limb;
+use collidervm_toy::core::{
+    DEFAULT_BLAKE3_LIMB_LEN, F1_THRESHOLD, FlowId, GreaterThan,
+    build_script_step_blake3_locked, flow_id_to_prefix_bytes,
+};
+use std::time::Instant;
+
+fn main() {
+    let flows = std::env::args()
+        .nth(1)
+        .map(|arg| arg.parse::<u32>().expect("number of flows"))
+        .unwrap_or(4);
+    let secp = secp256k1::Secp256k1::new();
+    let secret_key = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+    let pubkey = PublicKey::new(secret_key.public_key(&secp));
+    let b_bits = 16;
+    let build = |flow_id: FlowId| {
+        build_script_step_blake3_locked(
+            &pubkey,
+            &[&GreaterThan(F1_THRESHOLD)],
+            &flow_id_to_prefix_bytes(flow_id, b_bits),
+            b_bits,
+        )
+    };
+    // Fill the cache, x = 4 bytes and r = 8 bytes
+    build(0);
+
+    let start = Instant::now();
+    for flow_id in 0..flows {
+        optimizer::optimize(
+            blake3_compute_script_with_limb(12, DEFAULT_BLAKE3_LIMB_LEN)
+                .compile(),
+        );
+        build(flow_id.into());
+    }
+    let before = start.elapsed() / flows;
+
+    let start = Instant::now();
+    for flow_id in 0..flows {
+        build(flow_id.into());
+    }
+    let after = start.elapsed() / flows;
+
+    println!(
+        "per-flow step script build over {flows} flows: {before:?} \
+         compiling the compute script, {after:?} with the cache"
+    );
+}
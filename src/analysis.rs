@@ -0,0 +1,313 @@
+//! Resource usage of ColliderVM locking scripts.
+//!
+//! [`analyze_script`] executes a locking script on its witness with the
+//! [`Interpreter`] and measures it against the Tapscript limits, so tests and
+//! the demo binary can tell how close a step is to being unspendable or
+//! non-standard.
+
+use crate::interpreter::{
+    ExecError, Interpreter, MAX_ELEMENT_SIZE, MAX_STACK_SIZE, Op,
+    tapscript_spend,
+};
+use bitcoin::opcodes::all::OP_PUSHNUM_16;
+use bitcoin::script::Script;
+use bitcoin::{Transaction, TxOut, Witness};
+use std::fmt;
+
+/// Heaviest transaction relayed by default (`MAX_STANDARD_TX_WEIGHT`).
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+
+/// Size of the control block of a single-leaf Taproot tree, as built by
+/// [`crate::transactions::lock_spend_info`].
+const SINGLE_LEAF_CONTROL_BLOCK_LEN: usize = 33;
+
+/// A limit exceeded by a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// More than [`MAX_STACK_SIZE`] elements on the stack and altstack.
+    StackSize(usize),
+    /// A push of more than [`MAX_ELEMENT_SIZE`] bytes at this byte offset.
+    PushSize { offset: usize, len: usize },
+    /// A stack element of more than [`MAX_ELEMENT_SIZE`] bytes.
+    ElementSize(usize),
+    /// A spend heavier than [`MAX_STANDARD_TX_WEIGHT`].
+    Weight(usize),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::StackSize(depth) => write!(
+                f,
+                "{depth} stack elements, consensus allows {MAX_STACK_SIZE}"
+            ),
+            Violation::PushSize { offset, len } => write!(
+                f,
+                "push of {len} bytes at byte {offset}, consensus allows \
+                 {MAX_ELEMENT_SIZE}"
+            ),
+            Violation::ElementSize(len) => write!(
+                f,
+                "stack element of {len} bytes, consensus allows \
+                 {MAX_ELEMENT_SIZE}"
+            ),
+            Violation::Weight(weight) => write!(
+                f,
+                "weight of {weight} WU, standardness allows \
+                 {MAX_STANDARD_TX_WEIGHT}"
+            ),
+        }
+    }
+}
+
+/// Resources used by one execution of a locking script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptReport {
+    /// Script size in bytes.
+    pub size: usize,
+    /// Number of non-push opcodes.
+    pub opcode_count: usize,
+    /// Deepest main stack.
+    pub max_stack_depth: usize,
+    /// Deepest altstack.
+    pub max_altstack_depth: usize,
+    /// Most elements on both stacks at once, the quantity limited to
+    /// [`MAX_STACK_SIZE`].
+    pub max_total_depth: usize,
+    /// Largest stack element in bytes, witness included.
+    pub largest_element: usize,
+    /// Weight of the whole spending transaction for [`analyze_spend`], of the
+    /// witness alone for [`analyze_script`].
+    pub weight: usize,
+    /// Outcome of the execution, limits aside.
+    pub result: Result<(), ExecError>,
+    pub violations: Vec<Violation>,
+}
+
+impl ScriptReport {
+    /// Whether the script succeeded within every limit.
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok() && self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for ScriptReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "size            {} bytes", self.size)?;
+        writeln!(f, "opcodes         {}", self.opcode_count)?;
+        writeln!(
+            f,
+            "max stack       {} (altstack {}, total {})",
+            self.max_stack_depth, self.max_altstack_depth, self.max_total_depth
+        )?;
+        writeln!(f, "largest element {} bytes", self.largest_element)?;
+        writeln!(f, "weight          {} WU", self.weight)?;
+        match &self.result {
+            Ok(()) => write!(f, "result          success")?,
+            Err(err) => write!(f, "result          failed: {err}")?,
+        }
+        for violation in &self.violations {
+            write!(f, "\nviolation       {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Executes `script` on `witness` (the elements below the script in the
+/// witness stack, top last). The weight is the one of the witness with the
+/// script and a single-leaf control block.
+pub fn analyze_script(script: &Script, witness: &[Vec<u8>]) -> ScriptReport {
+    let mut elements = witness.to_vec();
+    elements.push(script.to_bytes());
+    elements.push(vec![0; SINGLE_LEAF_CONTROL_BLOCK_LEN]);
+    let weight = Witness::from_slice(&elements).size();
+    let interpreter = Interpreter::new(script, witness.to_vec());
+    analyze(script, witness, weight, interpreter)
+}
+
+/// Executes the tapscript spent by input `input` of `tx`, verifying its
+/// signatures against `prevouts`, the outputs spent by `tx`.
+pub fn analyze_spend(
+    tx: &Transaction,
+    input: usize,
+    prevouts: &[TxOut],
+) -> Result<ScriptReport, String> {
+    let (script, stack) = tapscript_spend(tx, input)?;
+    let interpreter = Interpreter::new(&script, stack.clone())
+        .map(|interpreter| interpreter.with_spend(tx, input, prevouts));
+    let weight = tx.weight().to_wu() as usize;
+    Ok(analyze(&script, &stack, weight, interpreter))
+}
+
+fn analyze(
+    script: &Script,
+    witness: &[Vec<u8>],
+    weight: usize,
+    interpreter: Result<Interpreter, ExecError>,
+) -> ScriptReport {
+    let mut report = ScriptReport {
+        size: script.len(),
+        opcode_count: 0,
+        max_stack_depth: witness.len(),
+        max_altstack_depth: 0,
+        max_total_depth: witness.len(),
+        largest_element: witness.iter().map(Vec::len).max().unwrap_or(0),
+        weight,
+        result: Ok(()),
+        violations: vec![],
+    };
+
+    let result = interpreter.map(Interpreter::without_limits).and_then(
+        |mut interpreter| {
+            for (offset, op) in interpreter.ops() {
+                match op {
+                    Op::Push(data) if data.len() > MAX_ELEMENT_SIZE => {
+                        report.violations.push(Violation::PushSize {
+                            offset: *offset,
+                            len: data.len(),
+                        });
+                    }
+                    Op::Opcode(opcode)
+                        if opcode.to_u8() > OP_PUSHNUM_16.to_u8() =>
+                    {
+                        report.opcode_count += 1;
+                    }
+                    _ => {}
+                }
+            }
+            while !interpreter.is_done() {
+                interpreter.step()?;
+                let (stack, altstack) =
+                    (interpreter.stack(), interpreter.altstack());
+                report.max_stack_depth =
+                    report.max_stack_depth.max(stack.len());
+                report.max_altstack_depth =
+                    report.max_altstack_depth.max(altstack.len());
+                report.max_total_depth =
+                    report.max_total_depth.max(stack.len() + altstack.len());
+                // Opcodes only create elements on top of either stack
+                for top in stack.last().into_iter().chain(altstack.last()) {
+                    report.largest_element =
+                        report.largest_element.max(top.len());
+                }
+            }
+            interpreter.finish()
+        },
+    );
+    report.result = result;
+
+    if report.max_total_depth > MAX_STACK_SIZE {
+        report
+            .violations
+            .push(Violation::StackSize(report.max_total_depth));
+    }
+    if report.largest_element > MAX_ELEMENT_SIZE {
+        report
+            .violations
+            .push(Violation::ElementSize(report.largest_element));
+    }
+    if report.weight > MAX_STANDARD_TX_WEIGHT {
+        report.violations.push(Violation::Weight(report.weight));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        GreaterThan, LessThan, SubFunction, build_script_step_locked,
+        flow_id_to_prefix_bytes, prefix_mask,
+    };
+    use crate::hash::{CollisionHash, Sha256Hash};
+    use crate::input::{Input, InputLayout};
+    use bitcoin::opcodes::all::{OP_DROP, OP_PUSHNUM_1};
+    use bitcoin::script::{Builder, PushBytesBuf};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+    #[test]
+    fn test_sha256_step_report() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let pubkey = bitcoin::PublicKey::new(secret_key.public_key(&secp));
+
+        let (b_bits, x, nonce) = (16, Input::try_from(114).unwrap(), 0);
+        let flow_id = Sha256Hash.flow_id(&x, nonce) & prefix_mask(b_bits);
+        let prefix = flow_id_to_prefix_bytes(flow_id, b_bits);
+        let sub_functions: [&dyn SubFunction; 2] =
+            [&GreaterThan(100), &LessThan(200)];
+        let lock = build_script_step_locked(
+            &pubkey,
+            &Sha256Hash,
+            &InputLayout::default(),
+            &sub_functions,
+            &prefix,
+            b_bits,
+        );
+        let mut witness = Sha256Hash.witness(&x, nonce, b_bits);
+        witness.push(vec![1; 64]);
+
+        let report = analyze_script(&lock, &witness);
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.size, lock.len());
+        assert!(report.opcode_count > 10);
+        assert!(report.max_altstack_depth >= 1);
+        // The signature, the SHA256 digest is 32 bytes
+        assert_eq!(report.largest_element, 64);
+        assert!(report.weight > lock.len());
+
+        // Wrong nonce: the prefix check fails, the resources are still
+        // measured up to the failing opcode
+        let mut witness = Sha256Hash.witness(&x, nonce + 1, b_bits);
+        witness.push(vec![1; 64]);
+        let report = analyze_script(&lock, &witness);
+        assert!(report.result.is_err());
+        assert!(report.violations.is_empty());
+    }
+
+    #[test]
+    fn test_limit_violations() {
+        let mut builder = Builder::new();
+        for _ in 0..MAX_STACK_SIZE {
+            builder = builder.push_opcode(OP_PUSHNUM_1);
+        }
+        for _ in 0..MAX_STACK_SIZE {
+            builder = builder.push_opcode(OP_DROP);
+        }
+        let report = analyze_script(&builder.into_script(), &[vec![1]]);
+        assert!(report.result.is_ok());
+        assert_eq!(report.max_total_depth, MAX_STACK_SIZE + 1);
+        assert_eq!(
+            report.violations,
+            [Violation::StackSize(MAX_STACK_SIZE + 1)]
+        );
+
+        let big_push =
+            PushBytesBuf::try_from(vec![0; MAX_ELEMENT_SIZE + 1]).unwrap();
+        let script = Builder::new()
+            .push_slice(big_push)
+            .push_opcode(OP_DROP)
+            .into_script();
+        let report = analyze_script(&script, &[vec![1]]);
+        assert_eq!(
+            report.violations,
+            [
+                Violation::PushSize {
+                    offset: 0,
+                    len: MAX_ELEMENT_SIZE + 1
+                },
+                Violation::ElementSize(MAX_ELEMENT_SIZE + 1)
+            ]
+        );
+
+        let report = analyze_script(
+            &Builder::new().into_script(),
+            &[vec![1; MAX_STANDARD_TX_WEIGHT]],
+        );
+        assert!(
+            report
+                .violations
+                .contains(&Violation::Weight(report.weight))
+        );
+    }
+}
@@ -1,6 +1,9 @@
+use crate::flow_set::{FlowSet, LowRange};
+use crate::hash::{Blake3Hash, CollisionHash};
+use crate::input::{Input, InputLayout};
 use crate::utils::NonceSearchProgress;
 use bitcoin::{
-    Amount, PublicKey, XOnlyPublicKey,
+    Amount, PublicKey, TapSighashType, XOnlyPublicKey,
     blockdata::script::{Builder, ScriptBuf},
     opcodes::{self, OP_TRUE},
 };
@@ -13,6 +16,12 @@ use blake3::Hasher;
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use secp256k1::Message;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
 use std::time::{Duration, Instant};
 
 /// F1 threshold: x must be > 100
@@ -32,17 +41,39 @@ pub fn create_toy_sighash_message(
     Message::from_digest(digest.to_byte_array())
 }
 
-/// Calculate H(x||nonce)|_B => flow_id
-pub fn calculate_flow_id(input: u32, nonce: u64) -> u32 {
+/// Flow identifier `d`: a little-endian hash prefix of up to
+/// [`MAX_B_BITS`] bits.
+pub type FlowId = u128;
+
+/// Largest supported hash prefix length `B`.
+pub const MAX_B_BITS: usize = FlowId::BITS as usize;
+
+/// Calculate H(x||nonce) => flow_id, to be masked down to its first B bits
+pub fn calculate_flow_id(input: u32, nonce: u64) -> FlowId {
     let mut hasher = Hasher::new();
     hasher.update(&input.to_le_bytes());
     hasher.update(&nonce.to_le_bytes());
     let hash = hasher.finalize();
 
-    let mut fourb = [0u8; 4];
-    fourb.copy_from_slice(&hash.as_bytes()[0..4]);
+    flow_id_from_hash(hash.as_bytes())
+}
 
-    u32::from_le_bytes(fourb)
+/// Read the first [`MAX_B_BITS`] bits of a digest as a little-endian flow ID
+pub(crate) fn flow_id_from_hash(hash: &[u8]) -> FlowId {
+    let mut prefix = [0u8; MAX_B_BITS / 8];
+    prefix.copy_from_slice(&hash[0..MAX_B_BITS / 8]);
+
+    FlowId::from_le_bytes(prefix)
+}
+
+/// Mask keeping the first `b_bits` bits of a flow ID, i.e. `H(x, r)|_B`
+pub fn prefix_mask(b_bits: usize) -> FlowId {
+    assert!(b_bits <= MAX_B_BITS, "b_bits must be <= {MAX_B_BITS}");
+    if b_bits == MAX_B_BITS {
+        FlowId::MAX
+    } else {
+        (1 << b_bits) - 1
+    }
 }
 
 /// Finds a valid nonce `r` for a given input `x` such that `H(x, r)|_B` falls within the set `D`. (Off-chain logic)
@@ -51,18 +82,33 @@ pub fn calculate_flow_id(input: u32, nonce: u64) -> u32 {
 /// The expected number of hash attempts is `2^(B-L)`.
 ///
 /// # Arguments
-/// * `input` - The input value `x`.
+/// * `input` - The input value `x`, below `2^31` so that scripts can read it
+///   as a script number (see [`Input::new`]).
 /// * `b_bits` - The hash prefix length `B`.
-/// * `l_bits` - The parameter `L` defining the size of set `D`.
+/// * `l_bits` - The parameter `L` defining the size of set `D = [0, 2^L)`.
 ///
 /// # Returns
-/// * `Ok((u64, u32))` - A tuple containing the found nonce `r` and the corresponding flow ID `d`.
-/// * `Err(String)` - An error if a nonce cannot be found (e.g., due to overflow or excessive attempts).
+/// * `Ok((u64, FlowId))` - A tuple containing the found nonce `r` and the corresponding flow ID `d`.
+/// * `Err(String)` - An error if `x >= 2^31` or a nonce cannot be found (e.g., due to overflow or excessive attempts).
 pub fn find_valid_nonce(
     input: u32,
     b_bits: usize,
     l_bits: usize,
-) -> Result<(u64, u32), String> {
+) -> Result<(u64, FlowId), String> {
+    find_valid_nonce_in_set(
+        &Input::try_from(input)?,
+        &LowRange::new(b_bits, l_bits),
+        &Blake3Hash::default(),
+    )
+}
+
+/// Same as [`find_valid_nonce`] for any input, set `D` and collision hash.
+pub fn find_valid_nonce_in_set(
+    input: &Input,
+    flow_set: &dyn FlowSet,
+    hash: &dyn CollisionHash,
+) -> Result<(u64, FlowId), String> {
+    let (b_bits, l_bits) = (flow_set.b_bits(), flow_set.l_bits());
     let mut nonce: u64 = 0;
 
     // Calculate expected number of attempts (2^(B-L)) for progress reporting
@@ -80,23 +126,17 @@ pub fn find_valid_nonce(
 
     let mut progress = NonceSearchProgress::new(expected_attempts);
 
-    let max_flow_id = (1u64 << l_bits) as u32;
-    let mask_b = if b_bits >= 32 {
-        u32::MAX
-    } else {
-        (1u32 << b_bits) - 1
-    };
+    let mask_b = prefix_mask(b_bits);
 
     loop {
         // Always get the prefix and hash
-        let hash = calculate_flow_id(input, nonce);
-        let prefix_b = hash & mask_b;
-        if prefix_b < max_flow_id {
+        let prefix_b = hash.flow_id(input, nonce) & mask_b;
+        if flow_set.contains(prefix_b) {
             // Found a nonce `r` such that H(x, r)|_B = d ∈ D
             progress.success(prefix_b, nonce);
             return Ok((nonce, prefix_b));
         } else {
-            // Hash prefix was outside the valid range [0, 2^L - 1], try next nonce
+            // Hash prefix was outside of D, try next nonce
             progress.update(nonce);
 
             // Increment nonce, checking for overflow
@@ -116,13 +156,110 @@ pub fn find_valid_nonce(
     }
 }
 
-/// Convert flow_id => little-endian prefix of length B/8
-pub fn flow_id_to_prefix_bytes(flow_id: u32, b_bits: usize) -> Vec<u8> {
-    assert!(b_bits <= 32);
-    assert_eq!(b_bits % 8, 0, "b_bits must be multiple of 8");
-    let prefix_len = b_bits / 8;
-    let le4 = flow_id.to_le_bytes();
-    let flow_id_prefix_bytes = le4[..prefix_len].to_vec();
+/// Multi-threaded variant of [`find_valid_nonce_in_set`].
+///
+/// Worker `t` of `num_threads` tries the nonces `t, t + num_threads, …` and
+/// stops as soon as it passes the smallest valid nonce found so far by any
+/// worker. The result is therefore the smallest valid nonce, the same one
+/// [`find_valid_nonce_in_set`] returns, whatever the thread count. Progress
+/// of all workers is aggregated into a single [`NonceSearchProgress`].
+pub fn find_valid_nonce_parallel(
+    input: &Input,
+    flow_set: &dyn FlowSet,
+    hash: &dyn CollisionHash,
+    num_threads: usize,
+) -> Result<(u64, FlowId), String> {
+    // Attempts are reported to the shared counter in batches to keep the
+    // workers off the same cache line.
+    const REPORT_BATCH: u64 = 1_024;
+
+    let (b_bits, l_bits) = (flow_set.b_bits(), flow_set.l_bits());
+    let num_threads = num_threads.max(1);
+
+    let expected_attempts: u64 = 1u64
+        .checked_shl((b_bits.saturating_sub(l_bits)) as u32)
+        .unwrap_or(u64::MAX);
+
+    println!(
+        "Finding valid nonce (L={}, B={}, threads={})... (Expected work: ~2^{} = {} hashes)",
+        l_bits,
+        b_bits,
+        num_threads,
+        b_bits.saturating_sub(l_bits),
+        expected_attempts
+    );
+
+    let mut progress = NonceSearchProgress::new(expected_attempts);
+
+    let mask_b = prefix_mask(b_bits);
+    // Same safety break as the sequential search
+    let max_nonce = expected_attempts.saturating_mul(100);
+
+    let best_nonce = AtomicU64::new(u64::MAX);
+    let attempts = AtomicU64::new(0);
+    let finished_workers = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for worker in 0..num_threads {
+            let (best_nonce, attempts, finished_workers) =
+                (&best_nonce, &attempts, &finished_workers);
+            scope.spawn(move || {
+                let mut nonce = worker as u64;
+                let mut pending = 0u64;
+                while nonce <= max_nonce
+                    && nonce < best_nonce.load(Ordering::Relaxed)
+                {
+                    let prefix_b = hash.flow_id(input, nonce) & mask_b;
+                    if flow_set.contains(prefix_b) {
+                        best_nonce.fetch_min(nonce, Ordering::Relaxed);
+                        break;
+                    }
+                    pending += 1;
+                    if pending == REPORT_BATCH {
+                        attempts.fetch_add(pending, Ordering::Relaxed);
+                        pending = 0;
+                    }
+                    match nonce.checked_add(num_threads as u64) {
+                        Some(next) => nonce = next,
+                        None => break,
+                    }
+                }
+                attempts.fetch_add(pending, Ordering::Relaxed);
+                finished_workers.fetch_add(1, Ordering::Release);
+            });
+        }
+
+        while finished_workers.load(Ordering::Acquire) < num_threads {
+            progress.update(attempts.load(Ordering::Relaxed));
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+
+    match best_nonce.into_inner() {
+        u64::MAX => {
+            progress.failure();
+            Err(format!(
+                "Could not find a valid nonce after {} attempts (expected ~{expected_attempts})",
+                attempts.into_inner()
+            ))
+        }
+        nonce => {
+            let flow_id = hash.flow_id(input, nonce) & mask_b;
+            progress.success(flow_id, nonce);
+            Ok((nonce, flow_id))
+        }
+    }
+}
+
+/// Convert flow_id => little-endian prefix of length ceil(B/8)
+///
+/// When B is not a multiple of 8 the last byte only carries its low `B % 8`
+/// bits, the remaining bits of `flow_id` must be zero (see [`prefix_mask`]).
+pub fn flow_id_to_prefix_bytes(flow_id: FlowId, b_bits: usize) -> Vec<u8> {
+    assert_eq!(flow_id & !prefix_mask(b_bits), 0, "flow_id wider than B");
+    let prefix_len = b_bits.div_ceil(8);
+    let le_bytes = flow_id.to_le_bytes();
+    let flow_id_prefix_bytes = le_bytes[..prefix_len].to_vec();
     // Transform to nibbles
     // For example: [0x12, 0x34] => [0x1, 0x2, 0x3, 0x4]
     // Or: [0x0d, 0x00] => [0x0, 0xd, 0x0, 0x0]
@@ -137,7 +274,7 @@ pub fn flow_id_to_prefix_bytes(flow_id: u32, b_bits: usize) -> Vec<u8> {
 }
 
 /// Helper: combine scripts (by just concatenating the raw bytes).
-fn combine_scripts(fragments: &[ScriptBuf]) -> ScriptBuf {
+pub(crate) fn combine_scripts(fragments: &[ScriptBuf]) -> ScriptBuf {
     let mut combined = Vec::new();
     for frag in fragments {
         combined.extend(frag.to_bytes());
@@ -145,6 +282,75 @@ fn combine_scripts(fragments: &[ScriptBuf]) -> ScriptBuf {
     ScriptBuf::from_bytes(combined)
 }
 
+/// What a part of a step locking script checks, see
+/// [`build_script_step_segments`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    /// `<pk> OP_CHECKSIGVERIFY`
+    SignatureCheck,
+    /// Expansion of a compact witness, see
+    /// [`CollisionHash::expand_witness_script`]
+    ExpandWitness,
+    /// Copy of number field `i` of `x` out of the witness
+    ReconstructX(usize),
+    /// Check of the `i`-th sub-function of the step, e.g. a threshold
+    SubFunction(usize),
+    /// Computation of `H(x, r)`
+    Hash,
+    /// Removal of the digest part beyond the prefix
+    Drop,
+    /// Comparison of `H(x, r)|_B` with the flow prefix
+    PrefixCompare,
+    /// The final `OP_TRUE`
+    Success,
+}
+
+impl std::fmt::Display for SegmentKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SegmentKind::SignatureCheck => write!(f, "signature check"),
+            SegmentKind::ExpandWitness => write!(f, "expand witness"),
+            SegmentKind::ReconstructX(field) => {
+                write!(f, "reconstruct x (field {field})")
+            }
+            SegmentKind::SubFunction(i) => write!(f, "sub-function {i}"),
+            SegmentKind::Hash => write!(f, "hash"),
+            SegmentKind::Drop => write!(f, "drop"),
+            SegmentKind::PrefixCompare => write!(f, "prefix compare"),
+            SegmentKind::Success => write!(f, "success"),
+        }
+    }
+}
+
+/// The byte range of a script covered by one segment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScriptSegment {
+    pub kind: SegmentKind,
+    pub range: Range<usize>,
+}
+
+/// Helper: combine labeled fragments, returning the byte range of each.
+pub(crate) fn combine_segments(
+    fragments: &[(SegmentKind, ScriptBuf)],
+) -> (ScriptBuf, Vec<ScriptSegment>) {
+    let mut segments = Vec::with_capacity(fragments.len());
+    let mut start = 0;
+    for (kind, fragment) in fragments {
+        let end = start + fragment.len();
+        segments.push(ScriptSegment {
+            kind: *kind,
+            range: start..end,
+        });
+        start = end;
+    }
+    let scripts = fragments
+        .iter()
+        .map(|(_, fragment)| fragment.clone())
+        .collect::<Vec<_>>();
+    (combine_scripts(&scripts), segments)
+}
+
 /// A small helper script that pushes `prefix_data` and does OP_EQUALVERIFY
 /// This is used to check if the top of the stack matches the prefix
 /// For example, if the content of the stack is:
@@ -153,11 +359,35 @@ fn combine_scripts(fragments: &[ScriptBuf]) -> ScriptBuf {
 /// We need to take care of the fact that the prefix is now in nibbles.
 /// Also the ordering of elements on the stack.
 /// We need to push the prefix in reverse order to the stack.
-fn build_prefix_equalverify(prefix_data: &[u8]) -> ScriptBuf {
+///
+/// When B is not a multiple of 8, the nibbles of the last byte only partially
+/// belong to `H(x, r)|_B`: bits above B are masked off before the comparison
+/// and a nibble without any bit below B is dropped unchecked.
+pub(crate) fn build_prefix_equalverify(
+    prefix_data: &[u8],
+    b_bits: usize,
+) -> ScriptBuf {
+    assert_eq!(
+        prefix_data.len(),
+        2 * b_bits.div_ceil(8),
+        "prefix must hold the nibbles of ceil(B/8) bytes"
+    );
     let mut b = Builder::new();
 
     // Check each nibble individually, pushing in reverse order to match stack evaluation
-    for &nibble in prefix_data.iter().rev() {
+    for (i, &nibble) in prefix_data.iter().enumerate().rev() {
+        // Nibble i holds bits [lowest_bit, lowest_bit + 4) of the flow ID:
+        // the high nibble of byte i/2 comes first.
+        let lowest_bit = 8 * (i / 2) + if i % 2 == 0 { 4 } else { 0 };
+        let checked_bits = b_bits.saturating_sub(lowest_bit).min(4);
+        match checked_bits {
+            0 => {
+                b = b.push_opcode(opcodes::all::OP_DROP);
+                continue;
+            }
+            4 => {}
+            _ => b = push_nibble_low_bits_mask(b, checked_bits),
+        }
         // For the nibble value, use push_int for accurate stack comparison
         b = b.push_int(nibble as i64);
         b = b.push_opcode(opcodes::all::OP_EQUALVERIFY);
@@ -166,14 +396,66 @@ fn build_prefix_equalverify(prefix_data: &[u8]) -> ScriptBuf {
     b.into_script()
 }
 
-/// duplicates (keeps) the first 8 nibbles, accumulates them into `x`,
-/// leaves `x` on the stack, original 24 nibbles untouched.
-fn build_script_reconstruct_x() -> ScriptBuf {
-    let mut b = Builder::new().push_int(0); // acc = 0
+/// Reduce the nibble on top of the stack to its low `bits` bits (`n mod 2^bits`)
+/// by conditionally subtracting 8, 4 and 2.
+fn push_nibble_low_bits_mask(mut b: Builder, bits: usize) -> Builder {
+    for power in [8, 4, 2] {
+        if power < 1 << bits {
+            break;
+        }
+        b = b
+            .push_opcode(opcodes::all::OP_DUP)
+            .push_int(power)
+            .push_opcode(opcodes::all::OP_GREATERTHANOREQUAL)
+            .push_opcode(opcodes::all::OP_IF)
+            .push_int(power)
+            .push_opcode(opcodes::all::OP_SUB)
+            .push_opcode(opcodes::all::OP_ENDIF);
+    }
+    b
+}
 
-    for i in 0..8 {
-        // acc *= 16
-        for _ in 0..4 {
+/// Copies the little-endian number at message bytes `[offset, offset +
+/// width)` out of the message limbs and leaves it on the stack, limbs
+/// untouched.
+///
+/// The message limbs sit at the bottom of the stack: every 32 bytes of the
+/// padded message, each 4-byte word reversed, form a 256-bit big-endian
+/// string cut into `limb_len`-bit limbs, see [`blake3_message_to_limbs`].
+/// The number is accumulated most significant bit first, one run of bits
+/// per limb. Whole limbs are copied as they are, the bits of a limb shared
+/// with another field are extracted with [`push_limb_bits`].
+pub(crate) fn build_script_reconstruct_field(
+    offset: usize,
+    width: usize,
+    limb_len: u8,
+) -> ScriptBuf {
+    let limb_len = limb_len as usize;
+    let limbs_per_half = 256usize.div_ceil(limb_len);
+
+    // (limb index, first bit in the limb, bit count), most significant
+    // byte first
+    let mut runs: Vec<(usize, usize, usize)> = vec![];
+    for byte in (offset..offset + width).rev() {
+        let reversed = 4 * (byte / 4) + 3 - byte % 4;
+        let (half, position) = (reversed / 32, reversed % 32);
+        for bit in 8 * position..8 * position + 8 {
+            let limb = half * limbs_per_half + bit / limb_len;
+            match runs.last_mut() {
+                Some((last, start, len))
+                    if *last == limb && *start + *len == bit % limb_len =>
+                {
+                    *len += 1
+                }
+                _ => runs.push((limb, bit % limb_len, 1)),
+            }
+        }
+    }
+
+    let mut b = Builder::new().push_int(0); // acc = 0
+    for (limb, start, len) in runs {
+        // acc *= 2^len
+        for _ in 0..len {
             b = b
                 .push_opcode(opcodes::all::OP_DUP)
                 .push_opcode(opcodes::all::OP_ADD);
@@ -182,162 +464,447 @@ fn build_script_reconstruct_x() -> ScriptBuf {
         b = b
             .push_opcode(opcodes::all::OP_DEPTH)
             .push_opcode(opcodes::all::OP_1SUB)
-            .push_int(i as i64)
+            .push_int(limb as i64)
             .push_opcode(opcodes::all::OP_SUB)
             .push_opcode(opcodes::all::OP_PICK);
-        // acc += nib
+        if len < limb_len {
+            b = push_limb_bits(b, limb_len, start, len);
+        }
+        // acc += bits
         b = b.push_opcode(opcodes::all::OP_ADD);
     }
     b.into_script()
 }
 
+/// Replace the `limb_len`-bit limb on top of the stack with its `len` bits
+/// starting `start` bits below its most significant one, by conditionally
+/// subtracting its powers of two from the highest.
+fn push_limb_bits(
+    mut b: Builder,
+    limb_len: usize,
+    start: usize,
+    len: usize,
+) -> Builder {
+    let low = limb_len - start - len;
+    // [limb] => [bits = 0, limb]
+    b = b.push_int(0).push_opcode(opcodes::all::OP_SWAP);
+    for bit in (low..limb_len).rev() {
+        let power = 1i64 << bit;
+        b = b
+            .push_opcode(opcodes::all::OP_DUP)
+            .push_int(power)
+            .push_opcode(opcodes::all::OP_GREATERTHANOREQUAL)
+            .push_opcode(opcodes::all::OP_IF)
+            .push_int(power)
+            .push_opcode(opcodes::all::OP_SUB);
+        // Bits above the run are only cleared
+        if bit < limb_len - start {
+            b = b
+                .push_opcode(opcodes::all::OP_SWAP)
+                .push_int(1 << (bit - low))
+                .push_opcode(opcodes::all::OP_ADD)
+                .push_opcode(opcodes::all::OP_SWAP);
+        }
+        b = b.push_opcode(opcodes::all::OP_ENDIF);
+    }
+    // Drop the bits below the run
+    b.push_opcode(opcodes::all::OP_DROP)
+}
+
+/// A sub-function `f_i` of the program `f = f_1 ∧ … ∧ f_k` split across the
+/// presigned transactions of a flow.
+///
+/// Every step of a flow checks one sub-function on a number field of the
+/// input `x` reconstructed from the witness, next to the signature check and
+/// the `H(x, r)|_B = d` prefix check.
+pub trait SubFunction {
+    /// Script fragment executed with the field on top of the stack. It must
+    /// consume it and abort the script when `f_i(x)` does not hold.
+    fn script(&self) -> ScriptBuf;
+
+    /// Off-chain evaluation of `f_i(x)`, mirroring [`SubFunction::script`].
+    fn evaluate(&self, x: u32) -> bool;
+
+    /// Index of the [`crate::input::Field::Number`] of `x` read by the
+    /// sub-function, the first field by default.
+    fn field(&self) -> usize {
+        0
+    }
+}
+
+/// Applies a sub-function to another field of the input, e.g.
+/// `OnField(1, LessThan(200))` checks the second field.
+#[derive(Debug, Clone, Copy)]
+pub struct OnField<F>(pub usize, pub F);
+
+impl<F: SubFunction> SubFunction for OnField<F> {
+    fn script(&self) -> ScriptBuf {
+        self.1.script()
+    }
+
+    fn evaluate(&self, x: u32) -> bool {
+        self.1.evaluate(x)
+    }
+
+    fn field(&self) -> usize {
+        self.0
+    }
+}
+
+/// Checks `x > threshold` (the toy `F1` with [`F1_THRESHOLD`]).
+#[derive(Debug, Clone, Copy)]
+pub struct GreaterThan(pub u32);
+
+impl SubFunction for GreaterThan {
+    fn script(&self) -> ScriptBuf {
+        Builder::new()
+            .push_int(self.0 as i64)
+            .push_opcode(opcodes::all::OP_GREATERTHAN)
+            .push_opcode(opcodes::all::OP_VERIFY)
+            .into_script()
+    }
+
+    fn evaluate(&self, x: u32) -> bool {
+        x > self.0
+    }
+}
+
+/// Checks `x < threshold` (the toy `F2` with [`F2_THRESHOLD`]).
+#[derive(Debug, Clone, Copy)]
+pub struct LessThan(pub u32);
+
+impl SubFunction for LessThan {
+    fn script(&self) -> ScriptBuf {
+        Builder::new()
+            .push_int(self.0 as i64)
+            .push_opcode(opcodes::all::OP_LESSTHAN)
+            .push_opcode(opcodes::all::OP_VERIFY)
+            .into_script()
+    }
+
+    fn evaluate(&self, x: u32) -> bool {
+        x < self.0
+    }
+}
+
+/// Size of a BIP-340 signature with the default sighash type.
+pub const SCHNORR_SIG_LEN: usize = 64;
+
+/// Size of a BIP-340 signature committing to `sighash_type`, which takes an
+/// extra byte unless it is the default.
+pub fn schnorr_sig_len(sighash_type: TapSighashType) -> usize {
+    match sighash_type {
+        TapSighashType::Default => SCHNORR_SIG_LEN,
+        _ => SCHNORR_SIG_LEN + 1,
+    }
+}
+
+/// Who has to sign every step of a flow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignerCheck {
+    /// The MuSig2 aggregate of the signer keys, `<pk> OP_CHECKSIGVERIFY`.
+    /// Needs an interactive signing round per flow transaction.
+    Aggregated(PublicKey),
+    /// `threshold` signatures of the individual `keys`, checked with
+    /// `OP_CHECKSIGADD`. Every signer signs on its own, at the cost of a
+    /// larger script and witness.
+    Threshold {
+        keys: Vec<XOnlyPublicKey>,
+        threshold: usize,
+    },
+}
+
+impl SignerCheck {
+    /// `threshold`-of-`keys.len()` check, `threshold` being at least 1.
+    pub fn threshold(
+        keys: Vec<XOnlyPublicKey>,
+        threshold: usize,
+    ) -> Result<Self, String> {
+        if threshold == 0 || threshold > keys.len() {
+            return Err(format!(
+                "threshold {threshold} out of range for {} keys",
+                keys.len()
+            ));
+        }
+        Ok(SignerCheck::Threshold { keys, threshold })
+    }
+
+    /// Upper bound on the size of the signature elements of the witness,
+    /// every signer signing with `sighash_type`.
+    pub fn max_witness_sizes(
+        &self,
+        sighash_type: TapSighashType,
+    ) -> Vec<usize> {
+        let sig_len = schnorr_sig_len(sighash_type);
+        match self {
+            SignerCheck::Aggregated(_) => vec![sig_len],
+            SignerCheck::Threshold { keys, .. } => vec![sig_len; keys.len()],
+        }
+    }
+
+    /// `<pk> OP_CHECKSIGVERIFY`, or `<pk_1> OP_CHECKSIG <pk_2>
+    /// OP_CHECKSIGADD … <pk_n> OP_CHECKSIGADD <t> OP_NUMEQUALVERIFY`.
+    pub fn script(&self) -> ScriptBuf {
+        match self {
+            SignerCheck::Aggregated(signer_pubkey) => Builder::new()
+                .push_x_only_key(&XOnlyPublicKey::from(signer_pubkey.inner))
+                .push_opcode(opcodes::all::OP_CHECKSIGVERIFY)
+                .into_script(),
+            SignerCheck::Threshold { keys, threshold } => {
+                let mut b = Builder::new();
+                for (i, key) in keys.iter().enumerate() {
+                    b = b.push_x_only_key(key).push_opcode(if i == 0 {
+                        opcodes::all::OP_CHECKSIG
+                    } else {
+                        opcodes::all::OP_CHECKSIGADD
+                    });
+                }
+                b.push_int(*threshold as i64)
+                    .push_opcode(opcodes::all::OP_NUMEQUALVERIFY)
+                    .into_script()
+            }
+        }
+    }
+}
+
 /// Build an F1 script with onchain BLAKE3, checking x>F1_THRESHOLD and the top (b_bits/8) bytes match flow_id_prefix.
 pub fn build_script_f1_blake3_locked(
     signer_pubkey: &PublicKey,
     flow_id_prefix: &[u8],
-    _b_bits: usize,
+    b_bits: usize,
 ) -> ScriptBuf {
-    let prefix_len = flow_id_prefix.len();
-    let total_msg_len = 12; // x_4b + r_4b0 + r_4b1
-    let limb_len = 4;
-
-    // 1) Script to check signature
-    let verify_signature_script = {
-        let mut b = Builder::new();
-        b = b.push_x_only_key(&XOnlyPublicKey::from(signer_pubkey.inner));
-        b.push_opcode(opcodes::all::OP_CHECKSIGVERIFY).into_script()
-    };
+    build_script_f_blake3_locked(
+        signer_pubkey,
+        &GreaterThan(F1_THRESHOLD),
+        flow_id_prefix,
+        b_bits,
+    )
+}
 
-    // 2) Reconstruct x from first 8 nibbles
-    let reconstruct_x_script = build_script_reconstruct_x();
+/// Build an F2 script with onchain BLAKE3, checking x<F2_THRESHOLD and prefix
+pub fn build_script_f2_blake3_locked(
+    signer_pubkey: &PublicKey,
+    flow_id_prefix: &[u8],
+    b_bits: usize,
+) -> ScriptBuf {
+    build_script_f_blake3_locked(
+        signer_pubkey,
+        &LessThan(F2_THRESHOLD),
+        flow_id_prefix,
+        b_bits,
+    )
+}
 
-    // 3) Check x_num > 100
-    let x_greater_check_script = Builder::new()
-        .push_int(F1_THRESHOLD as i64)
-        .push_opcode(opcodes::all::OP_GREATERTHAN)
-        .push_opcode(opcodes::all::OP_VERIFY)
-        .into_script();
+/// Build an F script with onchain BLAKE3 checking any predicate on x, e.g. a
+/// [`crate::predicate::Predicate`], and the prefix
+pub fn build_script_f_blake3_locked(
+    signer_pubkey: &PublicKey,
+    predicate: &dyn SubFunction,
+    flow_id_prefix: &[u8],
+    b_bits: usize,
+) -> ScriptBuf {
+    build_script_step_blake3_locked(
+        signer_pubkey,
+        &[predicate],
+        flow_id_prefix,
+        b_bits,
+    )
+}
 
-    // 4) BLAKE3 compute snippet - OPTIMIZED
-    let compute_compiled =
-        blake3_compute_script_with_limb(total_msg_len, limb_len).compile();
-    let compute_optimized = optimizer::optimize(compute_compiled);
-    let compute_blake3_script =
-        ScriptBuf::from_bytes(compute_optimized.to_bytes());
-
-    // 5) drop limbs we don't need for prefix check
-    // Needed nibbles: prefix_len (because now represented as nibbles) or B / 4
-    let needed_nibbles = prefix_len;
-    let blake3_script_hash_len_nibbles = 64;
-    let to_drop = blake3_script_hash_len_nibbles - needed_nibbles;
-    let drop_script = {
-        let mut b = Builder::new();
-        for _ in 0..to_drop {
-            b = b.push_opcode(opcodes::all::OP_DROP);
-        }
-        b.into_script()
-    };
+/// Build the locking script of one flow step with onchain BLAKE3: checks the
+/// signature, every sub-function in `sub_functions` on the reconstructed `x`
+/// and that the top (b_bits/8) bytes of `H(x, r)` match flow_id_prefix.
+///
+/// The double-collision layout checks a single sub-function per step, the
+/// triple-collision layout a pair `(f_i, f_j)`.
+pub fn build_script_step_blake3_locked(
+    signer_pubkey: &PublicKey,
+    sub_functions: &[&dyn SubFunction],
+    flow_id_prefix: &[u8],
+    b_bits: usize,
+) -> ScriptBuf {
+    build_script_step_locked(
+        signer_pubkey,
+        &Blake3Hash::default(),
+        &InputLayout::default(),
+        sub_functions,
+        flow_id_prefix,
+        b_bits,
+    )
+}
 
-    // 6) compare prefix => OP_EQUALVERIFY
-    let prefix_cmp_script = build_prefix_equalverify(flow_id_prefix);
+/// Same as [`build_script_step_blake3_locked`] with any collision hash and
+/// input layout. Every sub-function must read a number field of `layout`.
+pub fn build_script_step_locked(
+    signer_pubkey: &PublicKey,
+    hash: &dyn CollisionHash,
+    layout: &InputLayout,
+    sub_functions: &[&dyn SubFunction],
+    flow_id_prefix: &[u8],
+    b_bits: usize,
+) -> ScriptBuf {
+    build_script_step_locked_with_mode(
+        signer_pubkey,
+        hash,
+        layout,
+        sub_functions,
+        flow_id_prefix,
+        b_bits,
+        false,
+    )
+}
 
-    // 7) push OP_TRUE
-    let success_script = Builder::new().push_opcode(OP_TRUE).into_script();
+/// Same as [`build_script_step_locked`], also returning the byte range of
+/// each segment of the script for [`crate::trace`].
+pub fn build_script_step_segments(
+    signer_pubkey: &PublicKey,
+    hash: &dyn CollisionHash,
+    layout: &InputLayout,
+    sub_functions: &[&dyn SubFunction],
+    flow_id_prefix: &[u8],
+    b_bits: usize,
+) -> (ScriptBuf, Vec<ScriptSegment>) {
+    build_script_step_segments_with_signers(
+        &SignerCheck::Aggregated(*signer_pubkey),
+        hash,
+        layout,
+        sub_functions,
+        flow_id_prefix,
+        b_bits,
+    )
+}
 
-    // Combine the locking script parts
-    combine_scripts(&[
-        verify_signature_script,
-        reconstruct_x_script,
-        x_greater_check_script,
-        compute_blake3_script,
-        drop_script,
-        prefix_cmp_script,
-        success_script,
-    ])
+/// Same as [`build_script_step_segments`] with any [`SignerCheck`].
+pub fn build_script_step_segments_with_signers(
+    signers: &SignerCheck,
+    hash: &dyn CollisionHash,
+    layout: &InputLayout,
+    sub_functions: &[&dyn SubFunction],
+    flow_id_prefix: &[u8],
+    b_bits: usize,
+) -> (ScriptBuf, Vec<ScriptSegment>) {
+    combine_segments(&step_fragments(
+        signers,
+        hash,
+        layout,
+        sub_functions,
+        flow_id_prefix,
+        b_bits,
+        false,
+    ))
 }
 
-/// Build an F2 script with onchain BLAKE3, checking x<F2_THRESHOLD and prefix
-fn build_script_f2_blake3_locked_with_mode(
+fn build_script_step_locked_with_mode(
     signer_pubkey: &PublicKey,
+    hash: &dyn CollisionHash,
+    layout: &InputLayout,
+    sub_functions: &[&dyn SubFunction],
     flow_id_prefix: &[u8],
-    _b_bits: usize,
+    b_bits: usize,
     test_mode: bool,
 ) -> ScriptBuf {
-    let prefix_len = flow_id_prefix.len();
-    let total_msg_len = 12;
-    let limb_len = 4;
+    combine_segments(&step_fragments(
+        &SignerCheck::Aggregated(*signer_pubkey),
+        hash,
+        layout,
+        sub_functions,
+        flow_id_prefix,
+        b_bits,
+        test_mode,
+    ))
+    .0
+}
 
+fn step_fragments(
+    signers: &SignerCheck,
+    hash: &dyn CollisionHash,
+    layout: &InputLayout,
+    sub_functions: &[&dyn SubFunction],
+    flow_id_prefix: &[u8],
+    b_bits: usize,
+    test_mode: bool,
+) -> Vec<(SegmentKind, ScriptBuf)> {
     // 1) Script to check signature
-    let verify_signature_script = {
-        let mut b = Builder::new();
-        if test_mode {
+    let verify_signature_script = match signers {
+        SignerCheck::Aggregated(signer_pubkey) if test_mode => {
             // workaround an issue with sig verification implementation in script executor
-            b = b.push_key(signer_pubkey);
-        } else {
-            b = b.push_x_only_key(&XOnlyPublicKey::from(signer_pubkey.inner));
+            Builder::new()
+                .push_key(signer_pubkey)
+                .push_opcode(opcodes::all::OP_CHECKSIGVERIFY)
+                .into_script()
         }
-        b.push_opcode(opcodes::all::OP_CHECKSIGVERIFY).into_script()
+        _ => signers.script(),
     };
+    let mut fragments =
+        vec![(SegmentKind::SignatureCheck, verify_signature_script)];
+    let expand_script = hash.expand_witness_script(layout);
+    if !expand_script.is_empty() {
+        fragments.push((SegmentKind::ExpandWitness, expand_script));
+    }
 
-    // 2) Reconstruct x from first 8 nibbles
-    let reconstruct_x_script = build_script_reconstruct_x();
-
-    // 3) Check x_num < 200
-    let x_less_check_script = Builder::new()
-        .push_int(F2_THRESHOLD as i64)
-        .push_opcode(opcodes::all::OP_LESSTHAN)
-        .push_opcode(opcodes::all::OP_VERIFY)
-        .into_script();
+    // 2) + 3) Reconstruct the field of x from the witness and check f_i(x),
+    // once per sub-function since each check consumes the field
+    for (i, sub_function) in sub_functions.iter().enumerate() {
+        fragments.push((
+            SegmentKind::ReconstructX(sub_function.field()),
+            hash.field_script(layout, sub_function.field()),
+        ));
+        fragments.push((SegmentKind::SubFunction(i), sub_function.script()));
+    }
 
-    // 4) BLAKE3 compute snippet - OPTIMIZED
-    let compute_blake3_script = {
-        let compiled =
-            blake3_compute_script_with_limb(total_msg_len, limb_len).compile();
-        // Important: Optimize the compute script
-        let optimized = optimizer::optimize(compiled);
-        ScriptBuf::from_bytes(optimized.to_bytes())
-    };
+    // 4) + 5) + 6) Compute H(x, r) and compare its prefix
+    fragments.extend(hash.prefix_check_segments(
+        layout,
+        flow_id_prefix,
+        b_bits,
+    ));
 
-    // 5) drop limbs we don't need for prefix check
-    // Needed nibbles: prefix_len (because now represented as nibbles) or B / 4
-    let needed_nibbles = prefix_len;
-    let blake3_script_hash_len_nibbles = 64;
-    let to_drop = blake3_script_hash_len_nibbles - needed_nibbles;
-    let drop_script = {
-        let mut b = Builder::new();
-        for _ in 0..to_drop {
-            b = b.push_opcode(opcodes::all::OP_DROP);
-        }
-        b.into_script()
-    };
+    // 7) push OP_TRUE
+    let success_script = Builder::new().push_opcode(OP_TRUE).into_script();
+    fragments.push((SegmentKind::Success, success_script));
+    fragments
+}
 
-    // 6) compare prefix => OP_EQUALVERIFY
-    let prefix_cmp_script = build_prefix_equalverify(flow_id_prefix);
+/// Default limb length of the in-script BLAKE3 message encoding, see
+/// [`Blake3Hash::with_limb_len`].
+pub const DEFAULT_BLAKE3_LIMB_LEN: u8 = 4;
 
-    let success_script = Builder::new().push_opcode(OP_TRUE).into_script();
+/// Compiled BLAKE3 compute scripts by `(message length, limb length)`. The
+/// compute script only depends on those, not on the flow, so `2^L` flows
+/// share a single compilation.
+static BLAKE3_COMPUTE_SCRIPTS: OnceLock<
+    Mutex<HashMap<(usize, u8), ScriptBuf>>,
+> = OnceLock::new();
 
-    combine_scripts(&[
-        verify_signature_script,
-        reconstruct_x_script,
-        x_less_check_script,
-        compute_blake3_script,
-        drop_script,
-        prefix_cmp_script,
-        success_script,
-    ])
+/// Optimized BLAKE3 compute script over the `total_msg_len` byte `x || r`
+/// message in `limb_len`-bit limbs, leaving the 64 hash nibbles on the
+/// stack whatever the limb length.
+///
+/// Compiled once per message length and limb length, then cached.
+pub(crate) fn blake3_compute_script(
+    total_msg_len: usize,
+    limb_len: u8,
+) -> ScriptBuf {
+    let key = (total_msg_len, limb_len);
+    let cache = BLAKE3_COMPUTE_SCRIPTS.get_or_init(Default::default);
+    if let Some(script) = cache.lock().unwrap().get(&key) {
+        return script.clone();
+    }
+    // Compile without holding the lock, a concurrent compilation of the same
+    // key yields the same script
+    let script = compile_blake3_compute_script(total_msg_len, limb_len);
+    cache.lock().unwrap().entry(key).or_insert(script).clone()
 }
 
-pub fn build_script_f2_blake3_locked(
-    signer_pubkey: &PublicKey,
-    flow_id_prefix: &[u8],
-    _b_bits: usize,
+fn compile_blake3_compute_script(
+    total_msg_len: usize,
+    limb_len: u8,
 ) -> ScriptBuf {
-    build_script_f2_blake3_locked_with_mode(
-        signer_pubkey,
-        flow_id_prefix,
-        _b_bits,
-        false,
-    )
+    let compiled =
+        blake3_compute_script_with_limb(total_msg_len, limb_len).compile();
+    // Important: Optimize the compute script
+    let optimized = optimizer::optimize(compiled);
+    ScriptBuf::from_bytes(optimized.to_bytes())
 }
 
 /// A basic "hash rate" calibration
@@ -446,6 +1013,10 @@ pub fn blake3_message_to_limbs(message_bytes: &[u8], limb_len: u8) -> Vec<u32> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::flow_set::HighBitPattern;
+    use crate::hash::Sha256Hash;
+    use crate::input::Field;
+    use bitcoin::script::Instruction;
     use bitcoin_script::script;
     use bitvm::{
         execute_script_buf,
@@ -453,6 +1024,363 @@ mod tests {
             blake3_push_message_script_with_limb, blake3_verify_output_script,
         },
     };
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    /// Fingerprint of a generated script, see [`check_snapshots`].
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct ScriptSnapshot {
+        len: usize,
+        sha256: String,
+        opcodes: BTreeMap<String, usize>,
+    }
+
+    impl ScriptSnapshot {
+        fn new(script: &ScriptBuf) -> Self {
+            let mut opcodes = BTreeMap::new();
+            for instruction in script.instructions() {
+                let name = match instruction.expect("valid script") {
+                    Instruction::Op(opcode) => opcode.to_string(),
+                    Instruction::PushBytes(bytes) => match bytes.len() {
+                        0 => "OP_0".to_string(),
+                        len @ 1..=75 => format!("OP_PUSHBYTES_{len}"),
+                        76..=255 => "OP_PUSHDATA1".to_string(),
+                        _ => "OP_PUSHDATA2".to_string(),
+                    },
+                };
+                *opcodes.entry(name).or_default() += 1;
+            }
+            Self {
+                len: script.len(),
+                sha256: hex::encode(
+                    sha256::Hash::hash(script.as_bytes()).to_byte_array(),
+                ),
+                opcodes,
+            }
+        }
+
+        /// What differs from `self` in `other`.
+        fn diff(&self, other: &Self) -> String {
+            let mut changes = vec![];
+            if self.len != other.len {
+                changes.push(format!("len {} -> {}", self.len, other.len));
+            }
+            if self.sha256 != other.sha256 {
+                changes.push(format!(
+                    "sha256 {} -> {}",
+                    self.sha256, other.sha256
+                ));
+            }
+            let names = self.opcodes.keys().chain(other.opcodes.keys());
+            for name in names.collect::<std::collections::BTreeSet<_>>() {
+                let count = |opcodes: &BTreeMap<String, usize>| {
+                    opcodes.get(name).copied().unwrap_or(0) as i64
+                };
+                let delta = count(&other.opcodes) - count(&self.opcodes);
+                if delta != 0 {
+                    changes.push(format!("{name} {delta:+}"));
+                }
+            }
+            changes.join(", ")
+        }
+    }
+
+    /// Compares `scripts` to the snapshots recorded in
+    /// `src/snapshots/{file}` and fails on any difference.
+    ///
+    /// `UPDATE_SNAPSHOTS=1` records the current scripts instead, nothing
+    /// else writes to `src/snapshots/`: scripts without a snapshot fail too.
+    fn check_snapshots(file: &str, scripts: &[(String, ScriptBuf)]) {
+        let path =
+            format!("{}/src/snapshots/{file}", env!("CARGO_MANIFEST_DIR"));
+        let recorded: BTreeMap<String, ScriptSnapshot> =
+            match std::fs::read_to_string(&path) {
+                Ok(json) => serde_json::from_str(&json).unwrap(),
+                Err(_) => BTreeMap::new(),
+            };
+        let actual = scripts
+            .iter()
+            .map(|(name, script)| (name.clone(), ScriptSnapshot::new(script)))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut changed = vec![];
+        let mut missing = vec![];
+        for (name, snapshot) in &actual {
+            match recorded.get(name) {
+                Some(expected) if expected == snapshot => {}
+                Some(expected) => {
+                    changed.push(format!("{name}: {}", expected.diff(snapshot)))
+                }
+                None => missing.push(name.as_str()),
+            }
+        }
+        let stale = recorded
+            .keys()
+            .filter(|name| !actual.contains_key(*name))
+            .collect::<Vec<_>>();
+
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::create_dir_all(format!(
+                "{}/src/snapshots",
+                env!("CARGO_MANIFEST_DIR")
+            ))
+            .unwrap();
+            let json = serde_json::to_string_pretty(&actual).unwrap();
+            std::fs::write(&path, json + "\n").unwrap();
+            println!("recorded {} script snapshots in {path}", actual.len());
+            return;
+        }
+        assert!(
+            changed.is_empty() && missing.is_empty() && stale.is_empty(),
+            "generated scripts differ from {path}, rerun with \
+             UPDATE_SNAPSHOTS=1 if intended\nchanged:\n  {}\n\
+             missing: {missing:?}\nstale: {stale:?}",
+            changed.join("\n  ")
+        );
+    }
+
+    fn snapshot_pubkey() -> PublicKey {
+        let secp = secp256k1::Secp256k1::new();
+        let secret_key = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        PublicKey::new(secret_key.public_key(&secp))
+    }
+
+    /// `(B, flow ID)` pairs: the first and last flow of a fixed pattern for a
+    /// few `(B, L)`.
+    fn snapshot_flow_ids(b_l: &[(usize, usize)]) -> Vec<(usize, FlowId)> {
+        b_l.iter()
+            .flat_map(|&(b_bits, l_bits)| {
+                let pattern = 0x5a5a_5a5a_5a5a & prefix_mask(b_bits - l_bits);
+                let flow_set = HighBitPattern::new(b_bits, l_bits, pattern);
+                let flow_ids = flow_set.flow_ids().collect::<Vec<_>>();
+                [flow_ids[0], flow_ids[flow_ids.len() - 1]]
+                    .map(|flow_id| (b_bits, flow_id))
+            })
+            .collect()
+    }
+
+    fn multi_field_layout() -> InputLayout {
+        InputLayout::new(vec![
+            Field::Number(2),
+            Field::Bytes(5),
+            Field::Number(4),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_script_snapshots() {
+        let pubkey = snapshot_pubkey();
+        let mut scripts = vec![];
+        for (b_bits, flow_id) in snapshot_flow_ids(&[(8, 2), (13, 3), (16, 4)])
+        {
+            let prefix = flow_id_to_prefix_bytes(flow_id, b_bits);
+            scripts.push((
+                format!("prefix_equalverify/B{b_bits}/{flow_id:#x}"),
+                build_prefix_equalverify(&prefix, b_bits),
+            ));
+        }
+        for limb_len in [4, 5, 8, 13] {
+            for (offset, width) in [(0, 4), (4, 2), (7, 4)] {
+                scripts.push((
+                    format!(
+                        "reconstruct_field/limb{limb_len}/{offset}+{width}"
+                    ),
+                    build_script_reconstruct_field(offset, width, limb_len),
+                ));
+            }
+        }
+        scripts.push(("greater_than/100".into(), GreaterThan(100).script()));
+        scripts.push(("less_than/200".into(), LessThan(200).script()));
+
+        let sub_functions: [&dyn SubFunction; 2] =
+            [&GreaterThan(F1_THRESHOLD), &LessThan(F2_THRESHOLD)];
+        for (b_bits, flow_id) in snapshot_flow_ids(&[(8, 2), (16, 4), (32, 4)])
+        {
+            let prefix = flow_id_to_prefix_bytes(flow_id, b_bits);
+            for (name, layout) in [
+                ("default", InputLayout::default()),
+                ("multi_field", multi_field_layout()),
+            ] {
+                scripts.push((
+                    format!("step_sha256/{name}/B{b_bits}/{flow_id:#x}"),
+                    build_script_step_locked(
+                        &pubkey,
+                        &Sha256Hash,
+                        &layout,
+                        &sub_functions,
+                        &prefix,
+                        b_bits,
+                    ),
+                ));
+            }
+            let (script, _) = build_script_step_segments(
+                &pubkey,
+                &Sha256Hash,
+                &InputLayout::default(),
+                &sub_functions[..1],
+                &prefix,
+                b_bits,
+            );
+            scripts.push((
+                format!("step_segments_sha256/B{b_bits}/{flow_id:#x}"),
+                script,
+            ));
+        }
+        check_snapshots("scripts.json", &scripts);
+    }
+
+    /// The scripts embedding bitvm's BLAKE3, pinned by git revision.
+    #[test]
+    #[ignore = "src/snapshots/blake3_scripts.json is not recorded yet"]
+    fn test_blake3_script_snapshots() {
+        let pubkey = snapshot_pubkey();
+        let mut scripts = vec![];
+        for limb_len in [DEFAULT_BLAKE3_LIMB_LEN, 8] {
+            scripts.push((
+                format!("blake3_compute/12/limb{limb_len}"),
+                blake3_compute_script(12, limb_len),
+            ));
+        }
+        for (b_bits, flow_id) in snapshot_flow_ids(&[(8, 2), (16, 4), (32, 4)])
+        {
+            let prefix = flow_id_to_prefix_bytes(flow_id, b_bits);
+            let name =
+                |builder: &str| format!("{builder}/B{b_bits}/{flow_id:#x}");
+            scripts.push((
+                name("f1_blake3"),
+                build_script_f1_blake3_locked(&pubkey, &prefix, b_bits),
+            ));
+            scripts.push((
+                name("f2_blake3"),
+                build_script_f2_blake3_locked(&pubkey, &prefix, b_bits),
+            ));
+            scripts.push((
+                name("f_blake3/gt150"),
+                build_script_f_blake3_locked(
+                    &pubkey,
+                    &GreaterThan(150),
+                    &prefix,
+                    b_bits,
+                ),
+            ));
+            scripts.push((
+                name("step_blake3/f1_f2"),
+                build_script_step_blake3_locked(
+                    &pubkey,
+                    &[&GreaterThan(F1_THRESHOLD), &LessThan(F2_THRESHOLD)],
+                    &prefix,
+                    b_bits,
+                ),
+            ));
+            scripts.push((
+                name("step_blake3/multi_field/limb8"),
+                build_script_step_locked(
+                    &pubkey,
+                    &Blake3Hash::with_limb_len(8).unwrap(),
+                    &multi_field_layout(),
+                    &[&OnField(2, LessThan(F2_THRESHOLD))],
+                    &prefix,
+                    b_bits,
+                ),
+            ));
+        }
+        check_snapshots("blake3_scripts.json", &scripts);
+    }
+
+    #[test]
+    fn test_find_valid_nonce_parallel_matches_sequential() {
+        let (b_bits, l_bits) = (16, 4);
+        for input in [114, 123, 0x12345678] {
+            let expected = find_valid_nonce(input, b_bits, l_bits).unwrap();
+            for num_threads in [1, 2, 4, 7] {
+                assert_eq!(
+                    find_valid_nonce_parallel(
+                        &Input::try_from(input).unwrap(),
+                        &LowRange::new(b_bits, l_bits),
+                        &Blake3Hash::default(),
+                        num_threads
+                    )
+                    .unwrap(),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_wide_flow_id_prefix() {
+        // Paper-sized B with a tiny B-L gap
+        let (input, b_bits, l_bits) = (114, 120, 116);
+        let (nonce, flow_id) = find_valid_nonce(input, b_bits, l_bits).unwrap();
+        assert!(flow_id < 1 << l_bits);
+
+        let message =
+            [input.to_le_bytes().as_slice(), &nonce.to_le_bytes()].concat();
+        let hash = blake3::hash(&message);
+        let expected_nibbles = hash.as_bytes()[..b_bits / 8]
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0x0F])
+            .collect::<Vec<_>>();
+        assert_eq!(flow_id_to_prefix_bytes(flow_id, b_bits), expected_nibbles);
+    }
+
+    #[test]
+    fn test_bit_granular_prefix() {
+        let (input, b_bits, l_bits) = (114, 13, 5);
+        let (nonce, flow_id) = find_valid_nonce(input, b_bits, l_bits).unwrap();
+        assert!(flow_id < 1 << l_bits);
+        assert_eq!(flow_id, calculate_flow_id(input, nonce) & 0x1FFF);
+
+        // 13 bits => 2 bytes, the top 3 bits of the second one are free
+        assert_eq!(
+            flow_id_to_prefix_bytes(0x1abc, 13),
+            vec![0xb, 0xc, 0x1, 0xa]
+        );
+        assert_eq!(flow_id_to_prefix_bytes(flow_id, b_bits).len(), 4);
+    }
+
+    #[test]
+    fn test_bit_granular_prefix_script() {
+        // B = 12: byte 0 fully checked, only the low nibble of byte 1
+        let flow_id_prefix = vec![0x0, 0xd, 0x0, 0x5];
+        let run = |stack_nibbles: [i64; 4], b_bits: usize| {
+            let mut b = Builder::new();
+            for nibble in stack_nibbles {
+                b = b.push_int(nibble);
+            }
+            let mut script = b.into_script().to_bytes();
+            script.extend(
+                build_prefix_equalverify(&flow_id_prefix, b_bits).to_bytes(),
+            );
+            script.push(OP_TRUE.to_u8());
+            execute_script_buf(ScriptBuf::from_bytes(script)).success
+        };
+
+        assert!(run([0x0, 0xd, 0x0, 0x5], 12));
+        // the high nibble of byte 1 is outside of B
+        assert!(run([0x0, 0xd, 0xf, 0x5], 12));
+        assert!(!run([0x0, 0xd, 0x0, 0x4], 12));
+        // B = 10: only the low 2 bits of the low nibble of byte 1
+        let flow_id_prefix = vec![0x0, 0xd, 0x0, 0x1];
+        let run_10 = |low_nibble: i64| {
+            let mut script = Builder::new()
+                .push_int(0x0)
+                .push_int(0xd)
+                .push_int(0x7)
+                .push_int(low_nibble)
+                .into_script()
+                .to_bytes();
+            script.extend(
+                build_prefix_equalverify(&flow_id_prefix, 10).to_bytes(),
+            );
+            script.push(OP_TRUE.to_u8());
+            execute_script_buf(ScriptBuf::from_bytes(script)).success
+        };
+        assert!(run_10(0x1));
+        assert!(run_10(0xd));
+        assert!(!run_10(0x2));
+    }
 
     #[test]
     fn test_f1_witness_script() {
@@ -487,6 +1415,16 @@ mod tests {
         assert!(f1_res.error.is_none());
     }
 
+    #[test]
+    fn test_blake3_compute_script_cache() {
+        let script = blake3_compute_script(12, DEFAULT_BLAKE3_LIMB_LEN);
+        let cached = BLAKE3_COMPUTE_SCRIPTS.get().unwrap().lock().unwrap()
+            [&(12, DEFAULT_BLAKE3_LIMB_LEN)]
+            .clone();
+        assert_eq!(script, cached);
+        assert_eq!(blake3_compute_script(12, DEFAULT_BLAKE3_LIMB_LEN), cached);
+    }
+
     #[test]
     fn test_blake3_script_generation() {
         let message = [0u8; 32];
@@ -570,7 +1508,7 @@ mod tests {
 
         // flow id prefix: 000d0000
         let flow_id_prefix = vec![0x00, 0x0d, 0x00, 0x00];
-        let script_part_1 = build_prefix_equalverify(&flow_id_prefix);
+        let script_part_1 = build_prefix_equalverify(&flow_id_prefix, 16);
 
         let locking_script =
             combine_scripts(&[script_part_1, script! {OP_TRUE}.compile()]);
@@ -0,0 +1,1998 @@
+//! Generic k-step ColliderVM flows.
+//!
+//! A flow `d` chains `k + 1` presigned transactions: the first one moves the
+//! funding UTXO into the lock of step 1, every following transaction spends
+//! the lock of step `i` (checking `f_i(x)` and `H(x, r)|_B = d`) into the lock
+//! of step `i + 1`, and the last one pays the receiver.
+//!
+//! In the triple-collision variant ([`FlowMode::TripleCollision`]) the chain
+//! has `k choose 2 = k(k-1)/2` steps instead of `k`, each checking a pair
+//! `(f_i, f_j)`.
+//!
+//! [`offline_setup`] presigns one flow per `d ∈ D` before `x` is known, the
+//! online phase ([`online_execution`]) only searches a nonce and picks the
+//! matching presigned flow.
+
+use crate::analysis::{ScriptReport, analyze_spend};
+use crate::core::{
+    FlowId, ScriptSegment, SignerCheck, SubFunction,
+    build_script_step_segments_with_signers, find_valid_nonce_parallel,
+};
+use crate::flow_set::FlowSet;
+use crate::hash::{Blake3Hash, CollisionHash};
+use crate::input::{Input, InputLayout};
+use crate::musig2::simulate_musig2;
+use crate::trace::{Trace, TraceDetail, trace_spend};
+use crate::transactions::{
+    FundingUtxo, InternalKey, PayoutOutput, PayoutTemplate, RefundLeaf,
+    StepSignatures, add_fee_input, create_lock_tx, create_lock_tx_from_utxos,
+    create_payout_tx, create_refund_tx, finalize_funding_inputs,
+    finalize_lock_tx_with_signatures, finalize_refund_tx, funding_address,
+    max_spend_witness,
+};
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::taproot::TaprootSpendInfo;
+use bitcoin::{
+    Address, Amount, Network, OutPoint, ScriptBuf, TapSighashType, Transaction,
+    TxOut, Txid, Witness,
+};
+use musig2::LiftedSignature;
+use secp256k1::{PublicKey, Scalar};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// One presigned transaction of a flow.
+#[derive(Debug, Clone)]
+pub struct FlowTx {
+    pub tx: Transaction,
+    /// Sighashes the signers have to sign, one per input of `tx`: several
+    /// only for a funding spend of several UTXOs.
+    pub sighashes: Vec<Message>,
+    /// Leaf script spent by the input of `tx`.
+    pub spent_script: ScriptBuf,
+    /// Outputs spent by `tx`, one per input, fee input included.
+    pub prevouts: Vec<TxOut>,
+    /// Taproot spend info of the output spent by `tx`.
+    pub spent_spend_info: TaprootSpendInfo,
+}
+
+impl FlowTx {
+    /// Value of the outputs spent by `tx`.
+    pub fn spent_value(&self) -> u64 {
+        self.prevouts
+            .iter()
+            .map(|prevout| prevout.value.to_sat())
+            .sum()
+    }
+}
+
+/// A chain of presigned transactions for a single flow `d`.
+#[derive(Debug, Clone)]
+pub struct Flow {
+    /// Funding spend, one transaction per step and the final spend, in
+    /// broadcast order.
+    pub txs: Vec<FlowTx>,
+    /// Locking script of every step, see [`FlowBuilder::step_layout`] for
+    /// the sub-functions each of them checks.
+    pub locks: Vec<ScriptBuf>,
+    /// Segments of every lock, for [`Flow::trace`].
+    pub segments: Vec<Vec<ScriptSegment>>,
+    /// Collision hash checked by the locks.
+    pub hash: Arc<dyn CollisionHash>,
+    /// Hash prefix length `B` checked by the locks.
+    pub b_bits: usize,
+    /// Layout of the input `x` read by the locks.
+    pub layout: InputLayout,
+    /// Internal key of the outputs locked by [`Flow::locks`].
+    pub internal_key: InternalKey,
+    /// Timelocked exit next to every lock, see [`Flow::refund_tx`].
+    pub refund: Option<RefundLeaf>,
+    /// Signatures required by [`Flow::locks`].
+    pub signers: SignerCheck,
+    /// Sighash type of the signatures of the last transaction, see
+    /// [`Flow::tx_sighash_type`].
+    pub sighash_type: TapSighashType,
+    /// Outputs of the last transaction, before its anchor if any.
+    pub payout: PayoutTemplate,
+}
+
+impl Flow {
+    /// The sighashes to be signed by the signers, in broadcast order.
+    pub fn sighashes(&self) -> Vec<Message> {
+        self.txs
+            .iter()
+            .flat_map(|flow_tx| flow_tx.sighashes.iter().copied())
+            .collect()
+    }
+
+    /// Sighash type of the signatures of transaction `tx`: only the last one
+    /// takes [`Flow::sighash_type`]. The others are spent by presigned
+    /// transactions committing to their txid, so they are always signed with
+    /// the default type.
+    pub fn tx_sighash_type(&self, tx: usize) -> TapSighashType {
+        if tx + 1 == self.txs.len() {
+            self.sighash_type
+        } else {
+            TapSighashType::Default
+        }
+    }
+
+    /// Attach the witnesses to every transaction of the flow.
+    ///
+    /// `signatures` must follow the order of [`Flow::sighashes`]. The funding
+    /// spend only needs a signature per UTXO, every other transaction also
+    /// gets the witness encoding of `(x, r)` for [`Flow::hash`].
+    pub fn finalize(
+        &mut self,
+        signatures: &[LiftedSignature],
+        x: &Input,
+        nonce: &u64,
+    ) -> anyhow::Result<()> {
+        let signatures = signatures
+            .iter()
+            .map(|sig| StepSignatures::Aggregated(*sig))
+            .collect::<Vec<_>>();
+        self.finalize_with(&signatures, x, nonce)
+    }
+
+    /// Same as [`Flow::finalize`] with the signatures matching
+    /// [`Flow::signers`]. The funding spend always takes the aggregated
+    /// signatures.
+    pub fn finalize_with(
+        &mut self,
+        signatures: &[StepSignatures],
+        x: &Input,
+        nonce: &u64,
+    ) -> anyhow::Result<()> {
+        let expected = self.sighashes().len();
+        anyhow::ensure!(
+            signatures.len() == expected,
+            "expected {expected} signatures, got {}",
+            signatures.len()
+        );
+        anyhow::ensure!(
+            *x.layout() == self.layout,
+            "input layout {:?} does not match the flow layout {:?}",
+            x.layout(),
+            self.layout
+        );
+
+        let sighash_types = (1..self.txs.len())
+            .map(|tx| self.tx_sighash_type(tx))
+            .collect::<Vec<_>>();
+        let (funding_tx, step_txs) = self.txs.split_first_mut().unwrap();
+        let (funding_sigs, step_sigs) =
+            signatures.split_at(funding_tx.sighashes.len());
+        let funding_sigs = funding_sigs
+            .iter()
+            .map(|sig| match sig {
+                StepSignatures::Aggregated(sig) => Ok(*sig),
+                _ => Err(anyhow::anyhow!(
+                    "the funding spend needs the MuSig2 signature"
+                )),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        finalize_funding_inputs(
+            &mut funding_tx.tx,
+            &funding_sigs,
+            &funding_tx.spent_spend_info,
+            &funding_tx.spent_script,
+        )?;
+
+        for (i, (flow_tx, sig)) in
+            step_txs.iter_mut().zip(step_sigs).enumerate()
+        {
+            sig.check(&self.signers)
+                .map_err(|err| anyhow::anyhow!("step {}: {err}", i + 1))?;
+            finalize_lock_tx_with_signatures(
+                &mut flow_tx.tx,
+                sig,
+                sighash_types[i],
+                &flow_tx.spent_spend_info,
+                &flow_tx.spent_script,
+                self.hash.as_ref(),
+                self.b_bits,
+                x,
+                nonce,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Adds a fee input to the last transaction of the flow once finalized,
+    /// see [`add_fee_input`]. Needs an ANYONECANPAY [`Flow::sighash_type`].
+    ///
+    /// The earlier transactions are spent by presigned transactions that
+    /// commit to their txid, which another input would change.
+    pub fn add_fee_input(
+        &mut self,
+        fee_outpoint: &OutPoint,
+        fee_prevout: &TxOut,
+        fee_max_witness: &Witness,
+        change_addr: &Address,
+        fee_rate: u64,
+    ) -> anyhow::Result<()> {
+        let presigned_fee = *self.fees().last().unwrap();
+        let flow_tx = self.txs.last_mut().unwrap();
+        anyhow::ensure!(
+            flow_tx.tx.input.len() == 1,
+            "the last transaction already has a fee input"
+        );
+        anyhow::ensure!(
+            !flow_tx.tx.input[0].witness.is_empty(),
+            "the flow must be finalized before adding a fee input"
+        );
+        add_fee_input(
+            &mut flow_tx.tx,
+            presigned_fee,
+            self.sighash_type,
+            fee_outpoint,
+            fee_prevout,
+            fee_max_witness,
+            change_addr,
+            fee_rate,
+        )?;
+        flow_tx.prevouts.push(fee_prevout.clone());
+        Ok(())
+    }
+
+    /// The transactions of the flow, in broadcast order.
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.txs.iter().map(|flow_tx| flow_tx.tx.clone()).collect()
+    }
+
+    /// Trace of the spend of lock `step`, meaningful once the flow has been
+    /// finalized.
+    pub fn trace(
+        &self,
+        step: usize,
+        detail: TraceDetail,
+    ) -> anyhow::Result<Trace> {
+        anyhow::ensure!(
+            step < self.locks.len(),
+            "step {step} out of range for {} locks",
+            self.locks.len()
+        );
+        let flow_tx = &self.txs[step + 1];
+        let segments = &self.segments[step];
+        trace_spend(&flow_tx.tx, 0, &flow_tx.prevouts, segments, detail)
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Resources used by the spend of lock `step`, meaningful once the flow
+    /// has been finalized.
+    pub fn analyze(&self, step: usize) -> anyhow::Result<ScriptReport> {
+        anyhow::ensure!(
+            step < self.locks.len(),
+            "step {step} out of range for {} locks",
+            self.locks.len()
+        );
+        let flow_tx = &self.txs[step + 1];
+        analyze_spend(&flow_tx.tx, 0, &flow_tx.prevouts)
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Unsigned refund of the output locked by lock `step` to `refund_addr`,
+    /// with the sighash the refund key has to sign. Spendable once the
+    /// output is [`RefundLeaf::blocks`] deep, if the flow stalled there.
+    pub fn refund_tx(
+        &self,
+        step: usize,
+        refund_addr: &Address,
+        fee_rate: &u64,
+    ) -> anyhow::Result<(Transaction, Message)> {
+        let refund = self.refund_leaf(step)?;
+        create_refund_tx(&self.txs[step].tx, refund, refund_addr, fee_rate)
+    }
+
+    /// Attach the witness of a refund built by [`Flow::refund_tx`].
+    pub fn finalize_refund(
+        &self,
+        step: usize,
+        refund_tx: &mut Transaction,
+        sig: &secp256k1::schnorr::Signature,
+    ) -> anyhow::Result<()> {
+        let refund = self.refund_leaf(step)?;
+        // The output of txs[step] is the one spent by txs[step + 1]
+        let spend_info = &self.txs[step + 1].spent_spend_info;
+        finalize_refund_tx(refund_tx, sig, spend_info, refund)
+    }
+
+    /// Checks that no output of the flow can be spent through the key path:
+    /// each one commits to its leaves with an internal key that
+    /// [`InternalKey::is_unspendable`] for `tweak`. Only needs the public
+    /// transactions and trees, e.g. for an audit.
+    pub fn verify_no_key_path(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        tweak: Option<&Scalar>,
+    ) -> anyhow::Result<()> {
+        for step in 0..self.locks.len() {
+            let spend_info = &self.txs[step + 1].spent_spend_info;
+            let output = &self.txs[step].tx.output[0];
+            anyhow::ensure!(
+                output.script_pubkey
+                    == ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+                "output of step {step} does not commit to its tree"
+            );
+            anyhow::ensure!(
+                InternalKey::is_unspendable(
+                    secp,
+                    &spend_info.internal_key(),
+                    tweak
+                ),
+                "output of step {step} has a spendable internal key {}",
+                spend_info.internal_key()
+            );
+        }
+        Ok(())
+    }
+
+    fn refund_leaf(&self, step: usize) -> anyhow::Result<&RefundLeaf> {
+        anyhow::ensure!(
+            step < self.locks.len(),
+            "step {step} out of range for {} locks",
+            self.locks.len()
+        );
+        self.refund
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("the flow has no refund leaf"))
+    }
+
+    /// Fee paid by each transaction of the flow, e.g. the `parent_fee` of
+    /// [`crate::transactions::create_anchor_child_tx`].
+    pub fn fees(&self) -> Vec<u64> {
+        self.txs
+            .iter()
+            .map(|flow_tx| {
+                let paid = flow_tx.tx.output.iter().map(|output| output.value);
+                flow_tx.spent_value() - paid.sum::<Amount>().to_sat()
+            })
+            .collect()
+    }
+
+    /// Smallest deposit paying every fee and anchor of the flow, the fixed
+    /// outputs of [`Flow::payout`] and a non-dust withdrawal. Fees do not
+    /// depend on the amounts, so this holds for any deposit spread over as
+    /// many funding UTXOs.
+    pub fn required_funding(&self) -> u64 {
+        let outputs = &self.txs.last().unwrap().tx.output;
+        let payout = &outputs[..self.payout.outputs().len()];
+        let receiver = self
+            .payout
+            .outputs()
+            .iter()
+            .position(|output| *output == PayoutOutput::Receiver)
+            .unwrap();
+        let paid = payout
+            .iter()
+            .map(|output| output.value.to_sat())
+            .sum::<u64>();
+        self.txs[0].spent_value() - paid
+            + self.payout.min_value(&payout[receiver].script_pubkey)
+    }
+
+    /// Total on-chain cost of the flow in vbytes, meaningful once the flow
+    /// has been finalized.
+    pub fn total_vsize(&self) -> usize {
+        self.txs.iter().map(|flow_tx| flow_tx.tx.vsize()).sum()
+    }
+}
+
+/// A flow built and signed by the signers during the offline phase.
+#[derive(Debug, Clone)]
+pub struct PresignedFlow {
+    pub flow_id: FlowId,
+    pub flow: Flow,
+    /// Aggregated signatures over [`Flow::sighashes`].
+    pub signatures: Vec<LiftedSignature>,
+}
+
+impl PresignedFlow {
+    /// Complete the presigned transactions with the witnesses for `(x, r)`.
+    pub fn finalize(
+        &self,
+        x: &Input,
+        nonce: &u64,
+    ) -> anyhow::Result<Vec<Transaction>> {
+        Ok(self.finalized(x, nonce)?.transactions())
+    }
+
+    /// The flow finalized for `(x, r)`, e.g. for [`Flow::analyze`] and
+    /// [`Flow::trace`].
+    pub fn finalized(&self, x: &Input, nonce: &u64) -> anyhow::Result<Flow> {
+        let mut flow = self.flow.clone();
+        flow.finalize(&self.signatures, x, nonce)?;
+        Ok(flow)
+    }
+}
+
+/// All presigned flows, keyed by flow ID `d`.
+pub type PresignedFlows = BTreeMap<FlowId, PresignedFlow>;
+
+/// Offline phase: build and MuSig2-sign the flow of every `d ∈ D`, before
+/// the input `x` is known.
+///
+/// Every flow spends the same funding UTXOs, only the one selected by the
+/// operator's nonce will ever be broadcast.
+pub fn offline_setup(
+    builder: &FlowBuilder,
+    secp: &Secp256k1<secp256k1::All>,
+    sk_signers: &[(
+        musig2::secp256k1::SecretKey,
+        musig2::secp256k1::PublicKey,
+    )],
+    flow_set: &dyn FlowSet,
+    funding: &[FundingUtxo],
+    receiver_addr: &Address,
+) -> anyhow::Result<PresignedFlows> {
+    let pk_signers = sk_signers.iter().map(|key| key.1).collect::<Vec<_>>();
+    let agg_ctx = musig2::KeyAggContext::new(pk_signers)?;
+    let pk_signer: musig2::secp256k1::PublicKey = agg_ctx.aggregated_pubkey();
+    let pk_signer: PublicKey = crate::utils::inner_from(pk_signer);
+
+    anyhow::ensure!(
+        flow_set.b_bits() == builder.b_bits,
+        "D holds {}-bit flow IDs but the flow checks B={}",
+        flow_set.b_bits(),
+        builder.b_bits
+    );
+
+    let mut flows = PresignedFlows::new();
+    for flow_id in flow_set.flow_ids() {
+        let flow_id_prefix = flow_set
+            .prefix_nibbles(flow_id)
+            .map_err(anyhow::Error::msg)?;
+        let flow = builder.build_with_funding(
+            secp,
+            &pk_signer,
+            funding,
+            &flow_id_prefix,
+            receiver_addr,
+        )?;
+        let signatures = flow
+            .sighashes()
+            .iter()
+            .map(|sighash| simulate_musig2(sk_signers, sighash))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        flows.insert(
+            flow_id,
+            PresignedFlow {
+                flow_id,
+                flow,
+                signatures,
+            },
+        );
+    }
+    Ok(flows)
+}
+
+/// Online phase: find a nonce `r` with `H(x, r)|_B = d ∈ D` and complete the
+/// presigned flow `d`.
+///
+/// The nonce search runs on `num_threads` workers with the collision hash of
+/// the presigned flows. Returns the nonce, the selected flow ID and the
+/// transactions of the flow, ready to be broadcast in order.
+pub fn online_execution(
+    flows: &PresignedFlows,
+    x: &Input,
+    flow_set: &dyn FlowSet,
+    num_threads: usize,
+) -> anyhow::Result<(u64, FlowId, Vec<Transaction>)> {
+    let hash = flows
+        .values()
+        .next()
+        .map(|presigned| presigned.flow.hash.clone())
+        .ok_or_else(|| anyhow::anyhow!("no presigned flows"))?;
+    let (nonce, flow_id) =
+        find_valid_nonce_parallel(x, flow_set, hash.as_ref(), num_threads)
+            .map_err(anyhow::Error::msg)?;
+    let presigned = flows.get(&flow_id).ok_or_else(|| {
+        anyhow::anyhow!("no presigned flow for flow_id {flow_id}")
+    })?;
+    Ok((nonce, flow_id, presigned.finalize(x, &nonce)?))
+}
+
+/// Layout of the transactions of a flow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlowMode {
+    /// `k` steps, step `i` checks `f_i`. Cheating needs a double collision
+    /// (`~2^(B-L/2)` work).
+    #[default]
+    DoubleCollision,
+    /// `k choose 2` steps, step `ij` checks both `f_i` and `f_j`. Cheating
+    /// needs a triple collision (`~2^(B-L/3)` work), but the chain has
+    /// `k(k-1)/2` steps instead of `k`: every sub-function is scripted `k-1`
+    /// times and the hash prefix is checked `k(k-1)/2` times, so `k = 3`
+    /// keeps the step count and larger `k` grow it quadratically.
+    TripleCollision,
+}
+
+/// Builds flows made of any number `k` of [`SubFunction`] steps.
+pub struct FlowBuilder {
+    b_bits: usize,
+    network: Network,
+    fee_rate: u64,
+    mode: FlowMode,
+    hash: Arc<dyn CollisionHash>,
+    layout: InputLayout,
+    steps: Vec<Box<dyn SubFunction>>,
+    internal_key: InternalKey,
+    refund: Option<RefundLeaf>,
+    signers: Option<SignerCheck>,
+    anchors: bool,
+    sighash_type: TapSighashType,
+    payout: PayoutTemplate,
+}
+
+impl FlowBuilder {
+    pub fn new(b_bits: usize, network: Network, fee_rate: u64) -> Self {
+        Self {
+            b_bits,
+            network,
+            fee_rate,
+            mode: FlowMode::default(),
+            hash: Arc::new(Blake3Hash::default()),
+            layout: InputLayout::default(),
+            steps: Vec::new(),
+            internal_key: InternalKey::default(),
+            refund: None,
+            signers: None,
+            anchors: false,
+            sighash_type: TapSighashType::Default,
+            payout: PayoutTemplate::default(),
+        }
+    }
+
+    /// Select the transaction layout of the flow.
+    pub fn mode(mut self, mode: FlowMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Select the collision hash `H` (BLAKE3 by default).
+    pub fn hash(mut self, hash: impl CollisionHash + 'static) -> Self {
+        self.hash = Arc::new(hash);
+        self
+    }
+
+    /// The collision hash `H` checked by the flows.
+    pub fn collision_hash(&self) -> &dyn CollisionHash {
+        self.hash.as_ref()
+    }
+
+    /// Select the layout of the input `x` (a single `u32` by default).
+    pub fn input_layout(mut self, layout: InputLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Select the internal key of the flow outputs (the signers' key by
+    /// default, which can bypass the locks through the key path).
+    pub fn internal_key(mut self, internal_key: InternalKey) -> Self {
+        self.internal_key = internal_key;
+        self
+    }
+
+    /// Add a timelocked refund leaf next to every lock, so the deposit can
+    /// be recovered if the flow is never completed.
+    pub fn refund(mut self, refund: RefundLeaf) -> Self {
+        self.refund = Some(refund);
+        self
+    }
+
+    /// Add a pay-to-anchor output to every flow transaction, so that the
+    /// operator can raise the fee fixed at setup time with a CPFP child, see
+    /// [`crate::transactions::create_anchor_child_tx`]. The transactions
+    /// that fit in [`crate::transactions::TRUC_MAX_VSIZE`] become v3
+    /// (TRUC): each one has to confirm before the next one is broadcast.
+    pub fn anchors(mut self, anchors: bool) -> Self {
+        self.anchors = anchors;
+        self
+    }
+
+    /// Select the sighash type of the presigned signatures of the last
+    /// transaction (the default one, committing to the whole transaction,
+    /// unless set). `ALL|ANYONECANPAY` and `SINGLE|ANYONECANPAY` let the
+    /// operator add a fee input to it at broadcast time, see
+    /// [`Flow::add_fee_input`]. The earlier transactions always use the
+    /// default type, see [`Flow::tx_sighash_type`].
+    pub fn sighash_type(mut self, sighash_type: TapSighashType) -> Self {
+        self.sighash_type = sighash_type;
+        self
+    }
+
+    /// Select the outputs of the last transaction (everything to the
+    /// receiver by default), e.g. to pay an operator reward and a protocol
+    /// fee next to the withdrawal.
+    pub fn payout(mut self, payout: PayoutTemplate) -> Self {
+        self.payout = payout;
+        self
+    }
+
+    /// Select the signatures checked by every lock (the MuSig2 signature of
+    /// `pk_signer` by default). The funding spend always stays on the
+    /// aggregated key.
+    pub fn signer_check(mut self, signers: SignerCheck) -> Self {
+        self.signers = Some(signers);
+        self
+    }
+
+    /// Append sub-function `f_{k+1}` to the program checked by the flow.
+    pub fn step(mut self, sub_function: impl SubFunction + 'static) -> Self {
+        self.steps.push(Box::new(sub_function));
+        self
+    }
+
+    /// Number of sub-functions `k`.
+    pub fn k(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Indices of the sub-functions checked by each step of the flow.
+    pub fn step_layout(&self) -> Vec<Vec<usize>> {
+        let k = self.k();
+        match self.mode {
+            FlowMode::DoubleCollision => (0..k).map(|i| vec![i]).collect(),
+            FlowMode::TripleCollision => (0..k)
+                .flat_map(|i| (i + 1..k).map(move |j| vec![i, j]))
+                .collect(),
+        }
+    }
+
+    /// Off-chain evaluation of `f(x) = f_1(x) ∧ … ∧ f_k(x)`, false if `x`
+    /// does not follow the input layout.
+    pub fn evaluate(&self, x: &Input) -> bool {
+        self.evaluate_steps(x).into_iter().all(|holds| holds)
+    }
+
+    /// Off-chain evaluation of every `f_i(x)`, all false if `x` does not
+    /// follow the input layout.
+    pub fn evaluate_steps(&self, x: &Input) -> Vec<bool> {
+        self.steps
+            .iter()
+            .map(|step| {
+                *x.layout() == self.layout
+                    && x.number(step.field())
+                        .is_ok_and(|field| step.evaluate(field))
+            })
+            .collect()
+    }
+
+    /// Build the unsigned transaction chain of flow `flow_id_prefix`,
+    /// spending `funding_outpoint` and paying the rest to `receiver_addr`.
+    ///
+    /// The funding UTXO is assumed to pay `funding_value_sat` to
+    /// [`crate::transactions::funding_address`].
+    pub fn build(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        pk_signer: &PublicKey,
+        funding_outpoint: &OutPoint,
+        funding_value_sat: &u64,
+        flow_id_prefix: &[u8],
+        receiver_addr: &Address,
+    ) -> anyhow::Result<Flow> {
+        let funding = FundingUtxo {
+            outpoint: *funding_outpoint,
+            prevout: TxOut {
+                value: Amount::from_sat(*funding_value_sat),
+                script_pubkey: funding_address(secp, pk_signer, self.network)
+                    .script_pubkey(),
+            },
+        };
+        self.build_with_funding(
+            secp,
+            pk_signer,
+            &[funding],
+            flow_id_prefix,
+            receiver_addr,
+        )
+    }
+
+    /// Same as [`FlowBuilder::build`], spending every UTXO of `funding` in
+    /// the first transaction.
+    ///
+    /// Fails, naming the [`Flow::required_funding`], if the UTXOs cannot pay
+    /// the fees of the whole flow.
+    pub fn build_with_funding(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        pk_signer: &PublicKey,
+        funding: &[FundingUtxo],
+        flow_id_prefix: &[u8],
+        receiver_addr: &Address,
+    ) -> anyhow::Result<Flow> {
+        self.build_chain(
+            secp,
+            pk_signer,
+            funding,
+            flow_id_prefix,
+            receiver_addr,
+        )
+        .map_err(|err| {
+            let deposit = funding
+                .iter()
+                .map(|utxo| utxo.prevout.value.to_sat())
+                .sum::<u64>();
+            match self.required_funding(
+                secp,
+                pk_signer,
+                funding.len(),
+                flow_id_prefix,
+                receiver_addr,
+            ) {
+                Ok(required) if deposit < required => anyhow::anyhow!(
+                    "the funding UTXOs hold {deposit} sat, the flow needs \
+                         {required} sat"
+                ),
+                _ => err,
+            }
+        })
+    }
+
+    /// [`Flow::required_funding`] of flow `flow_id_prefix` funded by
+    /// `num_utxos` UTXOs, to be deposited before the flows are built.
+    pub fn required_funding(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        pk_signer: &PublicKey,
+        num_utxos: usize,
+        flow_id_prefix: &[u8],
+        receiver_addr: &Address,
+    ) -> anyhow::Result<u64> {
+        anyhow::ensure!(num_utxos > 0, "no funding UTXO");
+        // Any amount large enough for the fees gives the same transactions
+        let script_pubkey =
+            funding_address(secp, pk_signer, self.network).script_pubkey();
+        let funding = (0..num_utxos as u32)
+            .map(|vout| FundingUtxo {
+                outpoint: OutPoint {
+                    txid: Txid::all_zeros(),
+                    vout,
+                },
+                prevout: TxOut {
+                    value: Amount::MAX_MONEY / num_utxos as u64,
+                    script_pubkey: script_pubkey.clone(),
+                },
+            })
+            .collect::<Vec<_>>();
+        let flow = self.build_chain(
+            secp,
+            pk_signer,
+            &funding,
+            flow_id_prefix,
+            receiver_addr,
+        )?;
+        Ok(flow.required_funding())
+    }
+
+    fn build_chain(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        pk_signer: &PublicKey,
+        funding: &[FundingUtxo],
+        flow_id_prefix: &[u8],
+        receiver_addr: &Address,
+    ) -> anyhow::Result<Flow> {
+        let layout = self.step_layout();
+        anyhow::ensure!(
+            !layout.is_empty(),
+            "a {:?} flow with k={} has no steps",
+            self.mode,
+            self.k()
+        );
+        for (i, step) in self.steps.iter().enumerate() {
+            self.layout
+                .number_width(step.field())
+                .map_err(|err| anyhow::anyhow!("f_{}: {err}", i + 1))?;
+        }
+        // Only the ANYONECANPAY types add anything over the default one, and
+        // NONE would leave the outputs uncommitted for anyone to redirect
+        anyhow::ensure!(
+            matches!(
+                self.sighash_type,
+                TapSighashType::Default
+                    | TapSighashType::AllPlusAnyoneCanPay
+                    | TapSighashType::SinglePlusAnyoneCanPay
+            ),
+            "cannot presign flows with {}",
+            self.sighash_type
+        );
+        // SINGLE would leave the outputs after the withdrawal uncommitted
+        anyhow::ensure!(
+            self.sighash_type != TapSighashType::SinglePlusAnyoneCanPay
+                || self.payout.outputs().len() == 1,
+            "{} only commits to the first of {} payout outputs",
+            self.sighash_type,
+            self.payout.outputs().len()
+        );
+
+        let signers = self.signers.clone().unwrap_or_else(|| {
+            SignerCheck::Aggregated(bitcoin::PublicKey::new(*pk_signer))
+        });
+        self.hash
+            .check_layout(
+                &self.layout,
+                self.b_bits,
+                signers.max_witness_sizes(self.sighash_type).len(),
+            )
+            .map_err(anyhow::Error::msg)?;
+        let (locks, segments): (Vec<_>, Vec<_>) = layout
+            .iter()
+            .map(|indices| {
+                let sub_functions = indices
+                    .iter()
+                    .map(|&i| self.steps[i].as_ref())
+                    .collect::<Vec<_>>();
+                build_script_step_segments_with_signers(
+                    &signers,
+                    self.hash.as_ref(),
+                    &self.layout,
+                    &sub_functions,
+                    flow_id_prefix,
+                    self.b_bits,
+                )
+            })
+            .unzip();
+
+        let mut txs = Vec::with_capacity(locks.len() + 1);
+
+        let (tx, mut spend_info, funding_script, funding_spend_info, sighashes) =
+            create_lock_tx_from_utxos(
+                secp,
+                pk_signer,
+                &self.network,
+                funding,
+                &locks[0],
+                &self.internal_key,
+                self.refund.as_ref(),
+                self.anchors,
+                &self.fee_rate,
+            )?;
+        txs.push(FlowTx {
+            tx,
+            sighashes,
+            spent_script: funding_script,
+            prevouts: funding.iter().map(|utxo| utxo.prevout.clone()).collect(),
+            spent_spend_info: funding_spend_info,
+        });
+
+        // Every lock is spent with a witness of the same layout, the last
+        // one with signatures of the selected sighash type
+        let hash_sizes = self.hash.max_witness_sizes(&self.layout, self.b_bits);
+        let stack_sizes = |sighash_type| {
+            let mut sizes = hash_sizes.clone();
+            sizes.extend(signers.max_witness_sizes(sighash_type));
+            sizes
+        };
+        let lock_sizes = stack_sizes(TapSighashType::Default);
+
+        for (prev_lock, next_lock) in locks.iter().zip(locks.iter().skip(1)) {
+            let prev_tx = &txs.last().unwrap().tx;
+            let prevout = prev_tx.output[0].clone();
+            let spent_value = prevout.value.to_sat();
+            let (tx, next_spend_info, sighash) = create_lock_tx(
+                secp,
+                pk_signer,
+                &self.network,
+                prev_tx,
+                &spent_value,
+                prev_lock,
+                &max_spend_witness(&lock_sizes, prev_lock, &spend_info),
+                next_lock,
+                &self.internal_key,
+                self.refund.as_ref(),
+                self.anchors,
+                &self.fee_rate,
+            )?;
+            txs.push(FlowTx {
+                tx,
+                sighashes: vec![sighash],
+                spent_script: prev_lock.clone(),
+                prevouts: vec![prevout],
+                spent_spend_info: spend_info,
+            });
+            spend_info = next_spend_info;
+        }
+
+        let last_lock = locks.last().unwrap();
+        let prev_tx = &txs.last().unwrap().tx;
+        let prevout = prev_tx.output[0].clone();
+        let spent_value = prevout.value.to_sat();
+        let (tx, sighash) = create_payout_tx(
+            prev_tx,
+            &spent_value,
+            &self.payout,
+            receiver_addr,
+            last_lock,
+            &max_spend_witness(
+                &stack_sizes(self.sighash_type),
+                last_lock,
+                &spend_info,
+            ),
+            self.sighash_type,
+            self.anchors,
+            &self.fee_rate,
+        )?;
+        txs.push(FlowTx {
+            tx,
+            sighashes: vec![sighash],
+            spent_script: last_lock.clone(),
+            prevouts: vec![prevout],
+            spent_spend_info: spend_info,
+        });
+
+        Ok(Flow {
+            txs,
+            locks,
+            segments,
+            hash: self.hash.clone(),
+            b_bits: self.b_bits,
+            layout: self.layout.clone(),
+            internal_key: self.internal_key,
+            refund: self.refund,
+            signers,
+            sighash_type: self.sighash_type,
+            payout: self.payout.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::analyze_spend;
+    use crate::core::{
+        F1_THRESHOLD, F2_THRESHOLD, GreaterThan, LessThan, OnField,
+        SegmentKind, find_valid_nonce, find_valid_nonce_in_set,
+        flow_id_to_prefix_bytes,
+    };
+    use crate::flow_set::LowRange;
+    use crate::hash::{Blake3CompactHash, Sha256Hash};
+    use crate::input::{Field, FieldValue};
+    use crate::interpreter::{Interpreter, tapscript_spend};
+    use crate::musig2::{generate_keys, simulate_musig2};
+    use crate::transactions::{
+        P2A_DUST_SAT, anchor_script, create_anchor_child_tx,
+        placeholder_witness,
+    };
+    use crate::utils::inner_from;
+    use bitcoin::Sequence;
+    use bitcoin::taproot::LeafVersion;
+    use bitcoin::transaction::Version;
+    use bitvm::dry_run_taproot_input;
+    use std::str::FromStr;
+
+    const L: usize = 4;
+    const B: usize = 16;
+
+    /// Two MuSig2 signers, funding flows from one 200 000 sat output, and
+    /// the receiver of the payout.
+    struct Fixture {
+        secp: Secp256k1<secp256k1::All>,
+        sk_signers:
+            [(musig2::secp256k1::SecretKey, musig2::secp256k1::PublicKey); 2],
+        pk_signer: PublicKey,
+        receiver_addr: Address,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let sk_signers = generate_keys::<2>();
+            let pk_signers =
+                sk_signers.iter().map(|key| key.1).collect::<Vec<_>>();
+            let agg_ctx = musig2::KeyAggContext::new(pk_signers).unwrap();
+            let pk_signer: musig2::secp256k1::PublicKey =
+                agg_ctx.aggregated_pubkey();
+            let receiver_addr = Address::from_str(
+                "bcrt1qz3fps2lxvrp5rqj8ucsqrzjx2c3md9gawqr3l6",
+            )
+            .unwrap()
+            .require_network(Network::Regtest)
+            .unwrap();
+            Self {
+                secp: Secp256k1::new(),
+                sk_signers,
+                pk_signer: inner_from(pk_signer),
+                receiver_addr,
+            }
+        }
+
+        /// Unsigned flow of `builder` for `flow_id`.
+        fn build(
+            &self,
+            builder: &FlowBuilder,
+            flow_id: FlowId,
+        ) -> anyhow::Result<Flow> {
+            self.build_funded(builder, flow_id, 200_000)
+        }
+
+        /// Unsigned flow of `builder` for `flow_id`, funded with
+        /// `funding_value_sat`.
+        fn build_funded(
+            &self,
+            builder: &FlowBuilder,
+            flow_id: FlowId,
+            funding_value_sat: u64,
+        ) -> anyhow::Result<Flow> {
+            let funding_outpoint = OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0,
+            };
+            builder.build(
+                &self.secp,
+                &self.pk_signer,
+                &funding_outpoint,
+                &funding_value_sat,
+                &flow_id_to_prefix_bytes(flow_id, B),
+                &self.receiver_addr,
+            )
+        }
+
+        /// MuSig2 signatures of every sighash of `flow`.
+        fn sign(&self, flow: &Flow) -> Vec<LiftedSignature> {
+            flow.sighashes()
+                .iter()
+                .map(|sighash| {
+                    simulate_musig2(&self.sk_signers, sighash).unwrap()
+                })
+                .collect()
+        }
+
+        /// UTXOs of `values` paying to the funding address of the signers.
+        fn funding_utxos(&self, values: &[u64]) -> Vec<FundingUtxo> {
+            let script_pubkey =
+                funding_address(&self.secp, &self.pk_signer, Network::Regtest)
+                    .script_pubkey();
+            (0..)
+                .zip(values)
+                .map(|(vout, &value)| FundingUtxo {
+                    outpoint: OutPoint {
+                        txid: Txid::all_zeros(),
+                        vout,
+                    },
+                    prevout: TxOut {
+                        value: Amount::from_sat(value),
+                        script_pubkey: script_pubkey.clone(),
+                    },
+                })
+                .collect()
+        }
+    }
+
+    /// Nonce and flow id of `x` among the `2^L` flows of `builder`.
+    fn find_nonce(builder: &FlowBuilder, x: &Input) -> (u64, FlowId) {
+        find_valid_nonce_in_set(
+            x,
+            &LowRange::new(B, L),
+            builder.collision_hash(),
+        )
+        .unwrap()
+    }
+
+    fn build_signed_flow(
+        builder: &FlowBuilder,
+        x: impl TryInto<Input, Error: std::fmt::Debug>,
+    ) -> Flow {
+        let x = x.try_into().unwrap();
+        let fixture = Fixture::new();
+        let (nonce, flow_id) = find_nonce(builder, &x);
+        let mut flow = fixture.build(builder, flow_id).unwrap();
+        flow.finalize(&fixture.sign(&flow), &x, &nonce).unwrap();
+        flow
+    }
+
+    fn dry_run_flow(flow: &Flow) -> Vec<bool> {
+        let txs = flow.transactions();
+        txs.iter()
+            .zip(txs.iter().skip(1))
+            .map(|(prev_tx, tx)| {
+                dry_run_taproot_input(tx, 0, &prev_tx.output).success
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_three_step_flow() {
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD))
+            .step(GreaterThan(110));
+        let x = 114;
+        assert!(builder.evaluate(&Input::try_from(x).unwrap()));
+
+        let flow = build_signed_flow(&builder, x);
+        assert_eq!(flow.locks.len(), 3);
+        assert_eq!(flow.txs.len(), 4);
+        assert!(dry_run_flow(&flow).into_iter().all(|success| success));
+    }
+
+    #[test]
+    fn test_flow_rejects_failing_step() {
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD))
+            .step(GreaterThan(150));
+        let x = 114;
+        assert!(!builder.evaluate(&Input::try_from(x).unwrap()));
+
+        let flow = build_signed_flow(&builder, x);
+        assert_eq!(dry_run_flow(&flow), vec![true, true, false]);
+    }
+
+    #[test]
+    fn test_offline_setup_online_execution() {
+        let fixture = Fixture::new();
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+
+        let flows = offline_setup(
+            &builder,
+            &fixture.secp,
+            &fixture.sk_signers,
+            &LowRange::new(B, L),
+            &fixture.funding_utxos(&[200_000]),
+            &fixture.receiver_addr,
+        )
+        .unwrap();
+        assert_eq!(flows.len(), 1 << L);
+        assert!(flows.iter().all(|(d, flow)| *d == flow.flow_id));
+
+        let x = 114;
+        let (nonce, flow_id, txs) = online_execution(
+            &flows,
+            &Input::try_from(x).unwrap(),
+            &LowRange::new(B, L),
+            2,
+        )
+        .unwrap();
+        assert_eq!(find_valid_nonce(x, B, L).unwrap(), (nonce, flow_id));
+        assert_eq!(txs.len(), 3);
+        for (prev_tx, tx) in txs.iter().zip(txs.iter().skip(1)) {
+            assert!(dry_run_taproot_input(tx, 0, &prev_tx.output).success);
+        }
+    }
+
+    #[test]
+    fn test_triple_collision_layout() {
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .mode(FlowMode::TripleCollision)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD))
+            .step(GreaterThan(110));
+        assert_eq!(
+            builder.step_layout(),
+            vec![vec![0, 1], vec![0, 2], vec![1, 2]]
+        );
+
+        let x = 114;
+        let flow = build_signed_flow(&builder, x);
+        assert_eq!(flow.txs.len(), 4);
+        assert!(dry_run_flow(&flow).into_iter().all(|success| success));
+    }
+
+    #[test]
+    fn test_triple_collision_cost() {
+        let x = 114;
+        let double = FlowBuilder::new(B, Network::Regtest, 1)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+        let triple = FlowBuilder::new(B, Network::Regtest, 1)
+            .mode(FlowMode::TripleCollision)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+
+        let double_flow = build_signed_flow(&double, x);
+        let triple_flow = build_signed_flow(&triple, x);
+        // (2 choose 2) = 1 step checking both F1 and F2, hashing once
+        assert_eq!(triple_flow.locks.len(), 1);
+        assert_eq!(triple_flow.txs.len() + 1, double_flow.txs.len());
+        assert!(triple_flow.total_vsize() < double_flow.total_vsize());
+        assert!(
+            dry_run_flow(&triple_flow)
+                .into_iter()
+                .all(|success| success)
+        );
+    }
+
+    #[test]
+    fn test_sha256_flow_cost() {
+        let x = 114;
+        let blake3 = FlowBuilder::new(B, Network::Regtest, 1)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+        let sha256 = FlowBuilder::new(B, Network::Regtest, 1)
+            .hash(Sha256Hash)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+
+        let blake3_flow = build_signed_flow(&blake3, x);
+        let sha256_flow = build_signed_flow(&sha256, x);
+        assert_eq!(sha256_flow.total_vsize(), 426);
+        assert!(sha256_flow.total_vsize() * 10 < blake3_flow.total_vsize());
+        assert!(
+            dry_run_flow(&sha256_flow)
+                .into_iter()
+                .all(|success| success)
+        );
+
+        let flow = build_signed_flow(&sha256, 99);
+        assert_eq!(dry_run_flow(&flow), vec![false, true]);
+        assert_eq!(
+            flow.trace(0, TraceDetail::Segments)
+                .unwrap()
+                .failed_segment(),
+            Some(SegmentKind::SubFunction(0))
+        );
+        assert!(flow.trace(1, TraceDetail::Segments).unwrap().success());
+    }
+
+    #[test]
+    fn test_blake3_compact_flow_cost() {
+        let x = 114;
+        let builder = |hash| {
+            let mut builder = FlowBuilder::new(B, Network::Regtest, 1)
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(LessThan(F2_THRESHOLD));
+            builder.hash = hash;
+            builder
+        };
+        let limbs =
+            build_signed_flow(&builder(Arc::new(Blake3Hash::default())), x);
+        let compact =
+            build_signed_flow(&builder(Arc::new(Blake3CompactHash)), x);
+        assert!(dry_run_flow(&compact).into_iter().all(|success| success));
+
+        // Fewer witness elements, but the expansion script outweighs them,
+        // see test_blake3_compact_witness_cost in hash.rs
+        for i in [1, 2] {
+            let (limbs_tx, compact_tx) = (&limbs.txs[i].tx, &compact.txs[i].tx);
+            assert!(
+                compact_tx.input[0].witness.len()
+                    < limbs_tx.input[0].witness.len()
+            );
+            assert!(compact_tx.vsize() > limbs_tx.vsize());
+        }
+        let flow = build_signed_flow(&builder(Arc::new(Blake3CompactHash)), 99);
+        assert_eq!(dry_run_flow(&flow), vec![false, true]);
+    }
+
+    #[test]
+    fn test_blake3_limb_len_weight() {
+        // The vsize of each is printed by the blake3_limb_len example
+        let x = 114;
+        for limb_len in [4, 5, 6, 7, 8, 10, 12, 16, 20, 24, 29] {
+            let builder = FlowBuilder::new(B, Network::Regtest, 1)
+                .hash(Blake3Hash::with_limb_len(limb_len).unwrap())
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(LessThan(F2_THRESHOLD));
+            let flow = build_signed_flow(&builder, x);
+            assert!(dry_run_flow(&flow).into_iter().all(|success| success));
+        }
+    }
+
+    #[test]
+    fn test_multi_field_flow() {
+        let layout = InputLayout::new(vec![
+            Field::Number(4),
+            Field::Bytes(32),
+            Field::Number(2),
+        ])
+        .unwrap();
+        let input = |amount| {
+            layout
+                .encode(&[
+                    FieldValue::Number(114),
+                    FieldValue::Bytes(vec![0xab; 32]),
+                    FieldValue::Number(amount),
+                ])
+                .unwrap()
+        };
+        for hash in [
+            Arc::new(Blake3Hash::default()) as Arc<dyn CollisionHash>,
+            Arc::new(Sha256Hash),
+        ] {
+            let mut builder = FlowBuilder::new(B, Network::Regtest, 1)
+                .input_layout(layout.clone())
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(OnField(2, LessThan(1_000)));
+            builder.hash = hash;
+
+            assert!(builder.evaluate(&input(500)));
+            assert!(!builder.evaluate(&input(5_000)));
+            assert!(!builder.evaluate(&Input::try_from(114).unwrap()));
+
+            let flow = build_signed_flow(&builder, input(500));
+            assert!(dry_run_flow(&flow).into_iter().all(|success| success));
+            let flow = build_signed_flow(&builder, input(5_000));
+            assert_eq!(dry_run_flow(&flow), vec![true, false]);
+        }
+
+        // Sub-functions can only read number fields
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .input_layout(layout.clone())
+            .step(OnField(1, GreaterThan(0)));
+        let fixture = Fixture::new();
+        assert!(
+            offline_setup(
+                &builder,
+                &fixture.secp,
+                &fixture.sk_signers,
+                &LowRange::new(B, L),
+                &fixture.funding_utxos(&[200_000]),
+                &fixture.receiver_addr,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_refund_leaf() {
+        let fixture = Fixture::new();
+        let secp = &fixture.secp;
+        let keypair =
+            secp256k1::Keypair::from_seckey_slice(secp, &[7; 32]).unwrap();
+        let refund =
+            RefundLeaf::new(keypair.x_only_public_key().0, 144).unwrap();
+        assert!(RefundLeaf::new(keypair.x_only_public_key().0, 0).is_err());
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .hash(Sha256Hash)
+            .refund(refund)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+        let refund_addr = &fixture.receiver_addr;
+        let flow = fixture.build(&builder, 0).unwrap();
+
+        for step in 0..flow.locks.len() {
+            // Both leaves are committed to by the refunded output
+            let spend_info = &flow.txs[step + 1].spent_spend_info;
+            let output_key = spend_info.output_key().to_x_only_public_key();
+            for script in [&flow.locks[step], &refund.script()] {
+                let control_block = spend_info
+                    .control_block(&(script.clone(), LeafVersion::TapScript))
+                    .unwrap();
+                assert!(
+                    control_block
+                        .verify_taproot_commitment(secp, output_key, script)
+                );
+            }
+
+            let (mut refund_tx, sighash) =
+                flow.refund_tx(step, refund_addr, &1).unwrap();
+            assert_eq!(refund_tx.input[0].sequence, Sequence::from_height(144));
+            let sig = secp.sign_schnorr(&sighash, &keypair);
+            secp.verify_schnorr(&sig, &sighash, &refund.key()).unwrap();
+            flow.finalize_refund(step, &mut refund_tx, &sig).unwrap();
+
+            let (script, witness) = tapscript_spend(&refund_tx, 0).unwrap();
+            assert_eq!(script, refund.script());
+            let mut interpreter =
+                Interpreter::new(&script, witness).unwrap().with_spend(
+                    &refund_tx,
+                    0,
+                    std::slice::from_ref(&flow.txs[step].tx.output[0]),
+                );
+            interpreter.run().unwrap();
+        }
+        assert!(flow.refund_tx(flow.locks.len(), refund_addr, &1).is_err());
+
+        let builder = FlowBuilder::new(B, Network::Regtest, 1)
+            .hash(Sha256Hash)
+            .step(GreaterThan(F1_THRESHOLD));
+        let flow = fixture.build(&builder, 0).unwrap();
+        assert!(flow.refund_tx(0, refund_addr, &1).is_err());
+    }
+
+    #[test]
+    fn test_fees_match_weight() {
+        let fee_rate = 7;
+        let builder = || {
+            FlowBuilder::new(B, Network::Regtest, fee_rate)
+                .hash(Sha256Hash)
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(LessThan(F2_THRESHOLD))
+                .step(GreaterThan(110))
+        };
+        let fees = |flow: &Flow, fee_rate: u64| {
+            let mut value = 200_000;
+            flow.transactions()
+                .iter()
+                .map(|tx| {
+                    let fee = value - tx.output[0].value.to_sat();
+                    value = tx.output[0].value.to_sat();
+                    (fee, tx.vsize() as u64 * fee_rate)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The largest input fills the witness up to its bound
+        let x = InputLayout::default().max_input();
+        for (fee, actual) in fees(&build_signed_flow(&builder(), x), fee_rate) {
+            assert_eq!(fee, actual);
+        }
+        // Shorter script numbers save a few weight units
+        for (fee, actual) in fees(&build_signed_flow(&builder(), 114), fee_rate)
+        {
+            assert!(fee >= actual && fee - actual <= fee_rate);
+        }
+        // BLAKE3 pushes zero nibbles as empty elements: the 8 of x = 0 save
+        // 2 vbytes, and at most every nibble of x || r could be empty
+        let blake3 = FlowBuilder::new(B, Network::Regtest, 1)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+        let max_saving = Blake3Hash::default()
+            .max_witness_sizes(&InputLayout::default(), B)
+            .iter()
+            .sum::<usize>()
+            .div_ceil(4) as u64;
+        assert_eq!(max_saving, 6);
+        let blake3_fees = fees(&build_signed_flow(&blake3, 0), 1);
+        let (funding, steps) = blake3_fees.split_first().unwrap();
+        assert_eq!(funding.0, funding.1);
+        for (fee, actual) in steps {
+            assert!(fee - actual >= 2 && fee - actual <= max_saving);
+        }
+
+        let secp = Secp256k1::new();
+        let keypair =
+            secp256k1::Keypair::from_seckey_slice(&secp, &[7; 32]).unwrap();
+        let refund =
+            RefundLeaf::new(keypair.x_only_public_key().0, 144).unwrap();
+        let flow = build_signed_flow(&builder().refund(refund), 114);
+        let refund_addr = flow.transactions().last().unwrap().output[0]
+            .script_pubkey
+            .clone();
+        let refund_addr =
+            Address::from_script(&refund_addr, Network::Regtest).unwrap();
+        let (mut refund_tx, sighash) =
+            flow.refund_tx(1, &refund_addr, &fee_rate).unwrap();
+        let sig = secp.sign_schnorr(&sighash, &keypair);
+        flow.finalize_refund(1, &mut refund_tx, &sig).unwrap();
+        let fee = flow.txs[1].tx.output[0].value - refund_tx.output[0].value;
+        assert_eq!(fee.to_sat(), refund_tx.vsize() as u64 * fee_rate);
+    }
+
+    #[test]
+    fn test_anchor_cpfp() {
+        let builder = |anchors| {
+            FlowBuilder::new(B, Network::Regtest, 1)
+                .hash(Sha256Hash)
+                .anchors(anchors)
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(LessThan(F2_THRESHOLD))
+        };
+        let x = InputLayout::default().max_input();
+        let flow = build_signed_flow(&builder(true), x.clone());
+        for (flow_tx, fee) in flow.txs.iter().zip(flow.fees()) {
+            let tx = &flow_tx.tx;
+            assert_eq!(tx.version, Version(3));
+            assert_eq!(tx.output[1].script_pubkey, anchor_script());
+            assert_eq!(tx.output[1].value.to_sat(), P2A_DUST_SAT);
+            assert_eq!(fee, tx.vsize() as u64);
+        }
+
+        // The operator bumps f1 to 20 sat/vB with a P2TR UTXO of theirs
+        let change_addr = Fixture::new().receiver_addr;
+        let fee_outpoint = OutPoint {
+            txid: Txid::all_zeros(),
+            vout: 1,
+        };
+        let fee_prevout = TxOut {
+            value: Amount::from_sat(50_000),
+            script_pubkey: change_addr.script_pubkey(),
+        };
+        let fee_witness = placeholder_witness([64]);
+        let parent = &flow.txs[1].tx;
+        let mut child = create_anchor_child_tx(
+            parent,
+            flow.fees()[1],
+            &fee_outpoint,
+            &fee_prevout,
+            &fee_witness,
+            &change_addr,
+            20,
+        )
+        .unwrap();
+        assert_eq!(child.version, Version(3));
+        assert_eq!(child.input[1].previous_output.txid, parent.compute_txid());
+        assert_eq!(child.input[1].previous_output.vout, 1);
+        child.input[0].witness = fee_witness.clone();
+        let child_fee = 50_000 + P2A_DUST_SAT - child.output[0].value.to_sat();
+        assert_eq!(
+            flow.fees()[1] + child_fee,
+            20 * (parent.vsize() + child.vsize()) as u64
+        );
+
+        // A child cannot make up for a too small fee input
+        let small_prevout = TxOut {
+            value: Amount::from_sat(100),
+            ..fee_prevout.clone()
+        };
+        assert!(
+            create_anchor_child_tx(
+                parent,
+                flow.fees()[1],
+                &fee_outpoint,
+                &small_prevout,
+                &fee_witness,
+                &change_addr,
+                20,
+            )
+            .is_err()
+        );
+
+        let flow = build_signed_flow(&builder(false), x);
+        assert!(flow.txs.iter().all(|flow_tx| flow_tx.tx.output.len() == 1));
+        assert!(
+            create_anchor_child_tx(
+                &flow.txs[1].tx,
+                flow.fees()[1],
+                &fee_outpoint,
+                &fee_prevout,
+                &fee_witness,
+                &change_addr,
+                20,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_funding_utxos() {
+        let fixture = Fixture::new();
+        let (secp, pk_signer) = (&fixture.secp, &fixture.pk_signer);
+        let receiver_addr = &fixture.receiver_addr;
+        let builder = FlowBuilder::new(B, Network::Regtest, 3)
+            .hash(Sha256Hash)
+            .anchors(true)
+            .step(GreaterThan(F1_THRESHOLD))
+            .step(LessThan(F2_THRESHOLD));
+        let x = Input::try_from(114).unwrap();
+        let (nonce, flow_id) = find_nonce(&builder, &x);
+        let flow_id_prefix = flow_id_to_prefix_bytes(flow_id, B);
+        let build = |funding: &[FundingUtxo]| {
+            builder.build_with_funding(
+                secp,
+                pk_signer,
+                funding,
+                &flow_id_prefix,
+                receiver_addr,
+            )
+        };
+
+        let required_funding = |num_utxos| {
+            builder
+                .required_funding(
+                    secp,
+                    pk_signer,
+                    num_utxos,
+                    &flow_id_prefix,
+                    receiver_addr,
+                )
+                .unwrap()
+        };
+        let required = required_funding(2);
+        // Every input of the funding spend adds to the fee chain
+        assert!(required > required_funding(1));
+
+        let half = required / 2;
+        let mut flow =
+            build(&fixture.funding_utxos(&[half, required - half])).unwrap();
+        assert_eq!(flow.required_funding(), required);
+        let payout = &flow.txs.last().unwrap().tx.output[0];
+        assert_eq!(payout.value, payout.script_pubkey.minimal_non_dust());
+
+        let err = build(&fixture.funding_utxos(&[half, required - half - 1]))
+            .unwrap_err();
+        assert!(err.to_string().contains(&format!("needs {required} sat")));
+        let mut foreign = fixture.funding_utxos(&[half, required - half]);
+        foreign[1].prevout.script_pubkey = receiver_addr.script_pubkey();
+        assert!(build(&foreign).is_err());
+
+        // One signature per funding input, then one per step
+        assert_eq!(flow.txs[0].tx.input.len(), 2);
+        let sighashes = flow.sighashes();
+        assert_eq!(sighashes.len(), flow.txs.len() + 1);
+        assert_ne!(sighashes[0], sighashes[1]);
+        let signatures = fixture.sign(&flow);
+        assert!(flow.finalize(&signatures[1..], &x, &nonce).is_err());
+        flow.finalize(&signatures, &x, &nonce).unwrap();
+        let funding = &flow.txs[0];
+        for input in 0..2 {
+            let report =
+                analyze_spend(&funding.tx, input, &funding.prevouts).unwrap();
+            assert!(report.is_ok(), "{report}");
+        }
+        for step in 0..flow.locks.len() {
+            let report = flow.analyze(step).unwrap();
+            assert!(report.is_ok(), "{report}");
+        }
+        for (flow_tx, fee) in flow.txs.iter().zip(flow.fees()) {
+            assert!(fee >= 3 * flow_tx.tx.vsize() as u64);
+        }
+    }
+
+    #[test]
+    fn test_payout_outputs() {
+        let p2wpkh = |byte| {
+            ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array(
+                [byte; 20],
+            ))
+        };
+        let operator = TxOut {
+            value: Amount::from_sat(5_000),
+            script_pubkey: p2wpkh(7),
+        };
+        let protocol = TxOut {
+            value: Amount::from_sat(1_000),
+            script_pubkey: p2wpkh(9),
+        };
+        let payout = PayoutTemplate::new(vec![
+            PayoutOutput::Receiver,
+            PayoutOutput::Fixed(operator.clone()),
+            PayoutOutput::Fixed(protocol.clone()),
+        ])
+        .unwrap();
+        let builder = || {
+            FlowBuilder::new(B, Network::Regtest, 1)
+                .hash(Sha256Hash)
+                .anchors(true)
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(LessThan(F2_THRESHOLD))
+        };
+
+        let flow = build_signed_flow(&builder().payout(payout.clone()), 114);
+        let default_flow = build_signed_flow(&builder(), 114);
+        let last = flow.txs.last().unwrap();
+        let default_last = default_flow.txs.last().unwrap();
+        assert_eq!(last.tx.output.len(), 4);
+        assert_eq!(last.tx.output[1], operator);
+        assert_eq!(last.tx.output[2], protocol);
+        assert_eq!(last.tx.output[3].script_pubkey, anchor_script());
+        // The fixed outputs come out of the withdrawal, their weight too
+        let fee_diff = (last.tx.vsize() - default_last.tx.vsize()) as u64;
+        assert_eq!(
+            last.tx.output[0].value.to_sat() + 6_000 + fee_diff,
+            default_last.tx.output[0].value.to_sat()
+        );
+        assert_eq!(
+            flow.required_funding(),
+            default_flow.required_funding() + 6_000 + fee_diff
+        );
+        let report = flow.analyze(flow.locks.len() - 1).unwrap();
+        assert!(report.is_ok(), "{report}");
+
+        // The presigned signature commits to the split
+        let fixture = Fixture::new();
+        let SignerCheck::Aggregated(key) = &flow.signers else {
+            panic!("expected the aggregated signer check");
+        };
+        let witness = &last.tx.input[0].witness;
+        let sig = bitcoin::secp256k1::schnorr::Signature::from_slice(
+            witness.nth(witness.len() - 3).unwrap(),
+        )
+        .unwrap();
+        let prevout = flow.txs[flow.txs.len() - 2].tx.output[0].clone();
+        let verifies = |tx: &Transaction| {
+            let sighash = bitcoin::sighash::SighashCache::new(tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &bitcoin::sighash::Prevouts::All(std::slice::from_ref(
+                        &prevout,
+                    )),
+                    bitcoin::TapLeafHash::from_script(
+                        &last.spent_script,
+                        LeafVersion::TapScript,
+                    ),
+                    TapSighashType::Default,
+                )
+                .unwrap();
+            fixture
+                .secp
+                .verify_schnorr(
+                    &sig,
+                    &Message::from_digest(sighash.to_byte_array()),
+                    &key.inner.x_only_public_key().0,
+                )
+                .is_ok()
+        };
+        assert!(verifies(&last.tx));
+        let mut redirected = last.tx.clone();
+        redirected.output[1].value = Amount::from_sat(6_000);
+        redirected.output[0].value -= Amount::from_sat(1_000);
+        assert!(!verifies(&redirected));
+
+        let build = |builder: FlowBuilder, funding_value_sat| {
+            fixture.build_funded(&builder, 0, funding_value_sat)
+        };
+        let required = flow.required_funding();
+        assert!(build(builder().payout(payout.clone()), required).is_ok());
+        assert!(build(builder().payout(payout.clone()), required - 1).is_err());
+        // SINGLE would only commit to the withdrawal
+        let single =
+            builder().sighash_type(TapSighashType::SinglePlusAnyoneCanPay);
+        assert!(build(single.payout(payout.clone()), 200_000).is_err());
+        let all = builder().sighash_type(TapSighashType::AllPlusAnyoneCanPay);
+        assert!(build(all.payout(payout), 200_000).is_ok());
+    }
+
+    #[test]
+    fn test_anyone_can_pay_fee_input() {
+        let x = InputLayout::default().max_input();
+        let fixture = Fixture::new();
+        let addr = &fixture.receiver_addr;
+        let fee_outpoint = OutPoint {
+            txid: Txid::all_zeros(),
+            vout: 1,
+        };
+        let fee_prevout = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: addr.script_pubkey(),
+        };
+        let fee_witness = placeholder_witness([64]);
+        let builder = |sighash_type| {
+            FlowBuilder::new(B, Network::Regtest, 1)
+                .hash(Sha256Hash)
+                .sighash_type(sighash_type)
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(GreaterThan(110))
+        };
+
+        for sighash_type in [
+            TapSighashType::AllPlusAnyoneCanPay,
+            TapSighashType::SinglePlusAnyoneCanPay,
+        ] {
+            let mut flow = build_signed_flow(&builder(sighash_type), x.clone());
+            let last = flow.txs.len() - 1;
+            for (i, (flow_tx, fee)) in
+                flow.txs.iter().zip(flow.fees()).enumerate()
+            {
+                let witness = &flow_tx.tx.input[0].witness;
+                let sig = witness.nth(witness.len() - 3).unwrap();
+                if i == last {
+                    // The sighash byte follows the signature, and is paid for
+                    assert_eq!(sig.len(), 65);
+                    assert_eq!(sig[64], sighash_type as u8);
+                } else {
+                    assert_eq!(sig.len(), 64);
+                    assert_eq!(
+                        flow.tx_sighash_type(i),
+                        TapSighashType::Default
+                    );
+                }
+                assert_eq!(fee, flow_tx.tx.vsize() as u64);
+            }
+            // An extra input on a lock tx would strand its presigned child
+            let lock_tx = &flow.txs[1];
+            let sighash = bitcoin::sighash::SighashCache::new(&lock_tx.tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &bitcoin::sighash::Prevouts::All(&[flow.txs[0].tx.output
+                        [0]
+                    .clone()]),
+                    bitcoin::TapLeafHash::from_script(
+                        &lock_tx.spent_script,
+                        LeafVersion::TapScript,
+                    ),
+                    TapSighashType::Default,
+                )
+                .unwrap();
+            assert_eq!(sighash.to_byte_array(), *lock_tx.sighashes[0].as_ref());
+            for step in 0..flow.locks.len() {
+                let report = flow.analyze(step).unwrap();
+                assert!(report.is_ok(), "{report}");
+            }
+
+            let presigned_fee = *flow.fees().last().unwrap();
+            let presigned = flow.txs.last().unwrap().clone();
+            flow.add_fee_input(
+                &fee_outpoint,
+                &fee_prevout,
+                &fee_witness,
+                addr,
+                10,
+            )
+            .unwrap();
+            let tx = &flow.txs.last().unwrap().tx;
+            assert_eq!(tx.input.len(), 2);
+            assert_eq!(tx.input[0].witness, presigned.tx.input[0].witness);
+            // The presigned signature still covers the spend of the lock
+            let leaf_hash = bitcoin::TapLeafHash::from_script(
+                &presigned.spent_script,
+                LeafVersion::TapScript,
+            );
+            let sighash = bitcoin::sighash::SighashCache::new(tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &bitcoin::sighash::Prevouts::One(
+                        0,
+                        flow.txs[flow.txs.len() - 2].tx.output[0].clone(),
+                    ),
+                    leaf_hash,
+                    sighash_type,
+                )
+                .unwrap();
+            assert_eq!(
+                sighash.to_byte_array(),
+                *presigned.sighashes[0].as_ref()
+            );
+
+            let mut signed = tx.clone();
+            signed.input[1].witness = fee_witness.clone();
+            let fee = *flow.fees().last().unwrap();
+            match sighash_type {
+                TapSighashType::AllPlusAnyoneCanPay => {
+                    assert_eq!(signed.output, presigned.tx.output);
+                    assert_eq!(fee, presigned_fee + 10_000);
+                }
+                _ => {
+                    assert_eq!(signed.output.len(), 2);
+                    assert_eq!(fee, 10 * signed.vsize() as u64);
+                }
+            }
+            assert!(
+                flow.add_fee_input(
+                    &fee_outpoint,
+                    &fee_prevout,
+                    &fee_witness,
+                    addr,
+                    10
+                )
+                .is_err()
+            );
+        }
+
+        let mut flow =
+            build_signed_flow(&builder(TapSighashType::Default), x.clone());
+        assert!(
+            flow.add_fee_input(
+                &fee_outpoint,
+                &fee_prevout,
+                &fee_witness,
+                addr,
+                10
+            )
+            .is_err()
+        );
+        let none = builder(TapSighashType::NonePlusAnyoneCanPay);
+        assert!(fixture.build(&none, 0).is_err());
+    }
+
+    #[test]
+    fn test_threshold_signer_check() {
+        let fixture = Fixture::new();
+        let secp = &fixture.secp;
+        let keypairs = (1..=3)
+            .map(|i| {
+                secp256k1::Keypair::from_seckey_slice(secp, &[i; 32]).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let keys = keypairs
+            .iter()
+            .map(|keypair| keypair.x_only_public_key().0)
+            .collect::<Vec<_>>();
+        assert!(SignerCheck::threshold(keys.clone(), 0).is_err());
+        assert!(SignerCheck::threshold(keys.clone(), 4).is_err());
+        let signers = SignerCheck::threshold(keys, 2).unwrap();
+
+        let x = Input::try_from(114).unwrap();
+        let sha256 = || {
+            FlowBuilder::new(B, Network::Regtest, 1)
+                .hash(Sha256Hash)
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(LessThan(F2_THRESHOLD))
+        };
+        let threshold = sha256().signer_check(signers);
+        let (nonce, flow_id) = find_nonce(&threshold, &x);
+        let build =
+            |builder: &FlowBuilder| fixture.build(builder, flow_id).unwrap();
+
+        // Signers 1 and 3 sign every step, signer 2 is offline
+        let sign = |flow: &Flow, signed: &[bool]| {
+            flow.sighashes()
+                .iter()
+                .enumerate()
+                .map(|(i, sighash)| {
+                    if i == 0 {
+                        let sig = simulate_musig2(&fixture.sk_signers, sighash);
+                        return StepSignatures::Aggregated(sig.unwrap());
+                    }
+                    let sigs = keypairs
+                        .iter()
+                        .zip(signed)
+                        .map(|(keypair, &signed)| {
+                            let sig = secp.sign_schnorr(sighash, keypair);
+                            let key = keypair.x_only_public_key().0;
+                            secp.verify_schnorr(&sig, sighash, &key).unwrap();
+                            signed.then_some(sig)
+                        })
+                        .collect();
+                    StepSignatures::Threshold(sigs)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut flow = build(&threshold);
+        assert!(
+            flow.finalize_with(&sign(&flow, &[true, false, false]), &x, &nonce)
+                .is_err()
+        );
+        flow.finalize_with(&sign(&flow, &[true, false, true]), &x, &nonce)
+            .unwrap();
+        for step in 0..flow.locks.len() - 1 {
+            let report = flow.analyze(step).unwrap();
+            assert!(report.is_ok(), "{report}");
+        }
+        assert!(flow.trace(0, TraceDetail::Segments).unwrap().success());
+
+        // A single signature does not reach the threshold in script either:
+        // drop the one of signer 3, below those of signers 2 and 1, the
+        // script and the control block
+        let tx = &mut flow.txs[1].tx;
+        let mut witness = tx.input[0].witness.to_vec();
+        let signer_3 = witness.len() - 5;
+        assert_eq!(witness[signer_3].len(), 64);
+        witness[signer_3].clear();
+        tx.input[0].witness = witness.into();
+        let report = flow.analyze(0).unwrap();
+        assert!(!report.is_ok());
+        assert!(
+            !report
+                .result
+                .unwrap_err()
+                .reason
+                .contains("invalid signature")
+        );
+        assert_eq!(
+            flow.trace(0, TraceDetail::Segments)
+                .unwrap()
+                .failed_segment(),
+            Some(SegmentKind::SignatureCheck)
+        );
+
+        let mut aggregated = build_signed_flow(&sha256(), x.clone());
+        let mut flow = build(&threshold);
+        flow.finalize_with(&sign(&flow, &[true, true, false]), &x, &nonce)
+            .unwrap();
+        let lock_vsize = |flow: &Flow| flow.txs[1].tx.weight().to_vbytes_ceil();
+        // 2-of-3 OP_CHECKSIGADD against MuSig2, per step and in total
+        assert_eq!((lock_vsize(&flow), flow.total_vsize()), (188, 494));
+        assert_eq!(
+            (lock_vsize(&aggregated), aggregated.total_vsize()),
+            (154, 426)
+        );
+        // The aggregated flow cannot take threshold signatures
+        let sigs = sign(&aggregated, &[true, true, false]);
+        assert!(aggregated.finalize_with(&sigs, &x, &nonce).is_err());
+    }
+
+    #[test]
+    fn test_nums_flow_has_no_key_path() {
+        let fixture = Fixture::new();
+        let secp = &fixture.secp;
+        let build = |internal_key| {
+            let builder = FlowBuilder::new(B, Network::Regtest, 1)
+                .hash(Sha256Hash)
+                .internal_key(internal_key)
+                .step(GreaterThan(F1_THRESHOLD))
+                .step(LessThan(F2_THRESHOLD));
+            fixture.build(&builder, 0).unwrap()
+        };
+
+        let flow = build(InternalKey::Signers);
+        assert!(flow.verify_no_key_path(secp, None).is_err());
+        let flow = build(InternalKey::Nums);
+        flow.verify_no_key_path(secp, None).unwrap();
+
+        let internal_key = InternalKey::random_nums();
+        let InternalKey::TweakedNums(tweak) = internal_key else {
+            unreachable!()
+        };
+        let flow = build(internal_key);
+        flow.verify_no_key_path(secp, Some(&tweak)).unwrap();
+        assert!(flow.verify_no_key_path(secp, None).is_err());
+    }
+}
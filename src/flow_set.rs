@@ -0,0 +1,250 @@
+//! The set `D` of flow identifiers.
+//!
+//! The paper only requires `D` to be a set of `2^L` bitstrings of length `B`
+//! whose membership is easy to check. [`FlowSet`] lets the nonce search and
+//! the per-flow prefix checks share the same definition of `D`.
+
+use crate::core::{FlowId, MAX_B_BITS, flow_id_to_prefix_bytes, prefix_mask};
+use std::collections::BTreeSet;
+
+/// A set `D` of `2^L` flow identifiers, each a `B`-bit hash prefix.
+pub trait FlowSet: Sync {
+    /// Hash prefix length `B`.
+    fn b_bits(&self) -> usize;
+
+    /// `L`, the set holds `2^L` flow identifiers.
+    fn l_bits(&self) -> usize;
+
+    /// Whether the prefix `H(x, r)|_B` selects a flow, i.e. belongs to `D`.
+    fn contains(&self, prefix: FlowId) -> bool;
+
+    /// All flow identifiers of the set, in ascending order.
+    fn flow_ids(&self) -> Box<dyn Iterator<Item = FlowId> + '_>;
+
+    /// Nibble-encoded prefix checked in the scripts of flow `flow_id`.
+    fn prefix_nibbles(&self, flow_id: FlowId) -> Result<Vec<u8>, String> {
+        if !self.contains(flow_id) {
+            return Err(format!("flow_id {flow_id} is not in D"));
+        }
+        Ok(flow_id_to_prefix_bytes(flow_id, self.b_bits()))
+    }
+}
+
+/// Checks the `(B, L)` parameters of a flow set: `L ≤ B ≤ MAX_B_BITS`, and
+/// `L < MAX_B_BITS` so that `2^L` flow IDs still fit a [`FlowId`].
+pub fn validate_params(b_bits: usize, l_bits: usize) -> Result<(), String> {
+    if b_bits > MAX_B_BITS {
+        return Err(format!("B={b_bits} must be <= {MAX_B_BITS}"));
+    }
+    if l_bits > b_bits || l_bits >= MAX_B_BITS {
+        return Err(format!("L={l_bits} must be <= B and < {MAX_B_BITS}"));
+    }
+    Ok(())
+}
+
+fn check_params(b_bits: usize, l_bits: usize) {
+    if let Err(e) = validate_params(b_bits, l_bits) {
+        panic!("{e}");
+    }
+}
+
+/// `D = [0, 2^L)`: the prefixes whose top `B-L` bits are zero.
+#[derive(Debug, Clone, Copy)]
+pub struct LowRange {
+    b_bits: usize,
+    l_bits: usize,
+}
+
+impl LowRange {
+    pub fn new(b_bits: usize, l_bits: usize) -> Self {
+        check_params(b_bits, l_bits);
+        Self { b_bits, l_bits }
+    }
+}
+
+impl FlowSet for LowRange {
+    fn b_bits(&self) -> usize {
+        self.b_bits
+    }
+
+    fn l_bits(&self) -> usize {
+        self.l_bits
+    }
+
+    fn contains(&self, prefix: FlowId) -> bool {
+        prefix >> self.l_bits == 0
+    }
+
+    fn flow_ids(&self) -> Box<dyn Iterator<Item = FlowId> + '_> {
+        Box::new(0..1 << self.l_bits)
+    }
+}
+
+/// `D = { pattern || y : y ∈ [0, 2^L) }`: the prefixes whose top `B-L` bits
+/// equal `pattern`.
+#[derive(Debug, Clone, Copy)]
+pub struct HighBitPattern {
+    b_bits: usize,
+    l_bits: usize,
+    pattern: FlowId,
+}
+
+impl HighBitPattern {
+    pub fn new(b_bits: usize, l_bits: usize, pattern: FlowId) -> Self {
+        check_params(b_bits, l_bits);
+        assert_eq!(
+            pattern & !prefix_mask(b_bits - l_bits),
+            0,
+            "pattern must fit in B-L bits"
+        );
+        Self {
+            b_bits,
+            l_bits,
+            pattern,
+        }
+    }
+}
+
+impl FlowSet for HighBitPattern {
+    fn b_bits(&self) -> usize {
+        self.b_bits
+    }
+
+    fn l_bits(&self) -> usize {
+        self.l_bits
+    }
+
+    fn contains(&self, prefix: FlowId) -> bool {
+        prefix >> self.l_bits == self.pattern
+    }
+
+    fn flow_ids(&self) -> Box<dyn Iterator<Item = FlowId> + '_> {
+        let base = self.pattern << self.l_bits;
+        Box::new((0..1 << self.l_bits).map(move |low| base | low))
+    }
+}
+
+/// An explicitly listed `D`.
+#[derive(Debug, Clone)]
+pub struct ListedFlowSet {
+    b_bits: usize,
+    l_bits: usize,
+    flow_ids: BTreeSet<FlowId>,
+}
+
+impl ListedFlowSet {
+    /// Fails unless `flow_ids` holds a power of two of distinct `B`-bit
+    /// values.
+    pub fn new(
+        b_bits: usize,
+        flow_ids: impl IntoIterator<Item = FlowId>,
+    ) -> Result<Self, String> {
+        let flow_ids = flow_ids.into_iter().collect::<BTreeSet<_>>();
+        if !flow_ids.len().is_power_of_two() {
+            return Err(format!(
+                "|D| = {} is not a power of two",
+                flow_ids.len()
+            ));
+        }
+        if let Some(id) =
+            flow_ids.iter().find(|&&id| id & !prefix_mask(b_bits) != 0)
+        {
+            return Err(format!("flow_id {id} is wider than B={b_bits}"));
+        }
+        let l_bits = flow_ids.len().trailing_zeros() as usize;
+        check_params(b_bits, l_bits);
+        Ok(Self {
+            b_bits,
+            l_bits,
+            flow_ids,
+        })
+    }
+}
+
+impl FlowSet for ListedFlowSet {
+    fn b_bits(&self) -> usize {
+        self.b_bits
+    }
+
+    fn l_bits(&self) -> usize {
+        self.l_bits
+    }
+
+    fn contains(&self, prefix: FlowId) -> bool {
+        self.flow_ids.contains(&prefix)
+    }
+
+    fn flow_ids(&self) -> Box<dyn Iterator<Item = FlowId> + '_> {
+        Box::new(self.flow_ids.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{calculate_flow_id, find_valid_nonce_in_set};
+    use crate::hash::Blake3Hash;
+    use crate::input::Input;
+
+    #[test]
+    fn test_low_range() {
+        let flow_set = LowRange::new(16, 4);
+        assert_eq!(flow_set.flow_ids().count(), 16);
+        assert!(flow_set.flow_ids().all(|id| flow_set.contains(id)));
+        assert!(!flow_set.contains(16));
+        assert_eq!(flow_set.prefix_nibbles(13).unwrap(), vec![0, 0xd, 0, 0]);
+        assert!(flow_set.prefix_nibbles(0x100).is_err());
+    }
+
+    #[test]
+    fn test_validate_params() {
+        assert!(validate_params(MAX_B_BITS, MAX_B_BITS - 1).is_ok());
+        assert!(validate_params(MAX_B_BITS, MAX_B_BITS).is_err());
+        assert!(validate_params(MAX_B_BITS + 1, 8).is_err());
+        assert!(validate_params(8, 9).is_err());
+    }
+
+    #[test]
+    fn test_high_bit_pattern() {
+        let flow_set = HighBitPattern::new(16, 4, 0xabc);
+        let flow_ids = flow_set.flow_ids().collect::<Vec<_>>();
+        assert_eq!(flow_ids.len(), 16);
+        assert_eq!(flow_ids[0], 0xabc0);
+        assert_eq!(flow_ids[15], 0xabcf);
+        assert!(flow_set.contains(0xabc7));
+        assert!(!flow_set.contains(0x0007));
+        assert_eq!(
+            flow_set.prefix_nibbles(0xabc7).unwrap(),
+            vec![0xc, 0x7, 0xa, 0xb]
+        );
+
+        let (nonce, flow_id) = find_valid_nonce_in_set(
+            &Input::try_from(114).unwrap(),
+            &flow_set,
+            &Blake3Hash::default(),
+        )
+        .unwrap();
+        assert!(flow_set.contains(flow_id));
+        assert_eq!(calculate_flow_id(114, nonce) & 0xffff, flow_id);
+    }
+
+    #[test]
+    fn test_listed_flow_set() {
+        assert!(ListedFlowSet::new(16, [1, 2, 3]).is_err());
+        assert!(ListedFlowSet::new(8, [1, 0x100]).is_err());
+
+        let flow_set = ListedFlowSet::new(8, [0x11, 0x22, 0x33, 0x44]).unwrap();
+        assert_eq!(flow_set.l_bits(), 2);
+        assert!(flow_set.contains(0x33));
+        assert!(!flow_set.contains(0x34));
+
+        let (nonce, flow_id) = find_valid_nonce_in_set(
+            &Input::try_from(114).unwrap(),
+            &flow_set,
+            &Blake3Hash::default(),
+        )
+        .unwrap();
+        assert!(flow_set.contains(flow_id));
+        assert_eq!(calculate_flow_id(114, nonce) & 0xff, flow_id);
+    }
+}
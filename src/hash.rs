@@ -0,0 +1,852 @@
+//! The collision hash `H` behind the `H(x, r)|_B = d` check.
+//!
+//! A [`CollisionHash`] bundles everything that has to agree on `H`: the
+//! off-chain digest used by the nonce search, the witness encoding of
+//! `(x, r)` and the locking-script fragments that read `x` back and check
+//! the hash prefix.
+//!
+//! * [`Blake3Hash`] computes BLAKE3 in plain Tapscript with bitvm's limb
+//!   implementation. It runs on today's Bitcoin but costs ~17k vbytes per
+//!   step.
+//! * [`Blake3CompactHash`] is [`Blake3Hash`] with a few script numbers
+//!   for `(x, r)` in the witness, expanded to limbs in script.
+//! * [`Sha256Hash`] uses the native `OP_SHA256`. Cutting the digest down to
+//!   `B` bits needs `OP_CAT` (BIP-347), so its scripts are only valid on an
+//!   `OP_CAT`-enabled network such as Bitcoin Inquisition signet.
+
+use crate::core::{
+    DEFAULT_BLAKE3_LIMB_LEN, FlowId, SegmentKind, blake3_compute_script,
+    blake3_message_to_limbs, build_prefix_equalverify,
+    build_script_reconstruct_field, combine_segments, flow_id_from_hash,
+};
+use crate::input::{Field, Input, InputLayout};
+use crate::interpreter::MAX_STACK_SIZE;
+use crate::utils::encode_scriptnum;
+use bitcoin::blockdata::script::{Builder, ScriptBuf};
+use bitcoin::opcodes;
+use bitcoin::script::PushBytesBuf;
+use bitcoin_hashes::sha256;
+use std::fmt::Debug;
+
+/// A hash function `H` usable for the flow selection `H(x, r)|_B = d`.
+///
+/// `H` always commits to `x || r_le(8)`, with `x` serialized as
+/// [`Input::bytes`].
+pub trait CollisionHash: Debug + Send + Sync {
+    /// Short name for logs and reports.
+    fn name(&self) -> &'static str;
+
+    /// Off-chain `H(x, r)`, truncated to its first
+    /// [`crate::core::MAX_B_BITS`] bits read as a little-endian integer.
+    /// Masking with [`crate::core::prefix_mask`] gives `H(x, r)|_B`.
+    fn flow_id(&self, input: &Input, nonce: u64) -> FlowId;
+
+    /// Witness elements encoding `(x, r)`, bottom of the stack first. They
+    /// are pushed below the signature.
+    fn witness(&self, input: &Input, nonce: u64, b_bits: usize)
+    -> Vec<Vec<u8>>;
+
+    /// Upper bound on the size of each element of [`Self::witness`], for
+    /// any input following `layout` and any nonce. Defaults to the sizes of
+    /// the witness of [`InputLayout::max_input`] and `u64::MAX`, exact as
+    /// long as the encoding of a bit never shrinks when it is set.
+    fn max_witness_sizes(
+        &self,
+        layout: &InputLayout,
+        b_bits: usize,
+    ) -> Vec<usize> {
+        self.witness(&layout.max_input(), u64::MAX, b_bits)
+            .iter()
+            .map(Vec::len)
+            .collect()
+    }
+
+    /// Fails if the witness of an input following `layout` does not fit the
+    /// [`MAX_STACK_SIZE`] stack elements next to `reserved` other witness
+    /// elements (the signatures), e.g. BLAKE3 on short limbs of a long `x`.
+    fn check_layout(
+        &self,
+        layout: &InputLayout,
+        b_bits: usize,
+        reserved: usize,
+    ) -> Result<(), String> {
+        let elements = self.max_witness_sizes(layout, b_bits).len();
+        if elements + reserved > MAX_STACK_SIZE {
+            return Err(format!(
+                "{} encodes a {} byte input in {elements} witness elements, \
+                 the stack holds {} next to the signatures",
+                self.name(),
+                layout.len(),
+                MAX_STACK_SIZE - reserved.min(MAX_STACK_SIZE)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Script fragment run right after the signature check, turning the
+    /// witness elements into the stack read by [`Self::field_script`] and
+    /// [`Self::prefix_check_script`]. Empty by default.
+    fn expand_witness_script(&self, _layout: &InputLayout) -> ScriptBuf {
+        ScriptBuf::new()
+    }
+
+    /// Script fragment copying number field `field` of `x` on top of the
+    /// stack, leaving the witness elements untouched.
+    fn field_script(&self, layout: &InputLayout, field: usize) -> ScriptBuf;
+
+    /// [`Self::prefix_check_script`] split into labeled segments.
+    fn prefix_check_segments(
+        &self,
+        layout: &InputLayout,
+        flow_id_prefix: &[u8],
+        b_bits: usize,
+    ) -> Vec<(SegmentKind, ScriptBuf)>;
+
+    /// Script fragment consuming the witness elements and aborting unless
+    /// `H(x, r)|_B` matches the nibble-encoded `flow_id_prefix`.
+    fn prefix_check_script(
+        &self,
+        layout: &InputLayout,
+        flow_id_prefix: &[u8],
+        b_bits: usize,
+    ) -> ScriptBuf {
+        combine_segments(&self.prefix_check_segments(
+            layout,
+            flow_id_prefix,
+            b_bits,
+        ))
+        .0
+    }
+}
+
+fn number_width(layout: &InputLayout, field: usize) -> usize {
+    layout
+        .number_width(field)
+        .unwrap_or_else(|err| panic!("cannot expose field: {err}"))
+}
+
+/// BLAKE3 over `x || r_le(8)`, computed in script on limbs of
+/// [`DEFAULT_BLAKE3_LIMB_LEN`] bits unless configured otherwise.
+///
+/// Longer limbs mean fewer witness elements but costlier field
+/// reconstructions and a different compute script.
+#[derive(Debug, Clone, Copy)]
+pub struct Blake3Hash {
+    limb_len: u8,
+}
+
+impl Default for Blake3Hash {
+    fn default() -> Self {
+        Self {
+            limb_len: DEFAULT_BLAKE3_LIMB_LEN,
+        }
+    }
+}
+
+impl Blake3Hash {
+    /// BLAKE3 on `limb_len`-bit limbs, 4 to 31 bits.
+    pub fn with_limb_len(limb_len: u8) -> Result<Self, String> {
+        if !(4..32).contains(&limb_len) {
+            return Err(format!(
+                "limb length {limb_len} is not in the range [4, 32)"
+            ));
+        }
+        Ok(Self { limb_len })
+    }
+
+    pub fn limb_len(&self) -> u8 {
+        self.limb_len
+    }
+}
+
+impl CollisionHash for Blake3Hash {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn flow_id(&self, input: &Input, nonce: u64) -> FlowId {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(input.bytes());
+        hasher.update(&nonce.to_le_bytes());
+        flow_id_from_hash(hasher.finalize().as_bytes())
+    }
+
+    fn witness(
+        &self,
+        input: &Input,
+        nonce: u64,
+        _b_bits: usize,
+    ) -> Vec<Vec<u8>> {
+        let message = [input.bytes(), &nonce.to_le_bytes()].concat();
+        blake3_message_to_limbs(&message, self.limb_len)
+            .into_iter()
+            .map(|limb| encode_scriptnum(limb.into()))
+            .collect()
+    }
+
+    fn field_script(&self, layout: &InputLayout, field: usize) -> ScriptBuf {
+        let width = number_width(layout, field);
+        build_script_reconstruct_field(
+            layout.offset(field),
+            width,
+            self.limb_len,
+        )
+    }
+
+    fn prefix_check_segments(
+        &self,
+        layout: &InputLayout,
+        flow_id_prefix: &[u8],
+        b_bits: usize,
+    ) -> Vec<(SegmentKind, ScriptBuf)> {
+        // Drop the hash nibbles we don't need for the prefix check
+        let blake3_script_hash_len_nibbles = 64;
+        let to_drop = blake3_script_hash_len_nibbles - flow_id_prefix.len();
+        let drop_script = {
+            let mut b = Builder::new();
+            for _ in 0..to_drop {
+                b = b.push_opcode(opcodes::all::OP_DROP);
+            }
+            b.into_script()
+        };
+
+        vec![
+            (
+                SegmentKind::Hash,
+                blake3_compute_script(layout.len() + 8, self.limb_len),
+            ),
+            (SegmentKind::Drop, drop_script),
+            (
+                SegmentKind::PrefixCompare,
+                build_prefix_equalverify(flow_id_prefix, b_bits),
+            ),
+        ]
+    }
+}
+
+/// Low bits of a message word pushed as a single script number, the top
+/// nibble is pushed on its own since script numbers hold 31 bits.
+const WORD_LOW_BITS: u32 = 28;
+
+/// [`Blake3Hash`] on 4-bit limbs with a compact witness.
+///
+/// Every 4-byte little-endian word `w` of `x || r_le(8)` is pushed as two
+/// script numbers `[w >> 28, w mod 2^28]` instead of 8 nibble limbs, and the
+/// zero padding of the BLAKE3 block is not pushed at all. The script checks
+/// the ranges of both numbers, splits them back into the nibble limbs of
+/// [`Blake3Hash`] and pushes the padding, the rest of the script is shared
+/// with [`Blake3Hash`].
+///
+/// The witness shrinks by about a hundred bytes, but the expansion costs
+/// more script bytes than it saves, see `test_blake3_compact_witness_cost`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3CompactHash;
+
+impl Blake3CompactHash {
+    /// The 4-byte little-endian words of `x || r_le(8)`, zero-padded.
+    fn message_words(input: &Input, nonce: u64) -> Vec<u32> {
+        let message = [input.bytes(), &nonce.to_le_bytes()].concat();
+        message
+            .chunks(4)
+            .map(|chunk| {
+                let mut word = [0u8; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(word)
+            })
+            .collect()
+    }
+
+    /// Abort unless the number on top of the stack is in `[0, max)`.
+    fn push_range_check(b: Builder, max: i64) -> Builder {
+        b.push_opcode(opcodes::all::OP_DUP)
+            .push_int(0)
+            .push_int(max)
+            .push_opcode(opcodes::all::OP_WITHIN)
+            .push_opcode(opcodes::all::OP_VERIFY)
+    }
+
+    /// `[n]` => `[d_6, …, d_1, d_0]`, the hex digits of `n < 2^28` most
+    /// significant first, by conditionally subtracting `2^j·16^k`.
+    fn push_nibble_split(mut b: Builder) -> Builder {
+        for k in (1..WORD_LOW_BITS / 4).rev() {
+            // [n] => [d_k = 0, n]
+            b = b.push_int(0).push_opcode(opcodes::all::OP_SWAP);
+            for bit in (0..4).rev() {
+                let value = 1i64 << (4 * k + bit);
+                b = b
+                    .push_opcode(opcodes::all::OP_DUP)
+                    .push_int(value)
+                    .push_opcode(opcodes::all::OP_GREATERTHANOREQUAL)
+                    .push_opcode(opcodes::all::OP_IF)
+                    .push_int(value)
+                    .push_opcode(opcodes::all::OP_SUB)
+                    .push_opcode(opcodes::all::OP_SWAP)
+                    .push_int(1 << bit)
+                    .push_opcode(opcodes::all::OP_ADD)
+                    .push_opcode(opcodes::all::OP_SWAP)
+                    .push_opcode(opcodes::all::OP_ENDIF);
+            }
+        }
+        // What is left is d_0
+        b
+    }
+
+    /// Push `count` zeros, three at a time with `OP_3DUP`.
+    fn push_zeros(mut b: Builder, count: usize) -> Builder {
+        for _ in 0..count.min(3) {
+            b = b.push_int(0);
+        }
+        let rest = count.saturating_sub(3);
+        for _ in 0..rest / 3 {
+            b = b.push_opcode(opcodes::all::OP_3DUP);
+        }
+        match rest % 3 {
+            1 => b.push_opcode(opcodes::all::OP_DUP),
+            2 => b.push_opcode(opcodes::all::OP_2DUP),
+            _ => b,
+        }
+    }
+}
+
+impl CollisionHash for Blake3CompactHash {
+    fn name(&self) -> &'static str {
+        "blake3-compact"
+    }
+
+    fn flow_id(&self, input: &Input, nonce: u64) -> FlowId {
+        Blake3Hash::default().flow_id(input, nonce)
+    }
+
+    fn witness(
+        &self,
+        input: &Input,
+        nonce: u64,
+        _b_bits: usize,
+    ) -> Vec<Vec<u8>> {
+        Self::message_words(input, nonce)
+            .into_iter()
+            .flat_map(|word| {
+                let low = word & ((1 << WORD_LOW_BITS) - 1);
+                [word >> WORD_LOW_BITS, low]
+            })
+            .map(|number| encode_scriptnum(number.into()))
+            .collect()
+    }
+
+    fn check_layout(
+        &self,
+        layout: &InputLayout,
+        b_bits: usize,
+        reserved: usize,
+    ) -> Result<(), String> {
+        // The stack ends up holding the nibble limbs, not the witness words
+        Blake3Hash { limb_len: 4 }.check_layout(layout, b_bits, reserved)
+    }
+
+    fn expand_witness_script(&self, layout: &InputLayout) -> ScriptBuf {
+        let message_len = layout.len() + 8;
+        let words = message_len.div_ceil(4);
+        let limbs = blake3_message_to_limbs(&vec![0; message_len], 4).len();
+
+        // The words are expanded in order, each one rolled up from the
+        // bottom of the stack, so that the first limb ends up deepest
+        let mut b = Builder::new();
+        for _ in 0..words {
+            b = b
+                .push_opcode(opcodes::all::OP_DEPTH)
+                .push_opcode(opcodes::all::OP_1SUB)
+                .push_opcode(opcodes::all::OP_ROLL);
+            b = Self::push_range_check(b, 16);
+            b = b
+                .push_opcode(opcodes::all::OP_DEPTH)
+                .push_opcode(opcodes::all::OP_1SUB)
+                .push_opcode(opcodes::all::OP_ROLL);
+            b = Self::push_range_check(b, 1 << WORD_LOW_BITS);
+            b = Self::push_nibble_split(b);
+        }
+        Self::push_zeros(b, limbs - 8 * words).into_script()
+    }
+
+    fn field_script(&self, layout: &InputLayout, field: usize) -> ScriptBuf {
+        Blake3Hash::default().field_script(layout, field)
+    }
+
+    fn prefix_check_segments(
+        &self,
+        layout: &InputLayout,
+        flow_id_prefix: &[u8],
+        b_bits: usize,
+    ) -> Vec<(SegmentKind, ScriptBuf)> {
+        Blake3Hash::default().prefix_check_segments(
+            layout,
+            flow_id_prefix,
+            b_bits,
+        )
+    }
+}
+
+/// SHA256 over `x || r_le(8)`, computed with `OP_SHA256`.
+///
+/// The witness is `[suffix, x_0, …, x_{n-1}, r]`: number fields as script
+/// numbers so that the sub-functions can use them directly, bytes fields
+/// and `r` as raw bytes, and `suffix` the digest bytes after the first
+/// `B/8`. The script pads every field back to its width, concatenates the
+/// message with `OP_CAT` and compares `prefix || suffix` to the digest, so
+/// `B` must be a multiple of 8.
+///
+/// `OP_CAT` is only enforced by networks running BIP-347, e.g. Bitcoin
+/// Inquisition signet. Bitcoin Core treats it as `OP_SUCCESS126`, so on
+/// mainnet, testnet, signet or regtest these locks are anyone-can-spend, or
+/// non-standard and not relayed. The demo only builds them with
+/// `--assume-op-cat`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Hash;
+
+impl Sha256Hash {
+    fn digest(input: &Input, nonce: u64) -> [u8; 32] {
+        let message = [input.bytes(), &nonce.to_le_bytes()].concat();
+        sha256::Hash::hash(&message).to_byte_array()
+    }
+
+    /// Abort unless the element on top of the stack is exactly `width`
+    /// bytes long.
+    fn push_size_check(b: Builder, width: usize) -> Builder {
+        b.push_opcode(opcodes::all::OP_SIZE)
+            .push_int(width as i64)
+            .push_opcode(opcodes::all::OP_EQUALVERIFY)
+    }
+}
+
+impl CollisionHash for Sha256Hash {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn flow_id(&self, input: &Input, nonce: u64) -> FlowId {
+        flow_id_from_hash(&Self::digest(input, nonce))
+    }
+
+    fn witness(
+        &self,
+        input: &Input,
+        nonce: u64,
+        b_bits: usize,
+    ) -> Vec<Vec<u8>> {
+        assert_eq!(b_bits % 8, 0, "SHA256 flows need B to be a multiple of 8");
+        let digest = Self::digest(input, nonce);
+        let mut witness = vec![digest[b_bits / 8..].to_vec()];
+        for (i, field) in input.layout().fields().iter().enumerate() {
+            witness.push(match field {
+                Field::Number(_) => {
+                    encode_scriptnum(input.number(i).unwrap().into())
+                }
+                Field::Bytes(_) => input.field_bytes(i).to_vec(),
+            });
+        }
+        witness.push(nonce.to_le_bytes().to_vec());
+        witness
+    }
+
+    fn field_script(&self, layout: &InputLayout, field: usize) -> ScriptBuf {
+        number_width(layout, field);
+        // [suffix, x_0, …, x_{n-1}, r] => [suffix, x_0, …, x_{n-1}, r, x_i]
+        let depth = layout.fields().len() - field;
+        if depth == 1 {
+            Builder::new().push_opcode(opcodes::all::OP_OVER)
+        } else {
+            Builder::new()
+                .push_int(depth as i64)
+                .push_opcode(opcodes::all::OP_PICK)
+        }
+        .into_script()
+    }
+
+    fn prefix_check_segments(
+        &self,
+        layout: &InputLayout,
+        flow_id_prefix: &[u8],
+        b_bits: usize,
+    ) -> Vec<(SegmentKind, ScriptBuf)> {
+        assert_eq!(b_bits % 8, 0, "SHA256 flows need B to be a multiple of 8");
+        assert_eq!(
+            flow_id_prefix.len(),
+            2 * b_bits / 8,
+            "prefix must hold the nibbles of B/8 bytes"
+        );
+        // Nibbles (hi, lo) back to the digest bytes
+        let prefix_bytes = flow_id_prefix
+            .chunks(2)
+            .map(|nibbles| (nibbles[0] << 4) | nibbles[1])
+            .collect::<Vec<_>>();
+        let prefix_bytes = PushBytesBuf::try_from(prefix_bytes)
+            .expect("prefix of at most 16 bytes");
+
+        // Every part has a fixed width so that bytes cannot be shifted
+        // between fields or into r. The message is assembled right to left
+        // on the altstack.
+        let mut b = Self::push_size_check(Builder::new(), 8)
+            .push_opcode(opcodes::all::OP_TOALTSTACK);
+        for field in layout.fields().iter().rev() {
+            if let Field::Number(width) = *field {
+                // A minimally encoded, non-negative number...
+                b = b
+                    .push_opcode(opcodes::all::OP_DUP)
+                    .push_opcode(opcodes::all::OP_ABS)
+                    .push_opcode(opcodes::all::OP_OVER)
+                    .push_opcode(opcodes::all::OP_EQUALVERIFY);
+                // ...zero-padded to its little-endian width
+                for _ in 0..width {
+                    b = b
+                        .push_opcode(opcodes::all::OP_SIZE)
+                        .push_int(width as i64)
+                        .push_opcode(opcodes::all::OP_LESSTHAN)
+                        .push_opcode(opcodes::all::OP_IF)
+                        .push_slice([0u8])
+                        .push_opcode(opcodes::all::OP_CAT)
+                        .push_opcode(opcodes::all::OP_ENDIF);
+                }
+            }
+            b = Self::push_size_check(b, field.width())
+                .push_opcode(opcodes::all::OP_FROMALTSTACK)
+                .push_opcode(opcodes::all::OP_CAT)
+                .push_opcode(opcodes::all::OP_TOALTSTACK);
+        }
+
+        // [suffix] => [suffix, H(x || r)] => [H(x || r), prefix || suffix]
+        let hash_script = b
+            .push_opcode(opcodes::all::OP_FROMALTSTACK)
+            .push_opcode(opcodes::all::OP_SHA256)
+            .into_script();
+        let compare_script = Builder::new()
+            .push_opcode(opcodes::all::OP_SWAP)
+            .push_slice(prefix_bytes)
+            .push_opcode(opcodes::all::OP_SWAP)
+            .push_opcode(opcodes::all::OP_CAT)
+            .push_opcode(opcodes::all::OP_EQUALVERIFY)
+            .into_script();
+        vec![
+            (SegmentKind::Hash, hash_script),
+            (SegmentKind::PrefixCompare, compare_script),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        calculate_flow_id, flow_id_to_prefix_bytes, prefix_mask,
+    };
+    use crate::input::{FieldValue, MAX_INPUT_LEN};
+    use crate::interpreter::{ExecError, Interpreter};
+    use bitcoin::Witness;
+    use bitvm::execute_script_buf;
+
+    fn push_witness(witness: &[Vec<u8>]) -> Vec<u8> {
+        let mut b = Builder::new();
+        for element in witness {
+            b = b.push_slice(PushBytesBuf::try_from(element.clone()).unwrap());
+        }
+        b.into_script().to_bytes()
+    }
+
+    /// `[witness] <field> == expected` without any other check.
+    fn run_field_check(
+        hash: &dyn CollisionHash,
+        input: &Input,
+        field: usize,
+        expected: u32,
+    ) -> bool {
+        let mut script = push_witness(&hash.witness(input, 7, 16));
+        script.extend(hash.expand_witness_script(input.layout()).to_bytes());
+        script.extend(hash.field_script(input.layout(), field).to_bytes());
+        script.extend(
+            Builder::new()
+                .push_int(expected.into())
+                .push_opcode(opcodes::all::OP_EQUALVERIFY)
+                .into_script()
+                .to_bytes(),
+        );
+        execute_script_buf(ScriptBuf::from_bytes(script))
+            .error
+            .is_none()
+    }
+
+    /// `[witness] <field 0> == expected_x <prefix check> OP_TRUE`
+    fn run_sha256_check(
+        witness: &[Vec<u8>],
+        layout: &InputLayout,
+        flow_id_prefix: &[u8],
+        b_bits: usize,
+        expected_x: u32,
+    ) -> bool {
+        let mut script = push_witness(witness);
+        script.extend(Sha256Hash.field_script(layout, 0).to_bytes());
+        script.extend(
+            Builder::new()
+                .push_int(expected_x.into())
+                .push_opcode(opcodes::all::OP_EQUALVERIFY)
+                .into_script()
+                .to_bytes(),
+        );
+        script.extend(
+            Sha256Hash
+                .prefix_check_script(layout, flow_id_prefix, b_bits)
+                .to_bytes(),
+        );
+        script.push(opcodes::OP_TRUE.to_u8());
+        execute_script_buf(ScriptBuf::from_bytes(script)).success
+    }
+
+    fn multi_field_input() -> Input {
+        InputLayout::new(vec![
+            Field::Number(2),
+            Field::Bytes(5),
+            Field::Number(4),
+            Field::Number(1),
+        ])
+        .unwrap()
+        .encode(&[
+            FieldValue::Number(0x1234),
+            FieldValue::Bytes(vec![1, 2, 3, 4, 5]),
+            FieldValue::Number(0x0abc_def0),
+            FieldValue::Number(0x7f),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_blake3_matches_calculate_flow_id() {
+        let input = Input::try_from(114).unwrap();
+        assert_eq!(
+            Blake3Hash::default().flow_id(&input, 42),
+            calculate_flow_id(114, 42)
+        );
+        // 12 byte message => one 64 byte block of 4-bit limbs
+        assert_eq!(Blake3Hash::default().witness(&input, 42, 16).len(), 128);
+    }
+
+    /// The stack left by the expansion of `witness`.
+    fn expand(
+        layout: &InputLayout,
+        witness: Vec<Vec<u8>>,
+    ) -> Result<Vec<Vec<u8>>, ExecError> {
+        let script = Blake3CompactHash.expand_witness_script(layout);
+        let mut interpreter = Interpreter::new(&script, witness)?;
+        while !interpreter.is_done() {
+            interpreter.step()?;
+        }
+        Ok(interpreter.stack().to_vec())
+    }
+
+    #[test]
+    fn test_blake3_compact_witness_expansion() {
+        let inputs = [
+            Input::try_from(114).unwrap(),
+            InputLayout::default().max_input(),
+        ];
+        for input in inputs.iter().chain([&multi_field_input()]) {
+            for nonce in [0, 42, u64::MAX, 0x8765_4321_fedc_ba98] {
+                let witness = Blake3CompactHash.witness(input, nonce, 16);
+                assert_eq!(
+                    expand(input.layout(), witness).unwrap(),
+                    Blake3Hash::default().witness(input, nonce, 16)
+                );
+            }
+        }
+
+        let input = Input::try_from(114).unwrap();
+        let witness = Blake3CompactHash.witness(&input, 42, 16);
+        assert_eq!(witness.len(), 6);
+        // Numbers out of range would let the limbs stray from x || r
+        for (i, number) in [(0, 16), (0, -1), (1, 1 << 28), (3, -5)] {
+            let mut forged = witness.clone();
+            forged[i] = encode_scriptnum(number);
+            assert!(expand(input.layout(), forged).is_err());
+        }
+    }
+
+    #[test]
+    fn test_blake3_compact_witness_cost() {
+        let input = Input::try_from(114).unwrap();
+        let nonce = 0x8765_4321_fedc_ba98;
+        let limbs = Blake3Hash::default().witness(&input, nonce, 16);
+        let compact = Blake3CompactHash.witness(&input, nonce, 16);
+        let expand_script =
+            Blake3CompactHash.expand_witness_script(input.layout());
+
+        // Every step spends one of these witnesses next to the signature,
+        // the script and the control block, all witness data
+        let limbs_size = Witness::from_slice(&limbs).size();
+        let compact_size = Witness::from_slice(&compact).size();
+        assert_eq!((limbs.len(), limbs_size), (128, 147));
+        assert_eq!((compact.len(), compact_size), (6, 18));
+        assert_eq!(expand_script.len(), 1297);
+        // The F2 and spending transactions each get 1168 WU (292 vbytes)
+        // heavier
+        let delta = compact_size + expand_script.len() - limbs_size;
+        assert_eq!(delta, 1168);
+    }
+
+    #[test]
+    fn test_blake3_limb_len_fields() {
+        let inputs = [
+            Input::try_from(114).unwrap(),
+            Input::try_from(0x7654_3210).unwrap(),
+        ];
+        for limb_len in [4, 5, 7, 8, 13, 16, 31] {
+            let hash = Blake3Hash::with_limb_len(limb_len).unwrap();
+            for input in inputs.iter().chain([&multi_field_input()]) {
+                let witness = hash.witness(input, 0x0123_4567_89ab_cdef, 16);
+                let limbs = 256usize.div_ceil(limb_len.into());
+                assert_eq!(
+                    witness.len(),
+                    2 * limbs * (input.bytes().len() + 8).div_ceil(64)
+                );
+
+                for (field, kind) in input.layout().fields().iter().enumerate()
+                {
+                    let Field::Number(_) = kind else { continue };
+                    let script = hash.field_script(input.layout(), field);
+                    let mut interpreter =
+                        Interpreter::new(&script, witness.clone()).unwrap();
+                    while !interpreter.is_done() {
+                        interpreter.step().unwrap();
+                    }
+                    let stack = interpreter.stack();
+                    assert_eq!(stack[..witness.len()], witness);
+                    assert_eq!(
+                        stack[witness.len()..],
+                        [encode_scriptnum(input.number(field).unwrap().into())],
+                        "limb length {limb_len}, field {field}"
+                    );
+                }
+            }
+        }
+        assert!(Blake3Hash::with_limb_len(3).is_err());
+        assert!(Blake3Hash::with_limb_len(32).is_err());
+    }
+
+    #[test]
+    fn test_blake3_stack_budget() {
+        // 16 chunks of 2 * ceil(256 / limb_len) limbs
+        let layout =
+            InputLayout::new(vec![Field::Bytes(MAX_INPUT_LEN)]).unwrap();
+        let hash = Blake3Hash::with_limb_len(29).unwrap();
+        assert_eq!(hash.max_witness_sizes(&layout, 16).len(), 288);
+        assert!(hash.check_layout(&layout, 16, 1).is_ok());
+        let hash = Blake3Hash::with_limb_len(4).unwrap();
+        assert_eq!(hash.max_witness_sizes(&layout, 16).len(), 2048);
+        assert!(hash.check_layout(&layout, 16, 1).is_err());
+        // 7 chunks of 128 limbs fit, 8 do not
+        let layout = InputLayout::new(vec![Field::Bytes(440)]).unwrap();
+        assert!(hash.check_layout(&layout, 16, 1).is_ok());
+        assert!(hash.check_layout(&layout, 16, 105).is_err());
+        let layout = InputLayout::new(vec![Field::Bytes(441)]).unwrap();
+        assert!(hash.check_layout(&layout, 16, 1).is_err());
+        assert!(Blake3CompactHash.check_layout(&layout, 16, 1).is_err());
+    }
+
+    #[test]
+    fn test_expose_fields() {
+        let input = multi_field_input();
+        for hash in [
+            &Blake3Hash::default() as &dyn CollisionHash,
+            &Blake3CompactHash,
+            &Sha256Hash,
+        ] {
+            assert!(run_field_check(hash, &input, 0, 0x1234));
+            assert!(run_field_check(hash, &input, 2, 0x0abc_def0));
+            assert!(run_field_check(hash, &input, 3, 0x7f));
+            assert!(!run_field_check(hash, &input, 3, 0x7e));
+        }
+    }
+
+    #[test]
+    fn test_sha256_flow_id() {
+        let (x, nonce) = (114u32, 0x0102_0304_0506_0708u64);
+        let message =
+            [x.to_le_bytes().as_slice(), &nonce.to_le_bytes()].concat();
+        let digest = sha256::Hash::hash(&message).to_byte_array();
+        let flow_id = Sha256Hash.flow_id(&Input::try_from(x).unwrap(), nonce);
+        assert_eq!(flow_id.to_le_bytes(), digest[..16]);
+
+        let witness =
+            Sha256Hash.witness(&Input::try_from(x).unwrap(), nonce, 16);
+        assert_eq!(
+            witness,
+            vec![
+                digest[2..].to_vec(),
+                vec![114u8],
+                nonce.to_le_bytes().to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sha256_prefix_check_script() {
+        let (x, b_bits) = (114u32, 16);
+        let input = Input::try_from(x).unwrap();
+        let layout = input.layout();
+        for nonce in [0u64, 1, 0xdead_beef] {
+            let flow_id =
+                Sha256Hash.flow_id(&input, nonce) & prefix_mask(b_bits);
+            let flow_id_prefix = flow_id_to_prefix_bytes(flow_id, b_bits);
+            let witness = Sha256Hash.witness(&input, nonce, b_bits);
+            let run = |witness: &[Vec<u8>], prefix: &[u8], x: u32| {
+                run_sha256_check(witness, layout, prefix, b_bits, x)
+            };
+            assert!(run(&witness, &flow_id_prefix, x));
+
+            // Another flow's prefix
+            let other_prefix = flow_id_to_prefix_bytes(flow_id ^ 1, b_bits);
+            assert!(!run(&witness, &other_prefix, x));
+
+            // Claiming another x with the same nonce
+            let mut forged = witness.clone();
+            forged[1] = encode_scriptnum(115);
+            assert!(!run(&forged, &flow_id_prefix, 115));
+
+            // Shifting a byte of r into x
+            let mut forged = witness.clone();
+            let byte = forged[2].remove(0);
+            forged[1].push(byte);
+            assert!(!run(&forged, &flow_id_prefix, x));
+
+            // Non-minimal encoding of x
+            let mut forged = witness.clone();
+            forged[1].push(0);
+            assert!(!run(&forged, &flow_id_prefix, x));
+        }
+    }
+
+    #[test]
+    fn test_sha256_multi_field_prefix_check() {
+        let input = multi_field_input();
+        let layout = input.layout();
+        let b_bits = 16;
+        let nonce = 3;
+        let flow_id = Sha256Hash.flow_id(&input, nonce) & prefix_mask(b_bits);
+        let flow_id_prefix = flow_id_to_prefix_bytes(flow_id, b_bits);
+        let witness = Sha256Hash.witness(&input, nonce, b_bits);
+        assert!(run_sha256_check(
+            &witness,
+            layout,
+            &flow_id_prefix,
+            b_bits,
+            0x1234
+        ));
+
+        // Moving a byte from the bytes field into the next number
+        let mut forged = witness.clone();
+        let byte = forged[2].pop().unwrap();
+        forged[3].insert(0, byte);
+        assert!(!run_sha256_check(
+            &forged,
+            layout,
+            &flow_id_prefix,
+            b_bits,
+            0x1234
+        ));
+    }
+}
@@ -0,0 +1,287 @@
+//! The program input `x`.
+//!
+//! `x` is a byte string made of fixed-width fields described by an
+//! [`InputLayout`]. The collision hash commits to `x || r`, and each
+//! [`crate::core::SubFunction`] reads one [`Field::Number`] of `x`. The toy
+//! program uses a single 4-byte number, see [`InputLayout::default`].
+
+/// Longest supported input: `blake3_message_to_limbs` takes messages of up
+/// to 1024 bytes, 8 of which hold the nonce `r`. The witness encoding of a
+/// hash may run out of stack earlier, see
+/// [`crate::hash::CollisionHash::check_layout`].
+pub const MAX_INPUT_LEN: usize = 1024 - 8;
+
+/// A fixed-width field of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// Little-endian unsigned number of 1 to 4 bytes, exposed to the
+    /// sub-functions as a script number. It must stay below `2^(8w-1)` to
+    /// be a non-negative script number.
+    Number(usize),
+    /// Opaque bytes (a hash, a key, ...) committed to by `H` but not
+    /// exposed to the sub-functions.
+    Bytes(usize),
+}
+
+impl Field {
+    /// Width of the field in bytes.
+    pub fn width(&self) -> usize {
+        match *self {
+            Field::Number(width) | Field::Bytes(width) => width,
+        }
+    }
+}
+
+/// A value for one field, see [`InputLayout::encode`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue {
+    Number(u32),
+    Bytes(Vec<u8>),
+}
+
+/// The fields of the input `x`, in message order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputLayout {
+    fields: Vec<Field>,
+}
+
+impl Default for InputLayout {
+    /// A single 4-byte number, the `u32` input of the toy program.
+    fn default() -> Self {
+        Self {
+            fields: vec![Field::Number(4)],
+        }
+    }
+}
+
+impl InputLayout {
+    pub fn new(fields: Vec<Field>) -> Result<Self, String> {
+        if fields.is_empty() {
+            return Err("the input needs at least one field".to_string());
+        }
+        for (i, field) in fields.iter().enumerate() {
+            match *field {
+                Field::Number(width) if !(1..=4).contains(&width) => {
+                    return Err(format!(
+                        "number field {i} is {width} bytes wide, expected 1 to 4"
+                    ));
+                }
+                Field::Bytes(0) => {
+                    return Err(format!("bytes field {i} is empty"));
+                }
+                _ => {}
+            }
+        }
+        let layout = Self { fields };
+        if layout.len() > MAX_INPUT_LEN {
+            return Err(format!(
+                "input of {} bytes exceeds {MAX_INPUT_LEN} bytes",
+                layout.len()
+            ));
+        }
+        Ok(layout)
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Total length of `x` in bytes.
+    pub fn len(&self) -> usize {
+        self.fields.iter().map(Field::width).sum()
+    }
+
+    /// Always false, a layout has at least one field.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Byte offset of field `field` in `x`.
+    pub fn offset(&self, field: usize) -> usize {
+        self.fields[..field].iter().map(Field::width).sum()
+    }
+
+    /// Width of field `field` if it is a number, the only fields the
+    /// sub-functions can read.
+    pub fn number_width(&self, field: usize) -> Result<usize, String> {
+        match self.fields.get(field) {
+            Some(Field::Number(width)) => Ok(*width),
+            Some(Field::Bytes(_)) => {
+                Err(format!("field {field} is opaque bytes, not a number"))
+            }
+            None => Err(format!(
+                "field {field} out of range for {} fields",
+                self.fields.len()
+            )),
+        }
+    }
+
+    /// The input with every bit set that the layout allows, number fields
+    /// staying below `2^(8w-1)`. Witness encodings growing with the input
+    /// bits are the largest for it.
+    pub fn max_input(&self) -> Input {
+        let mut bytes = vec![0xff; self.len()];
+        for (i, field) in self.fields.iter().enumerate() {
+            if let Field::Number(width) = *field {
+                bytes[self.offset(i) + width - 1] = 0x7f;
+            }
+        }
+        Input::new(self.clone(), bytes).expect("valid maximal input")
+    }
+
+    /// Serialize one value per field into an input.
+    pub fn encode(&self, values: &[FieldValue]) -> Result<Input, String> {
+        if values.len() != self.fields.len() {
+            return Err(format!(
+                "expected {} field values, got {}",
+                self.fields.len(),
+                values.len()
+            ));
+        }
+        let mut bytes = Vec::with_capacity(self.len());
+        for (i, (field, value)) in self.fields.iter().zip(values).enumerate() {
+            match (*field, value) {
+                (Field::Number(width), FieldValue::Number(n))
+                    if u64::from(*n) >> (8 * width - 1) == 0 =>
+                {
+                    bytes.extend_from_slice(&n.to_le_bytes()[..width]);
+                }
+                (Field::Bytes(width), FieldValue::Bytes(data))
+                    if data.len() == width =>
+                {
+                    bytes.extend_from_slice(data);
+                }
+                _ => {
+                    return Err(format!(
+                        "value {value:?} does not fit field {i} ({field:?})"
+                    ));
+                }
+            }
+        }
+        Input::new(self.clone(), bytes)
+    }
+}
+
+/// An input `x`: the concatenation of the fields of its layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input {
+    layout: InputLayout,
+    bytes: Vec<u8>,
+}
+
+impl TryFrom<u32> for Input {
+    type Error = String;
+
+    /// The toy `u32` input, 4 little-endian bytes. Fails from `2^31` on,
+    /// like any [`Field::Number`] of the default layout.
+    fn try_from(x: u32) -> Result<Self, String> {
+        Self::new(InputLayout::default(), x.to_le_bytes().to_vec())
+    }
+}
+
+impl Input {
+    /// Fails unless `bytes` matches `layout` and every number field is a
+    /// non-negative script number.
+    pub fn new(layout: InputLayout, bytes: Vec<u8>) -> Result<Self, String> {
+        if bytes.len() != layout.len() {
+            return Err(format!(
+                "input of {} bytes does not match a {} byte layout",
+                bytes.len(),
+                layout.len()
+            ));
+        }
+        let input = Self { layout, bytes };
+        for (i, field) in input.layout.fields.iter().enumerate() {
+            if let Field::Number(width) = *field
+                && input.number(i)? >> (8 * width - 1) != 0
+            {
+                return Err(format!(
+                    "number field {i} must be below 2^{}",
+                    8 * width - 1
+                ));
+            }
+        }
+        Ok(input)
+    }
+
+    pub fn layout(&self) -> &InputLayout {
+        &self.layout
+    }
+
+    /// The serialized input, as committed to by the collision hash.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Raw bytes of field `field`.
+    pub fn field_bytes(&self, field: usize) -> &[u8] {
+        let offset = self.layout.offset(field);
+        &self.bytes[offset..offset + self.layout.fields[field].width()]
+    }
+
+    /// Value of number field `field`.
+    pub fn number(&self, field: usize) -> Result<u32, String> {
+        self.layout.number_width(field)?;
+        let mut le_bytes = [0u8; 4];
+        let data = self.field_bytes(field);
+        le_bytes[..data.len()].copy_from_slice(data);
+        Ok(u32::from_le_bytes(le_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_fields() {
+        let layout = InputLayout::new(vec![
+            Field::Number(4),
+            Field::Bytes(3),
+            Field::Number(2),
+        ])
+        .unwrap();
+        assert_eq!(layout.len(), 9);
+        assert_eq!(layout.offset(2), 7);
+
+        let input = layout
+            .encode(&[
+                FieldValue::Number(114),
+                FieldValue::Bytes(vec![0xaa, 0xbb, 0xcc]),
+                FieldValue::Number(0x1234),
+            ])
+            .unwrap();
+        assert_eq!(
+            input.bytes(),
+            [0x72, 0, 0, 0, 0xaa, 0xbb, 0xcc, 0x34, 0x12]
+        );
+        assert_eq!(input.number(0), Ok(114));
+        assert_eq!(input.number(2), Ok(0x1234));
+        assert!(input.number(1).is_err());
+        assert_eq!(input.field_bytes(1), [0xaa, 0xbb, 0xcc]);
+
+        assert_eq!(
+            Input::try_from(114).unwrap(),
+            InputLayout::default()
+                .encode(&[FieldValue::Number(114)])
+                .unwrap()
+        );
+        assert!(Input::try_from(0x7fff_ffff).is_ok());
+        assert!(Input::try_from(0x8000_0000).is_err());
+    }
+
+    #[test]
+    fn test_invalid_layouts_and_values() {
+        assert!(InputLayout::new(vec![]).is_err());
+        assert!(InputLayout::new(vec![Field::Number(5)]).is_err());
+        assert!(
+            InputLayout::new(vec![Field::Bytes(MAX_INPUT_LEN + 1)]).is_err()
+        );
+
+        let layout = InputLayout::new(vec![Field::Number(2)]).unwrap();
+        // Not a non-negative 2-byte script number
+        assert!(layout.encode(&[FieldValue::Number(0x8000)]).is_err());
+        assert!(layout.encode(&[FieldValue::Bytes(vec![1, 2])]).is_err());
+        assert!(Input::new(layout, vec![1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,803 @@
+//! A step-by-step Tapscript interpreter.
+//!
+//! `bitvm::execute_script_buf` only reports how a script ended. The
+//! [`Interpreter`] executes one opcode at a time and exposes the stack and
+//! altstack in between, which is what the resource analysis of
+//! [`crate::analysis`] needs.
+//!
+//! Signatures are verified against the BIP-341 sighash of the spend given to
+//! [`Interpreter::with_spend`]. Without a spend there is no message to
+//! verify: any non-empty signature of a valid size is accepted. `OP_CAT`
+//! follows BIP-347, every other `OP_SUCCESSx` is rejected, and timelocks are
+//! not checked against a transaction.
+
+use bitcoin::hashes::{Hash, hash160, ripemd160, sha1, sha256, sha256d};
+use bitcoin::opcodes::all::*;
+use bitcoin::opcodes::{Class, ClassifyContext, Opcode};
+use bitcoin::script::{Instruction, Script};
+use bitcoin::secp256k1::{Message, XOnlyPublicKey};
+use bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use bitcoin::taproot::{self, TapLeafHash};
+use bitcoin::{ScriptBuf, Transaction, TxOut};
+use secp256k1::SECP256K1;
+use std::fmt;
+
+/// Maximum number of elements on the stack and altstack together.
+pub const MAX_STACK_SIZE: usize = 1000;
+/// Maximum size of a stack element in bytes.
+pub const MAX_ELEMENT_SIZE: usize = 520;
+/// Maximum size of a script number operand in bytes.
+const MAX_NUM_SIZE: usize = 4;
+
+/// A parsed script instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Push(Vec<u8>),
+    Opcode(Opcode),
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Push(data) if data.is_empty() => write!(f, "OP_0"),
+            Op::Push(data) => write!(f, "<{}>", hex::encode(data)),
+            Op::Opcode(opcode) => write!(f, "{opcode}"),
+        }
+    }
+}
+
+/// Why and where a script failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecError {
+    /// Byte offset in the script of the failing instruction.
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at byte {})", self.reason, self.offset)
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// Executes a tapscript leaf on an initial stack, one opcode at a time.
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    ops: Vec<(usize, Op)>,
+    script_len: usize,
+    next: usize,
+    stack: Vec<Vec<u8>>,
+    altstack: Vec<Vec<u8>>,
+    conditions: Vec<bool>,
+    enforce_limits: bool,
+    spend: Option<Spend>,
+    /// Index in [`Self::ops`] of the last executed `OP_CODESEPARATOR`.
+    codeseparator: u32,
+}
+
+/// The transaction input executing the script, to compute sighashes.
+#[derive(Debug, Clone)]
+struct Spend {
+    tx: Transaction,
+    input: usize,
+    prevouts: Vec<TxOut>,
+}
+
+impl Interpreter {
+    /// Parses `script`, `stack` holds the witness elements, the last one on
+    /// top.
+    pub fn new(
+        script: &Script,
+        stack: Vec<Vec<u8>>,
+    ) -> Result<Self, ExecError> {
+        let mut ops = vec![];
+        for instruction in script.instruction_indices() {
+            let offset = ops.last().map_or(0, |&(offset, _)| offset + 1);
+            let (offset, instruction) =
+                instruction.map_err(|err| ExecError {
+                    offset,
+                    reason: err.to_string(),
+                })?;
+            let op = match instruction {
+                Instruction::PushBytes(data) => {
+                    Op::Push(data.as_bytes().to_vec())
+                }
+                Instruction::Op(opcode) => {
+                    if opcode != OP_CAT
+                        && opcode.classify(ClassifyContext::TapScript)
+                            == Class::SuccessOp
+                    {
+                        return Err(ExecError {
+                            offset,
+                            reason: format!(
+                                "{opcode} is an OP_SUCCESS, the script would \
+                                 always succeed"
+                            ),
+                        });
+                    }
+                    Op::Opcode(opcode)
+                }
+            };
+            ops.push((offset, op));
+        }
+        Ok(Self {
+            ops,
+            script_len: script.len(),
+            next: 0,
+            stack,
+            altstack: vec![],
+            conditions: vec![],
+            enforce_limits: true,
+            spend: None,
+            codeseparator: u32::MAX,
+        })
+    }
+
+    /// Verify signatures as input `input` of `tx`, spending `prevouts` (one
+    /// per input of `tx`). The script must be the leaf revealed by the
+    /// witness of that input, see [`tapscript_spend`].
+    pub fn with_spend(
+        mut self,
+        tx: &Transaction,
+        input: usize,
+        prevouts: &[TxOut],
+    ) -> Self {
+        self.spend = Some(Spend {
+            tx: tx.clone(),
+            input,
+            prevouts: prevouts.to_vec(),
+        });
+        self
+    }
+
+    /// Keep executing past [`MAX_STACK_SIZE`] and [`MAX_ELEMENT_SIZE`], to
+    /// measure by how much a script exceeds them.
+    pub fn without_limits(mut self) -> Self {
+        self.enforce_limits = false;
+        self
+    }
+
+    /// The parsed script with the byte offset of each instruction.
+    pub fn ops(&self) -> &[(usize, Op)] {
+        &self.ops
+    }
+
+    /// Index in [`Self::ops`] of the next instruction.
+    pub fn position(&self) -> usize {
+        self.next
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next == self.ops.len()
+    }
+
+    /// The main stack, top last.
+    pub fn stack(&self) -> &[Vec<u8>] {
+        &self.stack
+    }
+
+    /// The altstack, top last.
+    pub fn altstack(&self) -> &[Vec<u8>] {
+        &self.altstack
+    }
+
+    /// Whether the next instruction runs, false inside a branch not taken.
+    pub fn is_executing(&self) -> bool {
+        self.conditions.iter().all(|&taken| taken)
+    }
+
+    /// Executes the next instruction. Panics if the script is done.
+    pub fn step(&mut self) -> Result<(), ExecError> {
+        let (offset, op) = self.ops[self.next].clone();
+        let fail = |reason| ExecError { offset, reason };
+        if self.next == 0
+            && self.enforce_limits
+            && let Some(element) =
+                self.stack.iter().find(|e| e.len() > MAX_ELEMENT_SIZE)
+        {
+            return Err(fail(format!(
+                "witness element of {} bytes",
+                element.len()
+            )));
+        }
+        self.next += 1;
+        match op {
+            Op::Push(data) => {
+                if self.enforce_limits && data.len() > MAX_ELEMENT_SIZE {
+                    return Err(fail(format!("push of {} bytes", data.len())));
+                }
+                if self.is_executing() {
+                    self.stack.push(data);
+                }
+            }
+            Op::Opcode(opcode) => self.execute(opcode).map_err(fail)?,
+        }
+        let depth = self.stack.len() + self.altstack.len();
+        if self.enforce_limits && depth > MAX_STACK_SIZE {
+            return Err(fail(format!("{depth} stack elements")));
+        }
+        Ok(())
+    }
+
+    /// Executes the remaining instructions, then [`Self::finish`].
+    pub fn run(&mut self) -> Result<(), ExecError> {
+        while !self.is_done() {
+            self.step()?;
+        }
+        self.finish()
+    }
+
+    /// The checks of a finished tapscript: no open `OP_IF` and a single true
+    /// element left.
+    pub fn finish(&self) -> Result<(), ExecError> {
+        let fail = |reason: &str| ExecError {
+            offset: self.script_len,
+            reason: reason.to_string(),
+        };
+        if !self.conditions.is_empty() {
+            return Err(fail("unbalanced conditional"));
+        }
+        match self.stack.as_slice() {
+            [top] if cast_to_bool(top) => Ok(()),
+            [_] => Err(fail("false result")),
+            stack => Err(fail(&format!(
+                "{} elements left on the stack, expected 1",
+                stack.len()
+            ))),
+        }
+    }
+
+    fn execute(&mut self, opcode: Opcode) -> Result<(), String> {
+        match opcode {
+            OP_IF | OP_NOTIF => {
+                let mut taken = false;
+                if self.is_executing() {
+                    let top = self.pop()?;
+                    if top.len() > 1 || top.first().is_some_and(|&b| b != 1) {
+                        return Err(format!(
+                            "{opcode} argument must be empty or 0x01"
+                        ));
+                    }
+                    taken = top.is_empty() == (opcode == OP_NOTIF);
+                }
+                self.conditions.push(taken);
+                return Ok(());
+            }
+            OP_ELSE => {
+                let taken = self
+                    .conditions
+                    .last_mut()
+                    .ok_or("OP_ELSE without OP_IF")?;
+                *taken = !*taken;
+                return Ok(());
+            }
+            OP_ENDIF => {
+                self.conditions.pop().ok_or("OP_ENDIF without OP_IF")?;
+                return Ok(());
+            }
+            OP_VERIF | OP_VERNOTIF => {
+                return Err(format!("{opcode} is invalid"));
+            }
+            _ if !self.is_executing() => return Ok(()),
+            _ => {}
+        }
+
+        let code = opcode.to_u8();
+        if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&code) {
+            let n = i64::from(code - OP_PUSHNUM_1.to_u8() + 1);
+            self.stack.push(encode_num(n));
+            return Ok(());
+        }
+
+        match opcode {
+            OP_PUSHNUM_NEG1 => self.stack.push(encode_num(-1)),
+            OP_NOP | OP_NOP1 | OP_NOP4 | OP_NOP5 | OP_NOP6 | OP_NOP7
+            | OP_NOP8 | OP_NOP9 | OP_NOP10 => {}
+            OP_CODESEPARATOR => self.codeseparator = (self.next - 1) as u32,
+            OP_CLTV | OP_CSV => {
+                let locktime = decode_num(self.peek(0)?, 5)?;
+                if locktime < 0 {
+                    return Err(format!("negative {opcode} argument"));
+                }
+            }
+            OP_VERIFY => self.verify(opcode)?,
+            OP_RETURN => return Err("OP_RETURN".to_string()),
+
+            OP_TOALTSTACK => {
+                let top = self.pop()?;
+                self.altstack.push(top);
+            }
+            OP_FROMALTSTACK => {
+                let top = self.altstack.pop().ok_or("empty altstack")?;
+                self.stack.push(top);
+            }
+            OP_2DROP => {
+                self.pop()?;
+                self.pop()?;
+            }
+            OP_2DUP => self.copy(&[1, 1])?,
+            OP_3DUP => self.copy(&[2, 2, 2])?,
+            OP_2OVER => self.copy(&[3, 3])?,
+            OP_2ROT => self.roll(&[5, 5])?,
+            OP_2SWAP => self.roll(&[3, 3])?,
+            OP_IFDUP => {
+                if cast_to_bool(self.peek(0)?) {
+                    self.copy(&[0])?;
+                }
+            }
+            OP_DEPTH => self.stack.push(encode_num(self.stack.len() as i64)),
+            OP_DROP => {
+                self.pop()?;
+            }
+            OP_DUP => self.copy(&[0])?,
+            OP_NIP => {
+                let top = self.pop()?;
+                self.pop()?;
+                self.stack.push(top);
+            }
+            OP_OVER => self.copy(&[1])?,
+            OP_PICK | OP_ROLL => {
+                let n = self.pop_num()?;
+                if n < 0 || n as usize >= self.stack.len() {
+                    return Err(format!("{opcode} index {n} out of range"));
+                }
+                if opcode == OP_PICK {
+                    self.copy(&[n as usize])?;
+                } else {
+                    self.roll(&[n as usize])?;
+                }
+            }
+            OP_ROT => self.roll(&[2])?,
+            OP_SWAP => self.roll(&[1])?,
+            OP_TUCK => {
+                self.peek(1)?;
+                let top = self.peek(0)?.clone();
+                self.stack.insert(self.stack.len() - 2, top);
+            }
+
+            OP_CAT => {
+                let b = self.pop()?;
+                let mut a = self.pop()?;
+                if self.enforce_limits && a.len() + b.len() > MAX_ELEMENT_SIZE {
+                    return Err(format!(
+                        "OP_CAT result of {} bytes",
+                        a.len() + b.len()
+                    ));
+                }
+                a.extend(b);
+                self.stack.push(a);
+            }
+            OP_SIZE => {
+                let size = self.peek(0)?.len() as i64;
+                self.stack.push(encode_num(size));
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.stack.push(encode_bool(a == b));
+                if opcode == OP_EQUALVERIFY {
+                    self.verify(opcode)?;
+                }
+            }
+
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let a = self.pop_num()?;
+                let result = match opcode {
+                    OP_1ADD => a + 1,
+                    OP_1SUB => a - 1,
+                    OP_NEGATE => -a,
+                    OP_ABS => a.abs(),
+                    OP_NOT => i64::from(a == 0),
+                    _ => i64::from(a != 0),
+                };
+                self.stack.push(encode_num(result));
+            }
+            OP_ADD
+            | OP_SUB
+            | OP_BOOLAND
+            | OP_BOOLOR
+            | OP_NUMEQUAL
+            | OP_NUMEQUALVERIFY
+            | OP_NUMNOTEQUAL
+            | OP_LESSTHAN
+            | OP_GREATERTHAN
+            | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL
+            | OP_MIN
+            | OP_MAX => {
+                let b = self.pop_num()?;
+                let a = self.pop_num()?;
+                let result = match opcode {
+                    OP_ADD => a + b,
+                    OP_SUB => a - b,
+                    OP_BOOLAND => i64::from(a != 0 && b != 0),
+                    OP_BOOLOR => i64::from(a != 0 || b != 0),
+                    OP_NUMEQUAL | OP_NUMEQUALVERIFY => i64::from(a == b),
+                    OP_NUMNOTEQUAL => i64::from(a != b),
+                    OP_LESSTHAN => i64::from(a < b),
+                    OP_GREATERTHAN => i64::from(a > b),
+                    OP_LESSTHANOREQUAL => i64::from(a <= b),
+                    OP_GREATERTHANOREQUAL => i64::from(a >= b),
+                    OP_MIN => a.min(b),
+                    _ => a.max(b),
+                };
+                self.stack.push(encode_num(result));
+                if opcode == OP_NUMEQUALVERIFY {
+                    self.verify(opcode)?;
+                }
+            }
+            OP_WITHIN => {
+                let max = self.pop_num()?;
+                let min = self.pop_num()?;
+                let a = self.pop_num()?;
+                self.stack.push(encode_bool(min <= a && a < max));
+            }
+
+            OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                let data = self.pop()?;
+                let digest = match opcode {
+                    OP_RIPEMD160 => {
+                        ripemd160::Hash::hash(&data).to_byte_array().to_vec()
+                    }
+                    OP_SHA1 => sha1::Hash::hash(&data).to_byte_array().to_vec(),
+                    OP_SHA256 => {
+                        sha256::Hash::hash(&data).to_byte_array().to_vec()
+                    }
+                    OP_HASH160 => {
+                        hash160::Hash::hash(&data).to_byte_array().to_vec()
+                    }
+                    _ => sha256d::Hash::hash(&data).to_byte_array().to_vec(),
+                };
+                self.stack.push(digest);
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let key = self.pop()?;
+                let sig = self.pop()?;
+                self.stack.push(encode_bool(self.check_sig(&sig, &key)?));
+                if opcode == OP_CHECKSIGVERIFY {
+                    self.verify(opcode)?;
+                }
+            }
+            OP_CHECKSIGADD => {
+                let key = self.pop()?;
+                let n = self.pop_num()?;
+                let sig = self.pop()?;
+                let valid = self.check_sig(&sig, &key)?;
+                self.stack.push(encode_num(n + i64::from(valid)));
+            }
+            _ => return Err(format!("{opcode} is disabled in tapscript")),
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, String> {
+        self.stack.pop().ok_or_else(|| "empty stack".to_string())
+    }
+
+    fn pop_num(&mut self) -> Result<i64, String> {
+        decode_num(&self.pop()?, MAX_NUM_SIZE)
+    }
+
+    /// The element `depth` positions below the top.
+    fn peek(&self, depth: usize) -> Result<&Vec<u8>, String> {
+        self.stack
+            .len()
+            .checked_sub(depth + 1)
+            .map(|i| &self.stack[i])
+            .ok_or_else(|| {
+                format!("stack has fewer than {} elements", depth + 1)
+            })
+    }
+
+    /// Pushes copies of the elements at the given depths, in order.
+    fn copy(&mut self, depths: &[usize]) -> Result<(), String> {
+        for &depth in depths {
+            let element = self.peek(depth)?.clone();
+            self.stack.push(element);
+        }
+        Ok(())
+    }
+
+    /// Moves the elements at the given depths to the top, in order.
+    fn roll(&mut self, depths: &[usize]) -> Result<(), String> {
+        for &depth in depths {
+            self.peek(depth)?;
+            let element = self.stack.remove(self.stack.len() - 1 - depth);
+            self.stack.push(element);
+        }
+        Ok(())
+    }
+
+    /// BIP-342 signature check: an empty signature is false, any other one
+    /// must be valid. Keys of other sizes than 32 bytes are upgradable and
+    /// always succeed. Without [`Self::with_spend`] only the sizes are
+    /// checked.
+    fn check_sig(&self, sig: &[u8], key: &[u8]) -> Result<bool, String> {
+        if key.is_empty() {
+            return Err("empty public key".to_string());
+        }
+        if sig.is_empty() || key.len() != 32 {
+            return Ok(!sig.is_empty());
+        }
+        if sig.len() == 65 && sig[64] == 0 {
+            return Err("explicit SIGHASH_DEFAULT byte".to_string());
+        }
+        let sig = taproot::Signature::from_slice(sig)
+            .map_err(|err| format!("signature: {err}"))?;
+        let Some(spend) = &self.spend else {
+            return Ok(true);
+        };
+        let key = XOnlyPublicKey::from_slice(key)
+            .map_err(|_| "public key not on the curve".to_string())?;
+        let witness = &spend
+            .tx
+            .input
+            .get(spend.input)
+            .ok_or_else(|| format!("input {} out of range", spend.input))?
+            .witness;
+        let leaf = witness
+            .taproot_leaf_script()
+            .ok_or("the spend is not a tapscript spend")?;
+        let leaf_hash = TapLeafHash::from_script(leaf.script, leaf.version);
+        let annex = witness
+            .taproot_annex()
+            .map(Annex::new)
+            .transpose()
+            .map_err(|err| err.to_string())?;
+        let sighash = SighashCache::new(&spend.tx)
+            .taproot_signature_hash(
+                spend.input,
+                &Prevouts::All(&spend.prevouts),
+                annex,
+                Some((leaf_hash, self.codeseparator)),
+                sig.sighash_type,
+            )
+            .map_err(|err| format!("sighash: {err}"))?;
+        let message = Message::from_digest(sighash.to_byte_array());
+        SECP256K1
+            .verify_schnorr(&sig.signature, &message, &key)
+            .map_err(|_| "invalid signature".to_string())?;
+        Ok(true)
+    }
+
+    fn verify(&mut self, opcode: Opcode) -> Result<(), String> {
+        if cast_to_bool(&self.pop()?) {
+            Ok(())
+        } else {
+            Err(format!("{opcode} failed"))
+        }
+    }
+}
+
+/// The leaf script and the initial stack of tapscript input `input` of `tx`.
+pub fn tapscript_spend(
+    tx: &Transaction,
+    input: usize,
+) -> Result<(ScriptBuf, Vec<Vec<u8>>), String> {
+    let witness = &tx
+        .input
+        .get(input)
+        .ok_or_else(|| format!("input {input} out of range"))?
+        .witness;
+    let script = witness
+        .taproot_leaf_script()
+        .ok_or_else(|| format!("input {input} is not a tapscript spend"))?
+        .script;
+    let has_annex = witness.len() >= 2
+        && witness
+            .last()
+            .is_some_and(|last| last.first() == Some(&0x50));
+    let elements = witness.len() - if has_annex { 3 } else { 2 };
+    let stack = witness.iter().take(elements).map(<[u8]>::to_vec).collect();
+    Ok((script.to_owned(), stack))
+}
+
+fn cast_to_bool(element: &[u8]) -> bool {
+    match element.split_last() {
+        Some((&last, rest)) => {
+            rest.iter().any(|&b| b != 0) || (last != 0 && last != 0x80)
+        }
+        None => false,
+    }
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    encode_num(i64::from(value))
+}
+
+/// Minimal script number encoding.
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        bytes.push(abs as u8);
+        abs >>= 8;
+    }
+    match bytes.last_mut() {
+        Some(last) if *last & 0x80 != 0 => {
+            bytes.push(if n < 0 { 0x80 } else { 0 })
+        }
+        Some(last) if n < 0 => *last |= 0x80,
+        _ => {}
+    }
+    bytes
+}
+
+/// Decodes a minimally encoded script number of at most `max_len` bytes.
+pub fn decode_num(bytes: &[u8], max_len: usize) -> Result<i64, String> {
+    if bytes.len() > max_len {
+        return Err(format!("number of {} bytes", bytes.len()));
+    }
+    let Some((&last, rest)) = bytes.split_last() else {
+        return Ok(0);
+    };
+    if last & 0x7f == 0 && rest.last().is_none_or(|&b| b & 0x80 == 0) {
+        return Err(format!("non-minimal number {}", hex::encode(bytes)));
+    }
+    let magnitude = bytes
+        .iter()
+        .rev()
+        .fold(0i64, |acc, &b| acc << 8 | i64::from(b))
+        & !(0x80 << (8 * (bytes.len() - 1)));
+    Ok(if last & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::SubFunction;
+    use crate::predicate::Predicate;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::key::Keypair;
+    use bitcoin::script::Builder;
+    use bitcoin::taproot::{LeafVersion, TaprootBuilder};
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, OutPoint, TapSighashType, TxIn, Witness};
+
+    fn run(script: &Script, stack: Vec<Vec<u8>>) -> Result<(), ExecError> {
+        Interpreter::new(script, stack)?.run()
+    }
+
+    #[test]
+    fn test_script_numbers() {
+        for n in [0, 1, -1, 127, 128, -128, 255, 0x7fff_ffff, -0x7fff_ffff] {
+            assert_eq!(decode_num(&encode_num(n), 5), Ok(n), "{n}");
+        }
+        assert_eq!(encode_num(128), [0x80, 0]);
+        assert_eq!(encode_num(-1), [0x81]);
+        assert!(decode_num(&[1, 0], 4).is_err());
+        assert!(decode_num(&[0x80], 4).is_err());
+        assert!(decode_num(&[1, 0, 0, 0, 1], 4).is_err());
+    }
+
+    #[test]
+    fn test_tapscript_rules() {
+        let script = Builder::new()
+            .push_opcode(OP_IF)
+            .push_int(7)
+            .push_opcode(OP_ELSE)
+            .push_int(8)
+            .push_opcode(OP_ENDIF)
+            .push_int(8)
+            .push_opcode(OP_NUMEQUAL)
+            .into_script();
+        assert!(run(&script, vec![vec![]]).is_ok());
+        assert!(run(&script, vec![vec![1]]).is_err());
+        // MINIMALIF
+        assert!(run(&script, vec![vec![2]]).is_err());
+        // CLEANSTACK
+        assert!(run(&script, vec![vec![9], vec![]]).is_err());
+
+        let op_return = ScriptBuf::from_bytes(vec![0x51, 0x6a]);
+        assert_eq!(run(&op_return, vec![]).unwrap_err().offset, 1);
+
+        let push_0x89 = ScriptBuf::from_bytes(vec![0x01, 0x89]);
+        assert!(Interpreter::new(&push_0x89, vec![]).is_ok());
+        let op_success = ScriptBuf::from_bytes(vec![0x89]);
+        assert!(Interpreter::new(&op_success, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_predicate_scripts() {
+        for text in ["within:100..200", "mod:7=2&nobit:30", "ne:114&bit:1"] {
+            let predicate = text.parse::<Predicate>().unwrap();
+            let mut script = predicate.script().to_bytes();
+            script.push(OP_PUSHNUM_1.to_u8());
+            let script = ScriptBuf::from_bytes(script);
+            for x in [0, 2, 100, 114, 116, 199, 1 << 30, i32::MAX as u32] {
+                let result = run(&script, vec![encode_num(x.into())]);
+                assert_eq!(result.is_ok(), predicate.evaluate(x), "{text} {x}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_signature_verification() {
+        let keypair = Keypair::from_seckey_slice(SECP256K1, &[7; 32]).unwrap();
+        let key = keypair.x_only_public_key().0;
+        let script = Builder::new()
+            .push_x_only_key(&key)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, script.clone())
+            .unwrap()
+            .finalize(SECP256K1, key)
+            .unwrap();
+        let prevout = TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        };
+        let mut tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let sighash = SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(std::slice::from_ref(&prevout)),
+                TapLeafHash::from_script(&script, LeafVersion::TapScript),
+                TapSighashType::Default,
+            )
+            .unwrap();
+        let message = Message::from_digest(sighash.to_byte_array());
+        let sig = SECP256K1.sign_schnorr_no_aux_rand(&message, &keypair);
+        let control_block = spend_info
+            .control_block(&(script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        let mut run_spend = |sig: Vec<u8>, prevout: &TxOut| {
+            tx.input[0].witness = Witness::from_slice(&[
+                sig,
+                script.to_bytes(),
+                control_block.serialize(),
+            ]);
+            let (script, stack) = tapscript_spend(&tx, 0).unwrap();
+            Interpreter::new(&script, stack)
+                .unwrap()
+                .with_spend(&tx, 0, std::slice::from_ref(prevout))
+                .run()
+        };
+
+        let valid = sig.as_ref().to_vec();
+        assert_eq!(run_spend(valid.clone(), &prevout), Ok(()));
+        let mut forged = valid.clone();
+        forged[0] ^= 1;
+        let err = run_spend(forged, &prevout).unwrap_err();
+        assert_eq!(err.reason, "invalid signature");
+        // The same signature over another spent amount
+        let other = TxOut {
+            value: Amount::from_sat(20_000),
+            ..prevout.clone()
+        };
+        assert!(run_spend(valid.clone(), &other).is_err());
+        // BIP-342: a 65-byte signature never carries SIGHASH_DEFAULT
+        let mut explicit_default = valid.clone();
+        explicit_default.push(0);
+        assert!(run_spend(explicit_default, &prevout).is_err());
+        let mut wrong_type = valid;
+        wrong_type.push(TapSighashType::All as u8);
+        assert!(run_spend(wrong_type, &prevout).is_err());
+        assert_eq!(
+            run_spend(vec![], &prevout).unwrap_err().reason,
+            "false result"
+        );
+
+        // Without a spend only the size is checked
+        assert!(run(&script, vec![vec![1; 64]]).is_ok());
+        assert!(run(&script, vec![vec![1; 63]]).is_err());
+    }
+}
@@ -1,4 +1,12 @@
+pub mod analysis;
 pub mod core;
+pub mod flow;
+pub mod flow_set;
+pub mod hash;
+pub mod input;
+pub mod interpreter;
 pub mod musig2;
+pub mod predicate;
+pub mod trace;
 pub mod transactions;
 pub mod utils;
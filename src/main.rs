@@ -8,19 +8,18 @@
 //! # High‑level flow
 //! 1.  **Key generation** – by default the program creates one Signer key and
 //!     one Operator key and prints them (WIF + address).
-//! 2.  **Funding phase** – if the user has _not_ supplied a `funding_txid`, the
-//!     program prints clear CLI instructions telling the user how to fund the
-//!     demo address on Signet and exits.
-//! 3.  **Offline phase** – given a funding UTXO, the program
-//!     * finds a nonce `r` such that `H(x‖r)|_B ∈ D` (using
-//!       `collidervm_toy::core::find_valid_nonce`).
-//!     * chooses the corresponding flow `d` and builds the **locking script**
-//!       for `F1` (and `F2`) using the existing helpers.
-//!     * constructs and signs **tx_f1** (spends the funding UTXO → P2WSH locked
-//!       by the `F1` program).
-//! 4.  **Online phase** – it then builds and signs **f2_tx**, spending the F1
-//!     output with the witness `[sig, flow_id, x, script]`, paying the remaining
-//!     funds to an Operator address.
+//! 2.  **Funding phase** – the deposit the flows need is computed from their
+//!     fee chain. Unless `--funding-utxo` names existing deposits, the wallet
+//!     sends it to the signers' funding address. The amount and scriptPubKey
+//!     of every funding UTXO are then looked up on the node.
+//! 3.  **Offline phase** – given the funding UTXOs, the signers build and
+//!     MuSig2-sign the `F1 → F2 → spend` chain of **every** flow `d ∈ D`
+//!     (`collidervm_toy::flow::offline_setup`), before `x` is known.
+//! 4.  **Online phase** – `collidervm_toy::flow::online_execution` finds a
+//!     nonce `r` such that `H(x‖r)|_B = d ∈ D` on `--threads` workers
+//!     (`collidervm_toy::core::find_valid_nonce_parallel` over the flow set
+//!     `D`), looks up the presigned flow `d` and completes its witnesses
+//!     `[x‖r limbs, sig, script, control block]`.
 //! 5.  Both transactions are written to `f1.tx` and `f2.tx` (raw hex), and all relevant IDs / next steps are printed.
 //!
 //! ## Build & run
@@ -31,16 +30,27 @@
 #![allow(clippy::too_many_arguments)]
 
 use bitcoin::Network;
+use bitcoin::secp256k1::schnorr::Signature;
 use bitcoin::secp256k1::{PublicKey, Secp256k1, XOnlyPublicKey};
-use bitcoin::taproot::TaprootBuilder;
-use bitcoin::{Address, OutPoint};
+use bitcoin::{
+    Address, OutPoint, ScriptBuf, TapSighashType, Transaction, TxOut,
+};
 use bitcoincore_rpc::{Auth, Client, RpcApi};
-use clap::Parser;
-use collidervm_toy::core::{find_valid_nonce, flow_id_to_prefix_bytes};
+use clap::{Parser, ValueEnum};
+use collidervm_toy::analysis::analyze_spend;
+use collidervm_toy::core::{
+    DEFAULT_BLAKE3_LIMB_LEN, F1_THRESHOLD, F2_THRESHOLD,
+};
+use collidervm_toy::flow::{FlowBuilder, offline_setup, online_execution};
+use collidervm_toy::flow_set::{FlowSet, LowRange, validate_params};
+use collidervm_toy::hash::{Blake3CompactHash, Blake3Hash, Sha256Hash};
+use collidervm_toy::input::Input;
 use collidervm_toy::musig2::simulate_musig2;
+use collidervm_toy::predicate::Predicate;
+use collidervm_toy::trace::TraceDetail;
 use collidervm_toy::transactions::{
-    create_f1_tx, create_f2_tx, create_spending_tx, finalize_f1_tx,
-    finalize_lock_tx,
+    FundingUtxo, InternalKey, PayoutOutput, PayoutTemplate, RefundLeaf,
+    funding_address,
 };
 use collidervm_toy::utils::inner_from;
 use collidervm_toy::utils::{
@@ -54,19 +64,145 @@ use output::{
     write_demo_output_to_file,
 };
 
-/// Minimal amount we ask the user to deposit (200 000 sat ≈ 0.002 BTC)
-const REQUIRED_AMOUNT_SAT: u64 = 150_000;
-/// Hard‑coded ColliderVM parameters (match the toy simulation)
+/// Default ColliderVM parameters (match the toy simulation)
 const L_PARAM: usize = 4;
-const B_PARAM: usize = 16; // multiple of 8 ≤ 32
+const B_PARAM: usize = 16; // ≤ 128
+/// Largest L presigned without --many-flows, every flow builds and signs
+/// its own BLAKE3 step scripts
+const MAX_DEMO_L_PARAM: usize = 8;
+
+/// Collision hash `H` checked by the flows
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HashArg {
+    /// BLAKE3 computed in plain Tapscript (~17k vbytes per step)
+    Blake3,
+    /// BLAKE3 with (x, r) pushed as a few numbers and split into limbs in
+    /// script: a smaller witness but a larger script
+    Blake3Compact,
+    /// Native OP_SHA256, needs an OP_CAT-enabled network
+    Sha256,
+}
+
+/// Internal key of the flow outputs
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum InternalKeyArg {
+    /// The signers' key, which can also spend every output directly
+    Signers,
+    /// The BIP-341 NUMS point, outputs only spendable through their leaves
+    Nums,
+    /// The NUMS point plus a random tweak, printed so it can be audited
+    RandomNums,
+}
+
+/// Sighash type of the presigned step signatures
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SighashArg {
+    /// SIGHASH_DEFAULT, the transactions are fully frozen
+    Default,
+    /// SIGHASH_ALL|ANYONECANPAY, the last transaction takes a fee input
+    AllAnyoneCanPay,
+    /// SIGHASH_SINGLE|ANYONECANPAY, the last transaction takes a fee input
+    /// and a change output
+    SingleAnyoneCanPay,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Input value x (checked by F1 > 100 and F2 < 200)
+    /// Input value x (checked by F1 and F2), below 2^31
     #[arg(short, long, default_value_t = 114)]
     x: u32,
 
+    /// Predicate F1 checks on x, e.g. `gt:100`, `within:100..200`, `bit:1`,
+    /// `mod:7=2` or several joined by `&`
+    #[arg(long, default_value_t = Predicate::GreaterThan(F1_THRESHOLD))]
+    f1: Predicate,
+
+    /// Predicate F2 checks on x, same syntax as --f1
+    #[arg(long, default_value_t = Predicate::LessThan(F2_THRESHOLD))]
+    f2: Predicate,
+
+    /// Flow set size parameter L (2^L presigned flows, at most 2^8 without
+    /// --many-flows)
+    #[arg(short = 'l', long, default_value_t = L_PARAM)]
+    l_param: usize,
+
+    /// Presign more than 2^8 flows, which can take hours and gigabytes of
+    /// memory with BLAKE3 steps
+    #[arg(long)]
+    many_flows: bool,
+
+    /// Hash prefix length B in bits (at most 128)
+    #[arg(short = 'b', long, default_value_t = B_PARAM)]
+    b_param: usize,
+
+    /// Collision hash H (sha256 scripts use OP_CAT, only valid on an
+    /// OP_CAT-enabled network such as Bitcoin Inquisition signet, see
+    /// --assume-op-cat)
+    #[arg(long, value_enum, default_value_t = HashArg::Blake3)]
+    hash: HashArg,
+
+    /// Confirm that the network enforces OP_CAT (BIP-347), required by
+    /// --hash sha256. Bitcoin Core treats OP_CAT as OP_SUCCESS: its sha256
+    /// leaves would be anyone-can-spend, or non-standard and not relayed
+    #[arg(long)]
+    assume_op_cat: bool,
+
+    /// Limb length in bits of the BLAKE3 message in script, 4 to 31 (only
+    /// for --hash blake3)
+    #[arg(long, default_value_t = DEFAULT_BLAKE3_LIMB_LEN)]
+    limb_len: u8,
+
+    /// Number of threads for the nonce search (default = all available cores)
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Print the script resources (size, stack depth, limits) of every
+    /// flow transaction
+    #[arg(long)]
+    analyze: bool,
+
+    /// Print a per-segment summary of every step spend and write it as JSON
+    /// to the output directory, naming the segment that rejects it if any
+    #[arg(long)]
+    trace: bool,
+
+    /// Also record the stacks after every opcode in the JSON traces, which
+    /// takes gigabytes for BLAKE3 steps
+    #[arg(long, requires = "trace")]
+    trace_stacks: bool,
+
+    /// Internal key of the flow outputs
+    #[arg(long, value_enum, default_value_t = InternalKeyArg::Signers)]
+    internal_key: InternalKeyArg,
+
+    /// Add a refund leaf to every flow output, letting the signers recover
+    /// the deposit this many blocks after a stalled step confirmed. The
+    /// presigned refund of the F1 output is written to `refund.tx`
+    #[arg(long)]
+    refund_after: Option<u16>,
+
+    /// Address the presigned refund pays, the funding address by default
+    #[arg(long, requires = "refund_after")]
+    refund_address: Option<String>,
+
+    /// Add a pay-to-anchor output to every flow transaction, so that its fee
+    /// can be raised with a CPFP child. Transactions small enough become v3
+    /// (TRUC)
+    #[arg(long)]
+    anchors: bool,
+
+    /// Sighash type the signers sign the last flow transaction with. The
+    /// ANYONECANPAY types let the operator add a fee input to it, the
+    /// earlier transactions always use the default type
+    #[arg(long, value_enum, default_value_t = SighashArg::Default)]
+    sighash: SighashArg,
+
+    /// Funding UTXO `txid:vout` paying to the signers' funding address, can
+    /// be repeated. Without it, the wallet deposits the required amount
+    #[arg(long = "funding-utxo")]
+    funding_utxos: Vec<OutPoint>,
+
     /// Dry run mode doesn't interact with Bitcoin network
     #[arg(long)]
     dry_run: bool,
@@ -90,6 +226,12 @@ struct Args {
     )]
     receiver: String,
 
+    /// Fixed output `address:sat` of the spending tx after the receiver's
+    /// withdrawal, e.g. an operator reward or a protocol fee. Can be
+    /// repeated
+    #[arg(long, value_parser = parse_payout)]
+    payout: Vec<(String, u64)>,
+
     /// Network name
     #[arg(short, long, default_value = "regtest")]
     network: String,
@@ -144,102 +286,260 @@ fn main() -> anyhow::Result<()> {
         );
     }
 
-    let funding_outpoint = get_funding_outpoint(
-        &rpc_client,
-        &secp,
-        network,
-        &inner_from(pk_signer),
-        REQUIRED_AMOUNT_SAT,
-    );
-
-    // In a production‑ready tool we would RPC‑query the node to retrieve the
-    // exact amount & pkScript of the funding UTXO.  To keep the demo
-    // self‑contained we *assume* the UTXO pays `REQUIRED_AMOUNT_SAT` to the
-    // Signer's P2WPKH address.  The instructions ensured the user sends that.
-    let funding_value_sat = REQUIRED_AMOUNT_SAT;
-
-    let (nonce, flow_id) = find_valid_nonce(args.x, B_PARAM, L_PARAM)
-        .expect("nonce search should succeed quickly");
+    let receiver_addr =
+        Address::from_str(&args.receiver)?.require_network(network)?;
 
-    println!(
-        "Found nonce r = {nonce} selecting flow d = {flow_id} (B={B_PARAM} bits, L={L_PARAM})"
+    let (l_param, b_param) = (args.l_param, args.b_param);
+    validate_params(b_param, l_param).map_err(anyhow::Error::msg)?;
+    anyhow::ensure!(
+        l_param <= MAX_DEMO_L_PARAM || args.many_flows,
+        "L={l_param} presigns 2^{l_param} flows, pass --many-flows to \
+         presign more than 2^{MAX_DEMO_L_PARAM}"
     );
 
-    let flow_id_prefix = flow_id_to_prefix_bytes(flow_id, B_PARAM);
-
-    let (
-        mut f1_tx,
-        f1_lock,
-        f1_spend_info,
-        funding_script,
-        funding_spend_info,
-        message,
-    ) = create_f1_tx(
-        B_PARAM,
+    let builder = FlowBuilder::new(b_param, network, args.fee_rate)
+        .step(args.f1.clone())
+        .step(args.f2.clone());
+    let internal_key = match args.internal_key {
+        InternalKeyArg::Signers => InternalKey::Signers,
+        InternalKeyArg::Nums => InternalKey::Nums,
+        InternalKeyArg::RandomNums => InternalKey::random_nums(),
+    };
+    let sighash_type = match args.sighash {
+        SighashArg::Default => TapSighashType::Default,
+        SighashArg::AllAnyoneCanPay => TapSighashType::AllPlusAnyoneCanPay,
+        SighashArg::SingleAnyoneCanPay => {
+            TapSighashType::SinglePlusAnyoneCanPay
+        }
+    };
+    let mut payout = vec![PayoutOutput::Receiver];
+    for (address, sat) in &args.payout {
+        payout.push(PayoutOutput::Fixed(TxOut {
+            value: bitcoin::Amount::from_sat(*sat),
+            script_pubkey: Address::from_str(address)?
+                .require_network(network)?
+                .script_pubkey(),
+        }));
+    }
+    let builder = builder
+        .internal_key(internal_key)
+        .anchors(args.anchors)
+        .sighash_type(sighash_type)
+        .payout(PayoutTemplate::new(payout).map_err(anyhow::Error::msg)?);
+    let builder = match args.refund_after {
+        Some(blocks) => builder.refund(
+            RefundLeaf::new(
+                XOnlyPublicKey::from(inner_from::<_, PublicKey>(pk_signer)),
+                blocks,
+            )
+            .map_err(anyhow::Error::msg)?,
+        ),
+        None => builder,
+    };
+    let builder = match args.hash {
+        HashArg::Blake3 => builder.hash(
+            Blake3Hash::with_limb_len(args.limb_len)
+                .map_err(anyhow::Error::msg)?,
+        ),
+        _ if args.limb_len != DEFAULT_BLAKE3_LIMB_LEN => {
+            anyhow::bail!("--limb-len only applies to --hash blake3")
+        }
+        HashArg::Blake3Compact => builder.hash(Blake3CompactHash),
+        HashArg::Sha256 => {
+            anyhow::ensure!(
+                args.assume_op_cat,
+                "--hash sha256 needs OP_CAT, which {network} does not \
+                 enforce on Bitcoin Core: pass --assume-op-cat if the node \
+                 runs an OP_CAT-enabled fork such as Bitcoin Inquisition"
+            );
+            anyhow::ensure!(
+                b_param % 8 == 0,
+                "sha256 flows need B={b_param} to be a multiple of 8"
+            );
+            builder.hash(Sha256Hash)
+        }
+    };
+    let flow_set = LowRange::new(b_param, l_param);
+
+    // The fee chain only depends on the number of funding UTXOs, not on
+    // their amounts
+    let funding_address =
+        funding_address(&secp, &inner_from(pk_signer), network);
+    let first_flow_id = flow_set.flow_ids().next().unwrap();
+    let required_sat = builder.required_funding(
         &secp,
         &inner_from(pk_signer),
-        &network,
-        &funding_outpoint,
-        &funding_value_sat,
-        &flow_id_prefix,
-        &args.fee_rate,
+        args.funding_utxos.len().max(1),
+        &flow_set
+            .prefix_nibbles(first_flow_id)
+            .map_err(anyhow::Error::msg)?,
+        &receiver_addr,
     )?;
-    let final_signature = simulate_musig2(&sk_signers, &message)?;
-    finalize_f1_tx(
-        &mut f1_tx,
-        final_signature,
-        &funding_spend_info,
-        &funding_script,
+    let funding_outpoints = if args.funding_utxos.is_empty() {
+        vec![deposit_funding(
+            &rpc_client,
+            &funding_address,
+            required_sat,
+        )?]
+    } else {
+        args.funding_utxos.clone()
+    };
+    let funding = lookup_funding_utxos(&rpc_client, &funding_outpoints)?;
+    let funding_sat = funding
+        .iter()
+        .map(|utxo| utxo.prevout.value.to_sat())
+        .sum::<u64>();
+    anyhow::ensure!(
+        funding_sat >= required_sat,
+        "the funding UTXOs hold {funding_sat} sat, deposit at least \
+         {required_sat} sat to {funding_address}"
+    );
+    println!(
+        "Funding: {funding_sat} sat in {} UTXO(s), the flows need \
+         {required_sat} sat",
+        funding.len()
     );
 
-    let f1_output_value = f1_tx.output[0].value.to_sat();
-    let (mut f2_tx, f2_lock, f2_spend_info, message) = create_f2_tx(
-        B_PARAM,
+    // Offline phase: the signers presign every flow d ∈ D before x is known
+    println!(
+        "Presigning {} flows (B={b_param} bits, L={l_param}, H={})...",
+        1u128 << l_param,
+        builder.collision_hash().name()
+    );
+    let flows = offline_setup(
+        &builder,
         &secp,
-        &inner_from(pk_signer),
-        &network,
-        &f1_tx,
-        &f1_output_value,
-        &f1_lock,
-        &flow_id_prefix,
-        &args.fee_rate,
-    )?;
-    let final_signature = simulate_musig2(&sk_signers, &message)?;
-    finalize_lock_tx(
-        &mut f2_tx,
-        final_signature,
-        &f1_spend_info,
-        &f1_lock,
-        &args.x,
-        &nonce,
+        &sk_signers,
+        &flow_set,
+        &funding,
+        &receiver_addr,
     )?;
+    match internal_key {
+        InternalKey::Signers => {}
+        InternalKey::Nums => {
+            for presigned in flows.values() {
+                presigned.flow.verify_no_key_path(&secp, None)?;
+            }
+            println!("Flow outputs keyed by the NUMS point, no key path");
+        }
+        InternalKey::TweakedNums(tweak) => {
+            for presigned in flows.values() {
+                presigned.flow.verify_no_key_path(&secp, Some(&tweak))?;
+            }
+            println!(
+                "Flow outputs keyed by NUMS + r·G, no key path (r = {})",
+                hex::encode(tweak.to_be_bytes())
+            );
+        }
+    }
 
-    let receiver_addr =
-        Address::from_str(&args.receiver)?.require_network(network)?;
+    // Online phase: the operator checks f(x) off-chain, then only searches a
+    // nonce and picks the flow
+    let x = Input::try_from(args.x).map_err(anyhow::Error::msg)?;
+    let predicates = [("F1", &args.f1), ("F2", &args.f2)];
+    for ((name, predicate), holds) in
+        predicates.into_iter().zip(builder.evaluate_steps(&x))
+    {
+        anyhow::ensure!(
+            holds,
+            "x = {} does not satisfy {name} = {predicate}, the flow would be \
+             rejected on-chain",
+            args.x
+        );
+    }
+    let num_threads = args.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    });
+    let (nonce, flow_id, txs) =
+        online_execution(&flows, &x, &flow_set, num_threads)?;
 
-    let f2_output_value = f2_tx.output[0].value.to_sat();
-    let (mut spending_tx, message) = create_spending_tx(
-        &f2_tx,
-        &f2_output_value,
-        &receiver_addr,
-        &f2_lock,
-        &args.fee_rate,
-    )?;
-    let final_signature = simulate_musig2(&sk_signers, &message)?;
-    finalize_lock_tx(
-        &mut spending_tx,
-        final_signature,
-        &f2_spend_info,
-        &f2_lock,
-        &args.x,
-        &nonce,
-    )?;
+    println!(
+        "Found nonce r = {nonce} selecting flow d = {flow_id} (B={b_param} bits, L={l_param})"
+    );
+
+    let [f1_tx, f2_tx, spending_tx]: [Transaction; 3] =
+        txs.try_into().map_err(|txs: Vec<Transaction>| {
+            anyhow::anyhow!("expected 3 flow transactions, got {}", txs.len())
+        })?;
+
+    let flow = flows[&flow_id].finalized(&x, &nonce)?;
+    if args.analyze {
+        let funding = &flow.txs[0];
+        let report = analyze_spend(&funding.tx, 0, &funding.prevouts)
+            .map_err(anyhow::Error::msg)?;
+        println!("Script spent by the f1 tx:\n{report}\n");
+        for (step, name) in ["f2", "spending"].into_iter().enumerate() {
+            let report = flow.analyze(step)?;
+            println!("Script spent by the {name} tx:\n{report}\n");
+        }
+    }
+
+    if args.trace {
+        std::fs::create_dir_all(&args.output_dir)?;
+        let detail = if args.trace_stacks {
+            TraceDetail::Opcodes
+        } else {
+            TraceDetail::Segments
+        };
+        for step in 0..flow.locks.len() {
+            let trace = flow.trace(step, detail)?;
+            let path =
+                format!("{}/step{}.trace.json", args.output_dir, step + 1);
+            std::fs::write(&path, trace.to_json()?)?;
+            println!("Step {}:", step + 1);
+            for summary in &trace.segments {
+                println!(
+                    "  {:<28} {:>6} ops, max depth {:>4}, top [{}]",
+                    summary
+                        .segment
+                        .map_or("outside segments".to_string(), |s| s
+                            .to_string()),
+                    summary.instructions,
+                    summary.max_depth,
+                    summary.stack_top.join(" ")
+                );
+            }
+            match &trace.failure {
+                None => println!("Step {} succeeds, trace in {path}", step + 1),
+                Some(failure) => println!(
+                    "Step {} fails in {} ({}), trace in {path}",
+                    step + 1,
+                    failure
+                        .segment
+                        .map_or("final checks".to_string(), |s| s.to_string()),
+                    failure.reason
+                ),
+            }
+        }
+    }
 
     let f1_tx_path = write_transaction_to_file(&f1_tx, &args.output_dir, "f1")?;
     let f2_tx_path = write_transaction_to_file(&f2_tx, &args.output_dir, "f2")?;
     let spending_tx_path =
         write_transaction_to_file(&spending_tx, &args.output_dir, "spending")?;
 
+    if let Some(blocks) = args.refund_after {
+        // Signed together with the flow, before the deposit is at stake
+        let flow = &flows[&flow_id].flow;
+        let refund_addr = match &args.refund_address {
+            Some(address) => {
+                Address::from_str(address)?.require_network(network)?
+            }
+            None => funding_address.clone(),
+        };
+        let (mut refund_tx, sighash) =
+            flow.refund_tx(0, &refund_addr, &args.fee_rate)?;
+        let sig = simulate_musig2(&sk_signers, &sighash)?;
+        let sig = Signature::from_slice(&sig.serialize())?;
+        flow.finalize_refund(0, &mut refund_tx, &sig)?;
+        let path =
+            write_transaction_to_file(&refund_tx, &args.output_dir, "refund")?;
+        println!(
+            "Refund of the F1 output to {refund_addr}, valid {blocks} blocks \
+             after f1 confirmed: {path}"
+        );
+    }
+
     let signers = sk_signers
         .iter()
         .map(|key| KeyPair {
@@ -266,9 +566,9 @@ fn main() -> anyhow::Result<()> {
         }),
         input_x: args.x,
         parameters: DemoParameters {
-            required_amount_sat: REQUIRED_AMOUNT_SAT,
-            l_param: L_PARAM,
-            b_param: B_PARAM,
+            required_amount_sat: required_sat,
+            l_param,
+            b_param,
         },
     };
 
@@ -279,9 +579,15 @@ fn main() -> anyhow::Result<()> {
     )?;
 
     if !args.dry_run {
-        println!("▶️  Waiting for founding tx: {}", funding_outpoint.txid);
-        wait_for_confirmation(&rpc_client, &funding_outpoint.txid, 1, timeout)
-            .unwrap();
+        for utxo in &funding {
+            println!("▶️  Waiting for funding tx: {}", utxo.outpoint.txid);
+            wait_for_confirmation(
+                &rpc_client,
+                &utxo.outpoint.txid,
+                1,
+                timeout,
+            )?;
+        }
 
         println!("▶️  Pushed f1: {}", f1_tx.compute_txid());
         let f1_txid = rpc_client.send_raw_transaction(&f1_tx)?;
@@ -299,60 +605,75 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-/// create a funding taproot address, and demo the spending tx with musig2
-fn create_funding_taproot_address(
-    pubkey: &PublicKey,
-    secp: &Secp256k1<secp256k1::All>,
-    network: Network,
-) -> Address {
-    let xonly_pk = XOnlyPublicKey::from(*pubkey);
-    let leaf_script =
-        collidervm_toy::transactions::get_funding_script(&xonly_pk);
-
-    let spend_info = TaprootBuilder::new()
-        .add_leaf(0, leaf_script.clone())
-        .unwrap()
-        .finalize(secp, xonly_pk)
-        .unwrap();
-
-    // The scriptPubKey for this Taproot output and the address (for funding):
-    Address::p2tr_tweaked(spend_info.output_key(), network)
+/// Parse a `--payout` output `address:sat`.
+fn parse_payout(s: &str) -> Result<(String, u64), String> {
+    let (address, sat) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected address:sat, got {s}"))?;
+    let sat = sat
+        .parse()
+        .map_err(|err| format!("invalid amount {sat}: {err}"))?;
+    Ok((address.to_string(), sat))
 }
 
-fn get_funding_outpoint(
+/// Send `amount_sat` from the wallet to `funding_address`, returning the
+/// funding outpoint.
+fn deposit_funding(
     rpc_client: &Client,
-    secp: &Secp256k1<secp256k1::All>,
-    network: Network,
-    signer_pubkey: &PublicKey,
-    required_amount_sat: u64,
-) -> OutPoint {
-    let funding_address =
-        create_funding_taproot_address(signer_pubkey, secp, network);
-    let txid = rpc_client
-        .send_to_address(
-            &funding_address,
-            bitcoin::Amount::from_sat(required_amount_sat),
-            None,
-            None,
-            None,
-            None,
-            None,
-            None,
-        )
-        .map_err(|err| panic!("Error: {err}"))
-        .unwrap();
-
-    let confirmed_funding_tx =
-        rpc_client.get_raw_transaction(&txid, None).unwrap();
-
-    println!("▶️  Pushed founding tx: {txid}");
-
-    let tx_out_sp_0 = &confirmed_funding_tx.output[0].script_pubkey;
-    let vout = if *tx_out_sp_0 == funding_address.script_pubkey() {
-        0
-    } else {
-        1
-    };
+    funding_address: &Address,
+    amount_sat: u64,
+) -> anyhow::Result<OutPoint> {
+    let txid = rpc_client.send_to_address(
+        funding_address,
+        bitcoin::Amount::from_sat(amount_sat),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    println!("▶️  Pushed funding tx: {txid}");
 
-    OutPoint { txid, vout }
+    let funding_tx = rpc_client.get_raw_transaction(&txid, None)?;
+    let vout = funding_tx
+        .output
+        .iter()
+        .position(|output| {
+            output.script_pubkey == funding_address.script_pubkey()
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!("funding tx {txid} does not pay {funding_address}")
+        })?;
+    Ok(OutPoint {
+        txid,
+        vout: vout as u32,
+    })
+}
+
+/// Amount and scriptPubKey of every funding outpoint, which must be unspent
+/// (possibly still in the mempool).
+fn lookup_funding_utxos(
+    rpc_client: &Client,
+    outpoints: &[OutPoint],
+) -> anyhow::Result<Vec<FundingUtxo>> {
+    outpoints
+        .iter()
+        .map(|outpoint| {
+            let tx_out = rpc_client
+                .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "funding UTXO {outpoint} is unknown or already spent"
+                    )
+                })?;
+            Ok(FundingUtxo {
+                outpoint: *outpoint,
+                prevout: TxOut {
+                    value: tx_out.value,
+                    script_pubkey: ScriptBuf::from(tx_out.script_pub_key.hex),
+                },
+            })
+        })
+        .collect()
 }
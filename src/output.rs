@@ -21,7 +21,7 @@ pub struct TransactionInfo {
     pub f2: TxInfo,
     pub spending: TxInfo,
     pub nonce: u64,
-    pub flow_id: u32,
+    pub flow_id: u128,
 }
 
 #[derive(Serialize)]
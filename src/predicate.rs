@@ -0,0 +1,354 @@
+//! A small library of predicates on `x`, compiled to Bitcoin Script.
+//!
+//! Every [`Predicate`] is a [`SubFunction`]: its script consumes the number
+//! on top of the stack and aborts unless the predicate holds, and
+//! [`SubFunction::evaluate`] mirrors it off-chain so that an operator can
+//! check `f_i(x)` before searching a nonce.
+//!
+//! Script numbers are at most 4 bytes, so `x` and every constant must be
+//! below `2^31`. Bitwise and division opcodes are disabled, bit tests and
+//! modular checks are unrolled into conditional subtractions.
+//!
+//! Predicates also parse from a short text form, e.g.
+//! `gt:100&lt:200&mod:7=3` (see [`Predicate::from_str`]).
+
+use crate::core::SubFunction;
+use bitcoin::blockdata::script::{Builder, ScriptBuf};
+use bitcoin::opcodes;
+use std::fmt;
+use std::str::FromStr;
+
+/// Largest value of a 4-byte script number.
+const MAX_SCRIPT_NUM: u32 = i32::MAX as u32;
+
+/// A predicate on a number field of `x`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// `x > n`
+    GreaterThan(u32),
+    /// `x < n`
+    LessThan(u32),
+    /// `min <= x < max`, as `OP_WITHIN`
+    Within { min: u32, max: u32 },
+    /// `x == n`
+    Equal(u32),
+    /// `x != n`
+    NotEqual(u32),
+    /// Bit `i` of `x` is set
+    BitSet(u8),
+    /// Bit `i` of `x` is clear
+    BitClear(u8),
+    /// `x mod modulus == remainder`, `modulus` must be non-zero
+    Mod { modulus: u32, remainder: u32 },
+    /// All of the predicates hold
+    And(Vec<Predicate>),
+}
+
+impl Predicate {
+    /// Conjunction of `self` and `other`, flattening nested conjunctions.
+    pub fn and(self, other: Predicate) -> Predicate {
+        let mut all = match self {
+            Predicate::And(all) => all,
+            predicate => vec![predicate],
+        };
+        match other {
+            Predicate::And(more) => all.extend(more),
+            predicate => all.push(predicate),
+        }
+        Predicate::And(all)
+    }
+
+    /// Append the script of the predicate: consumes `x`, aborts unless the
+    /// predicate holds.
+    fn push_verify(&self, b: Builder) -> Builder {
+        match self {
+            Predicate::GreaterThan(n) => push_num(b, *n)
+                .push_opcode(opcodes::all::OP_GREATERTHAN)
+                .push_opcode(opcodes::all::OP_VERIFY),
+            Predicate::LessThan(n) => push_num(b, *n)
+                .push_opcode(opcodes::all::OP_LESSTHAN)
+                .push_opcode(opcodes::all::OP_VERIFY),
+            Predicate::Within { min, max } => push_num(push_num(b, *min), *max)
+                .push_opcode(opcodes::all::OP_WITHIN)
+                .push_opcode(opcodes::all::OP_VERIFY),
+            Predicate::Equal(n) => {
+                push_num(b, *n).push_opcode(opcodes::all::OP_NUMEQUALVERIFY)
+            }
+            Predicate::NotEqual(n) => push_num(b, *n)
+                .push_opcode(opcodes::all::OP_NUMNOTEQUAL)
+                .push_opcode(opcodes::all::OP_VERIFY),
+            Predicate::BitSet(i) | Predicate::BitClear(i) => {
+                assert!(*i < 31, "bit index {i} out of a 31-bit script number");
+                // Clear the bits above i, then compare against 2^i
+                let mut b = b;
+                for bit in (*i + 1..31).rev() {
+                    b = push_sub_if_at_least(b, 1 << bit);
+                }
+                let opcode = match self {
+                    Predicate::BitSet(_) => opcodes::all::OP_GREATERTHANOREQUAL,
+                    _ => opcodes::all::OP_LESSTHAN,
+                };
+                push_num(b, 1 << i)
+                    .push_opcode(opcode)
+                    .push_opcode(opcodes::all::OP_VERIFY)
+            }
+            Predicate::Mod { modulus, remainder } => {
+                assert!(*modulus > 0, "modulus must be non-zero");
+                // Binary long division: subtract modulus * 2^j while it fits
+                let mut b = b;
+                let mut multiples = vec![];
+                let mut multiple = *modulus;
+                while multiple <= MAX_SCRIPT_NUM {
+                    multiples.push(multiple);
+                    multiple = match multiple.checked_mul(2) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                }
+                for multiple in multiples.into_iter().rev() {
+                    b = push_sub_if_at_least(b, multiple);
+                }
+                push_num(b, *remainder)
+                    .push_opcode(opcodes::all::OP_NUMEQUALVERIFY)
+            }
+            Predicate::And(all) => {
+                let mut b = b;
+                for (i, predicate) in all.iter().enumerate() {
+                    // Every predicate but the last works on a copy of x
+                    if i + 1 < all.len() {
+                        b = b.push_opcode(opcodes::all::OP_DUP);
+                    }
+                    b = predicate.push_verify(b);
+                }
+                if all.is_empty() {
+                    b = b.push_opcode(opcodes::all::OP_DROP);
+                }
+                b
+            }
+        }
+    }
+}
+
+fn push_num(b: Builder, n: u32) -> Builder {
+    assert!(
+        n <= MAX_SCRIPT_NUM,
+        "constant {n} is not a 4-byte script number"
+    );
+    b.push_int(n as i64)
+}
+
+/// `if x >= n { x -= n }` on the number on top of the stack.
+fn push_sub_if_at_least(b: Builder, n: u32) -> Builder {
+    push_num(b.push_opcode(opcodes::all::OP_DUP), n)
+        .push_opcode(opcodes::all::OP_GREATERTHANOREQUAL)
+        .push_opcode(opcodes::all::OP_IF)
+        .push_int(n as i64)
+        .push_opcode(opcodes::all::OP_SUB)
+        .push_opcode(opcodes::all::OP_ENDIF)
+}
+
+impl SubFunction for Predicate {
+    fn script(&self) -> ScriptBuf {
+        self.push_verify(Builder::new()).into_script()
+    }
+
+    fn evaluate(&self, x: u32) -> bool {
+        match self {
+            Predicate::GreaterThan(n) => x > *n,
+            Predicate::LessThan(n) => x < *n,
+            Predicate::Within { min, max } => (*min..*max).contains(&x),
+            Predicate::Equal(n) => x == *n,
+            Predicate::NotEqual(n) => x != *n,
+            Predicate::BitSet(i) => x >> i & 1 == 1,
+            Predicate::BitClear(i) => x >> i & 1 == 0,
+            Predicate::Mod { modulus, remainder } => {
+                x.checked_rem(*modulus) == Some(*remainder)
+            }
+            Predicate::And(all) => all.iter().all(|p| p.evaluate(x)),
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Predicate::GreaterThan(n) => write!(f, "gt:{n}"),
+            Predicate::LessThan(n) => write!(f, "lt:{n}"),
+            Predicate::Within { min, max } => write!(f, "within:{min}..{max}"),
+            Predicate::Equal(n) => write!(f, "eq:{n}"),
+            Predicate::NotEqual(n) => write!(f, "ne:{n}"),
+            Predicate::BitSet(i) => write!(f, "bit:{i}"),
+            Predicate::BitClear(i) => write!(f, "nobit:{i}"),
+            Predicate::Mod { modulus, remainder } => {
+                write!(f, "mod:{modulus}={remainder}")
+            }
+            Predicate::And(all) => {
+                let all = all.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", all.join("&"))
+            }
+        }
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = String;
+
+    /// Parses `op:args` terms joined by `&`: `gt:N`, `lt:N`,
+    /// `within:MIN..MAX`, `eq:N`, `ne:N`, `bit:I`, `nobit:I` and
+    /// `mod:M=R`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('&') {
+            return s
+                .split('&')
+                .map(str::parse)
+                .collect::<Result<Vec<_>, _>>()
+                .map(Predicate::And);
+        }
+
+        let (op, args) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected op:args, got {s:?}"))?;
+        let num = |arg: &str| -> Result<u32, String> {
+            let n = arg
+                .trim()
+                .parse::<u32>()
+                .map_err(|err| format!("invalid number {arg:?}: {err}"))?;
+            if n > MAX_SCRIPT_NUM {
+                return Err(format!("{n} is not a 4-byte script number"));
+            }
+            Ok(n)
+        };
+        let bit = |arg: &str| -> Result<u8, String> {
+            match num(arg)? {
+                i @ 0..31 => Ok(i as u8),
+                i => Err(format!("bit index {i} must be below 31")),
+            }
+        };
+        let pair = |arg: &str, sep: &str| -> Result<(u32, u32), String> {
+            let (a, b) = arg
+                .split_once(sep)
+                .ok_or_else(|| format!("expected A{sep}B, got {arg:?}"))?;
+            Ok((num(a)?, num(b)?))
+        };
+
+        match op.trim() {
+            "gt" => Ok(Predicate::GreaterThan(num(args)?)),
+            "lt" => Ok(Predicate::LessThan(num(args)?)),
+            "within" => {
+                let (min, max) = pair(args, "..")?;
+                Ok(Predicate::Within { min, max })
+            }
+            "eq" => Ok(Predicate::Equal(num(args)?)),
+            "ne" => Ok(Predicate::NotEqual(num(args)?)),
+            "bit" => Ok(Predicate::BitSet(bit(args)?)),
+            "nobit" => Ok(Predicate::BitClear(bit(args)?)),
+            "mod" => {
+                let (modulus, remainder) = pair(args, "=")?;
+                if modulus == 0 {
+                    return Err("modulus must be non-zero".to_string());
+                }
+                Ok(Predicate::Mod { modulus, remainder })
+            }
+            op => Err(format!("unknown predicate {op:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvm::execute_script_buf;
+
+    /// Runs `<x> <predicate script> OP_TRUE`.
+    fn run(predicate: &Predicate, x: u32) -> bool {
+        let mut script =
+            Builder::new().push_int(x as i64).into_script().to_bytes();
+        script.extend(predicate.script().to_bytes());
+        script.push(opcodes::OP_TRUE.to_u8());
+        execute_script_buf(ScriptBuf::from_bytes(script)).success
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        for text in [
+            "gt:100",
+            "lt:200",
+            "within:100..200",
+            "eq:114",
+            "ne:0",
+            "bit:3",
+            "nobit:30",
+            "mod:7=2",
+            "gt:100&lt:200&mod:2=0",
+        ] {
+            let predicate = text.parse::<Predicate>().unwrap();
+            assert_eq!(predicate.to_string(), text);
+        }
+        assert_eq!(
+            "gt:1&lt:9".parse::<Predicate>().unwrap(),
+            Predicate::GreaterThan(1).and(Predicate::LessThan(9))
+        );
+        for text in
+            ["gt", "gt:x", "bit:31", "mod:0=0", "foo:1", "eq:4294967295"]
+        {
+            assert!(text.parse::<Predicate>().is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let p = |text: &str| text.parse::<Predicate>().unwrap();
+        assert!(p("within:100..200").evaluate(100));
+        assert!(!p("within:100..200").evaluate(200));
+        assert!(p("bit:1").evaluate(0b10));
+        assert!(!p("bit:1").evaluate(0b101));
+        assert!(p("nobit:1").evaluate(0b101));
+        assert!(p("mod:7=2").evaluate(114));
+        assert!(!p("mod:7=3").evaluate(7));
+        assert!(p("gt:100&lt:200").evaluate(114));
+        assert!(!p("gt:100&lt:200").evaluate(214));
+    }
+
+    #[test]
+    fn test_script_mirrors_evaluate() {
+        let predicates = [
+            "gt:100",
+            "lt:200",
+            "within:100..200",
+            "eq:114",
+            "ne:114",
+            "bit:0",
+            "bit:4",
+            "nobit:6",
+            "bit:30",
+            "mod:7=2",
+            "mod:1=0",
+            "mod:1000000=114",
+            "gt:100&lt:200&bit:1",
+        ]
+        .map(|text| text.parse::<Predicate>().unwrap());
+        let inputs = [
+            0,
+            1,
+            2,
+            100,
+            101,
+            114,
+            115,
+            199,
+            200,
+            1 << 30,
+            i32::MAX as u32,
+        ];
+        for predicate in &predicates {
+            for x in inputs {
+                assert_eq!(
+                    run(predicate, x),
+                    predicate.evaluate(x),
+                    "{predicate} on {x}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,409 @@
+//! Traces of step locking scripts, segment by segment.
+//!
+//! A [`Trace`] summarizes the execution of every [`SegmentKind`] of a lock
+//! (instructions run, stack depth, top of the stack after it), so a rejected
+//! spend points at the part of the script that failed (signature check,
+//! reconstruction of `x`, a sub-function, the hash, ...). The stack and
+//! altstack after every instruction are only recorded with
+//! [`TraceDetail::Opcodes`]: a BLAKE3 step runs tens of thousands of
+//! instructions on hundreds of elements. The segments come from
+//! [`crate::core::build_script_step_segments`], the trace can be exported as
+//! JSON with [`Trace::to_json`].
+
+use crate::core::{ScriptSegment, SegmentKind};
+use crate::interpreter::{ExecError, Interpreter, tapscript_spend};
+use bitcoin::script::Script;
+use bitcoin::{Transaction, TxOut};
+use serde::Serialize;
+
+/// Number of top stack elements kept by a [`SegmentSummary`].
+pub const SUMMARY_TOP: usize = 4;
+
+/// What a [`Trace`] records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceDetail {
+    /// A [`SegmentSummary`] per segment.
+    #[default]
+    Segments,
+    /// The summaries and a [`TraceStep`] per instruction.
+    Opcodes,
+}
+
+/// The state after one instruction.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStep {
+    /// Byte offset of the instruction in the script.
+    pub offset: usize,
+    pub op: String,
+    pub segment: Option<SegmentKind>,
+    /// False inside a branch not taken.
+    pub executed: bool,
+    /// Hex elements, top last.
+    pub stack: Vec<String>,
+    /// Hex elements, top last.
+    pub altstack: Vec<String>,
+}
+
+/// The execution of consecutive instructions of the same segment.
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentSummary {
+    /// `None` for instructions outside every segment.
+    pub segment: Option<SegmentKind>,
+    /// Instructions run, a failing one included.
+    pub instructions: usize,
+    /// Most elements on the stack and altstack together.
+    pub max_depth: usize,
+    /// Elements on the stack after the segment.
+    pub stack_depth: usize,
+    /// Elements on the altstack after the segment.
+    pub altstack_depth: usize,
+    /// The [`SUMMARY_TOP`] topmost hex elements after the segment, top last.
+    pub stack_top: Vec<String>,
+}
+
+/// Where and why the script was rejected.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceFailure {
+    pub offset: usize,
+    pub reason: String,
+    /// `None` for the final checks after the last instruction.
+    pub segment: Option<SegmentKind>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trace {
+    /// The initial stack, hex elements, top last.
+    pub witness: Vec<String>,
+    /// In execution order.
+    pub segments: Vec<SegmentSummary>,
+    /// Empty unless traced with [`TraceDetail::Opcodes`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<TraceStep>,
+    pub failure: Option<TraceFailure>,
+}
+
+impl Trace {
+    pub fn success(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// The segment that rejected the input, if any.
+    pub fn failed_segment(&self) -> Option<SegmentKind> {
+        self.failure.as_ref().and_then(|failure| failure.segment)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+fn to_hex(elements: &[Vec<u8>]) -> Vec<String> {
+    elements.iter().map(hex::encode).collect()
+}
+
+fn segment_at(
+    segments: &[ScriptSegment],
+    offset: usize,
+) -> Option<SegmentKind> {
+    segments
+        .iter()
+        .find(|segment| segment.range.contains(&offset))
+        .map(|segment| segment.kind)
+}
+
+/// Executes `script` on `witness` (top last), labeling every instruction
+/// with the segment covering it. Signatures are only checked for their
+/// size, see [`trace_spend`].
+pub fn trace_script(
+    script: &Script,
+    witness: &[Vec<u8>],
+    segments: &[ScriptSegment],
+    detail: TraceDetail,
+) -> Trace {
+    let interpreter = Interpreter::new(script, witness.to_vec());
+    trace_interpreter(interpreter, witness, segments, detail)
+}
+
+/// Traces the tapscript spent by input `input` of `tx`, verifying its
+/// signatures against `prevouts`, the outputs spent by `tx`.
+pub fn trace_spend(
+    tx: &Transaction,
+    input: usize,
+    prevouts: &[TxOut],
+    segments: &[ScriptSegment],
+    detail: TraceDetail,
+) -> Result<Trace, String> {
+    let (script, witness) = tapscript_spend(tx, input)?;
+    let interpreter = Interpreter::new(&script, witness.clone())
+        .map(|interpreter| interpreter.with_spend(tx, input, prevouts));
+    Ok(trace_interpreter(interpreter, &witness, segments, detail))
+}
+
+fn trace_interpreter(
+    interpreter: Result<Interpreter, ExecError>,
+    witness: &[Vec<u8>],
+    segments: &[ScriptSegment],
+    detail: TraceDetail,
+) -> Trace {
+    let mut trace = Trace {
+        witness: to_hex(witness),
+        segments: vec![],
+        steps: vec![],
+        failure: None,
+    };
+    let result = interpreter.and_then(|mut interpreter| {
+        while !interpreter.is_done() {
+            let executed = interpreter.is_executing();
+            let (offset, op) =
+                interpreter.ops()[interpreter.position()].clone();
+            let segment = segment_at(segments, offset);
+            if trace
+                .segments
+                .last()
+                .is_none_or(|last| last.segment != segment)
+            {
+                let depth =
+                    interpreter.stack().len() + interpreter.altstack().len();
+                trace.segments.push(SegmentSummary {
+                    segment,
+                    instructions: 0,
+                    max_depth: depth,
+                    stack_depth: interpreter.stack().len(),
+                    altstack_depth: interpreter.altstack().len(),
+                    stack_top: vec![],
+                });
+            }
+            let summary = trace.segments.last_mut().unwrap();
+            summary.instructions += 1;
+            interpreter.step()?;
+
+            let (stack, altstack) =
+                (interpreter.stack(), interpreter.altstack());
+            summary.max_depth =
+                summary.max_depth.max(stack.len() + altstack.len());
+            summary.stack_depth = stack.len();
+            summary.altstack_depth = altstack.len();
+            summary.stack_top =
+                to_hex(&stack[stack.len().saturating_sub(SUMMARY_TOP)..]);
+            if detail == TraceDetail::Opcodes {
+                trace.steps.push(TraceStep {
+                    offset,
+                    op: op.to_string(),
+                    segment,
+                    executed,
+                    stack: to_hex(stack),
+                    altstack: to_hex(altstack),
+                });
+            }
+        }
+        interpreter.finish()
+    });
+    if let Err(err) = result {
+        trace.failure = Some(TraceFailure {
+            offset: err.offset,
+            segment: segment_at(segments, err.offset),
+            reason: err.reason,
+        });
+    }
+    trace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        GreaterThan, LessThan, SubFunction, build_script_step_segments,
+        flow_id_to_prefix_bytes, prefix_mask,
+    };
+    use crate::hash::{CollisionHash, Sha256Hash};
+    use crate::input::{Input, InputLayout};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::key::Keypair;
+    use bitcoin::secp256k1::{Message, Secp256k1};
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin::taproot::{LeafVersion, TapLeafHash, TaprootBuilder};
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, TapSighashType, TxIn, Witness};
+
+    const B: usize = 16;
+
+    /// A transaction spending a step lock checking `100 < x < 200`, and the
+    /// output it spends.
+    struct StepSpend {
+        lock: ScriptBuf,
+        segments: Vec<ScriptSegment>,
+        tx: Transaction,
+        prevout: TxOut,
+        control_block: Vec<u8>,
+        sig: Vec<u8>,
+    }
+
+    impl StepSpend {
+        fn new() -> Self {
+            let secp = Secp256k1::new();
+            let keypair = Keypair::from_seckey_slice(&secp, &[7; 32]).unwrap();
+            let pubkey = bitcoin::PublicKey::new(keypair.public_key());
+            let flow_id = Sha256Hash.flow_id(&Input::try_from(114).unwrap(), 0)
+                & prefix_mask(B);
+            let sub_functions: [&dyn SubFunction; 2] =
+                [&GreaterThan(100), &LessThan(200)];
+            let (lock, segments) = build_script_step_segments(
+                &pubkey,
+                &Sha256Hash,
+                &InputLayout::default(),
+                &sub_functions,
+                &flow_id_to_prefix_bytes(flow_id, B),
+                B,
+            );
+            assert_eq!(segments.last().unwrap().range.end, lock.len());
+
+            let internal_key = keypair.x_only_public_key().0;
+            let spend_info = TaprootBuilder::new()
+                .add_leaf(0, lock.clone())
+                .unwrap()
+                .finalize(&secp, internal_key)
+                .unwrap();
+            let prevout = TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: ScriptBuf::new_p2tr_tweaked(
+                    spend_info.output_key(),
+                ),
+            };
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    ..Default::default()
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(9_000),
+                    script_pubkey: ScriptBuf::new(),
+                }],
+            };
+            let sighash = SighashCache::new(&tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &Prevouts::All(std::slice::from_ref(&prevout)),
+                    TapLeafHash::from_script(&lock, LeafVersion::TapScript),
+                    TapSighashType::Default,
+                )
+                .unwrap();
+            let message = Message::from_digest(sighash.to_byte_array());
+            let sig = secp.sign_schnorr_no_aux_rand(&message, &keypair);
+            let control_block = spend_info
+                .control_block(&(lock.clone(), LeafVersion::TapScript))
+                .unwrap()
+                .serialize();
+            Self {
+                lock,
+                segments,
+                tx,
+                prevout,
+                control_block,
+                sig: sig.as_ref().to_vec(),
+            }
+        }
+
+        fn trace(
+            &mut self,
+            x: u32,
+            nonce: u64,
+            sig: Vec<u8>,
+            detail: TraceDetail,
+        ) -> Trace {
+            let mut witness =
+                Sha256Hash.witness(&Input::try_from(x).unwrap(), nonce, B);
+            witness.push(sig);
+            witness.push(self.lock.to_bytes());
+            witness.push(self.control_block.clone());
+            self.tx.input[0].witness = Witness::from_slice(&witness);
+            let prevouts = std::slice::from_ref(&self.prevout);
+            trace_spend(&self.tx, 0, prevouts, &self.segments, detail).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_trace_labels_failing_segment() {
+        let mut spend = StepSpend::new();
+        let sig = spend.sig.clone();
+        let trace = spend.trace(114, 0, sig.clone(), TraceDetail::Segments);
+        assert!(trace.success(), "{:?}", trace.failure);
+        assert!(trace.steps.is_empty());
+        let kinds = trace
+            .segments
+            .iter()
+            .map(|summary| summary.segment.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds.first(), Some(&SegmentKind::SignatureCheck));
+        assert!(kinds.contains(&SegmentKind::SubFunction(1)));
+        assert_eq!(kinds.last(), Some(&SegmentKind::Success));
+        let success = trace.segments.last().unwrap();
+        assert_eq!(success.stack_top, ["01"]);
+        assert_eq!((success.stack_depth, success.altstack_depth), (1, 0));
+        let instructions = spend.lock.instructions().count();
+        assert_eq!(
+            trace
+                .segments
+                .iter()
+                .map(|summary| summary.instructions)
+                .sum::<usize>(),
+            instructions
+        );
+
+        let trace = spend.trace(114, 0, vec![], TraceDetail::Segments);
+        assert_eq!(trace.failed_segment(), Some(SegmentKind::SignatureCheck));
+        let mut forged = sig.clone();
+        forged[63] ^= 1;
+        let trace = spend.trace(114, 0, forged, TraceDetail::Segments);
+        assert_eq!(trace.failed_segment(), Some(SegmentKind::SignatureCheck));
+        assert_eq!(trace.failure.unwrap().reason, "invalid signature");
+        let trace = spend.trace(99, 0, sig.clone(), TraceDetail::Segments);
+        assert_eq!(trace.failed_segment(), Some(SegmentKind::SubFunction(0)));
+        let trace = spend.trace(200, 0, sig.clone(), TraceDetail::Segments);
+        assert_eq!(trace.failed_segment(), Some(SegmentKind::SubFunction(1)));
+        let trace = spend.trace(114, 1, sig, TraceDetail::Segments);
+        assert_eq!(trace.failed_segment(), Some(SegmentKind::PrefixCompare));
+        assert_eq!(
+            trace.segments.last().unwrap().segment,
+            Some(SegmentKind::PrefixCompare)
+        );
+    }
+
+    #[test]
+    fn test_trace_json() {
+        let mut spend = StepSpend::new();
+        let sig = spend.sig.clone();
+        let trace = spend.trace(114, 1, sig.clone(), TraceDetail::Segments);
+        let json: serde_json::Value =
+            serde_json::from_str(&trace.to_json().unwrap()).unwrap();
+        assert_eq!(json["failure"]["segment"], "prefix_compare");
+        assert_eq!(json["segments"][0]["segment"], "signature_check");
+        assert_eq!(json["segments"][0]["instructions"], 2);
+        assert!(json.get("steps").is_none());
+
+        let trace = spend.trace(114, 0, sig, TraceDetail::Opcodes);
+        assert_eq!(trace.steps.len(), spend.lock.instructions().count());
+        let json: serde_json::Value =
+            serde_json::from_str(&trace.to_json().unwrap()).unwrap();
+        assert_eq!(json["steps"][0]["segment"], "signature_check");
+        assert_eq!(json["steps"][2]["segment"]["reconstruct_x"], 0);
+        assert_eq!(json["steps"][2]["op"], "OP_OVER");
+    }
+
+    #[test]
+    fn test_trace_script_without_spend() {
+        let spend = StepSpend::new();
+        let mut witness =
+            Sha256Hash.witness(&Input::try_from(114).unwrap(), 0, B);
+        witness.push(vec![1; 64]);
+        let trace = trace_script(
+            &spend.lock,
+            &witness,
+            &spend.segments,
+            TraceDetail::Segments,
+        );
+        assert!(trace.success(), "{:?}", trace.failure);
+    }
+}
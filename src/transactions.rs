@@ -1,11 +1,16 @@
 use crate::core::{
-    blake3_message_to_limbs, build_script_f1_blake3_locked,
+    SCHNORR_SIG_LEN, SignerCheck, build_script_f1_blake3_locked,
     build_script_f2_blake3_locked,
 };
-use crate::utils::{encode_scriptnum, estimate_fee_vbytes};
+use crate::hash::{Blake3Hash, CollisionHash};
+use crate::input::{Input, InputLayout};
+use crate::utils::estimate_fee_vbytes;
 use anyhow;
 use bitcoin::sighash::Prevouts;
-use bitcoin::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::taproot::{
+    LeafVersion, TAPROOT_CONTROL_BASE_SIZE, TAPROOT_CONTROL_NODE_SIZE,
+    TaprootBuilder, TaprootSpendInfo,
+};
 use bitcoin::transaction::Version;
 use bitcoin::{
     Address, Amount, Network, OutPoint, ScriptBuf, Sequence, TapLeafHash,
@@ -16,7 +21,261 @@ use bitcoin::{
     sighash::SighashCache,
 };
 use musig2::LiftedSignature;
-use secp256k1::{PublicKey, XOnlyPublicKey};
+use secp256k1::{Parity, PublicKey, Scalar, XOnlyPublicKey};
+
+/// Value of a pay-to-anchor output, the dust limit of its 4-byte script.
+pub const P2A_DUST_SAT: u64 = 240;
+/// Largest v3 (TRUC) transaction relayed by Bitcoin Core.
+pub const TRUC_MAX_VSIZE: usize = 10_000;
+/// Largest v3 (TRUC) transaction with an unconfirmed v3 parent.
+pub const TRUC_CHILD_MAX_VSIZE: usize = 1_000;
+
+/// Control block of the refund leaf, which sits next to the lock at depth 1.
+const REFUND_CONTROL_BLOCK_LEN: usize =
+    TAPROOT_CONTROL_BASE_SIZE + TAPROOT_CONTROL_NODE_SIZE;
+
+/// x coordinate of the BIP-341 NUMS point `H`, `lift_x(SHA256(G))` with `G`
+/// uncompressed: nobody knows its discrete logarithm.
+const NUMS_X: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60,
+    0x35, 0xe9, 0x7a, 0x5e, 0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5,
+    0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// Internal key of the Taproot outputs of a flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InternalKey {
+    /// The signers' aggregated key: they can also spend every output
+    /// through the key path, bypassing the flow leaves.
+    #[default]
+    Signers,
+    /// The NUMS point `H`, so outputs can only be spent through their
+    /// leaves. Recognisable by anyone, and so are the flow outputs.
+    Nums,
+    /// `H + r·G` for a random `r`, which hides the use of `H` until `r` is
+    /// revealed to prove the key unspendable, see
+    /// [`InternalKey::is_unspendable`].
+    TweakedNums(Scalar),
+}
+
+impl InternalKey {
+    /// [`InternalKey::TweakedNums`] with a fresh random tweak.
+    pub fn random_nums() -> Self {
+        let tweak = std::iter::repeat_with(rand::random::<[u8; 32]>)
+            .find_map(|bytes| Scalar::from_be_bytes(bytes).ok())
+            .expect("infinite iterator");
+        InternalKey::TweakedNums(tweak)
+    }
+
+    /// The x-only internal key, `pk_signer` being the signers' key.
+    pub fn x_only_public_key(
+        &self,
+        secp: &Secp256k1<secp256k1::All>,
+        pk_signer: &PublicKey,
+    ) -> XOnlyPublicKey {
+        match self {
+            InternalKey::Signers => XOnlyPublicKey::from(*pk_signer),
+            InternalKey::Nums => nums_key(secp, None),
+            InternalKey::TweakedNums(tweak) => nums_key(secp, Some(tweak)),
+        }
+    }
+
+    /// Checks that `key` is `H` (no `tweak`) or `H + tweak·G`, i.e. that
+    /// nobody can sign for it. Lets an auditor given the tweak rule out a
+    /// key-path spend of an output built with `key` as internal key.
+    pub fn is_unspendable(
+        secp: &Secp256k1<secp256k1::All>,
+        key: &XOnlyPublicKey,
+        tweak: Option<&Scalar>,
+    ) -> bool {
+        *key == nums_key(secp, tweak)
+    }
+}
+
+/// `H`, or `H + tweak·G`.
+fn nums_key(
+    secp: &Secp256k1<secp256k1::All>,
+    tweak: Option<&Scalar>,
+) -> XOnlyPublicKey {
+    let nums = XOnlyPublicKey::from_slice(&NUMS_X).expect("valid point");
+    match tweak {
+        None => nums,
+        Some(tweak) => {
+            nums.public_key(Parity::Even)
+                .add_exp_tweak(secp, tweak)
+                .expect("tweak of a NUMS point")
+                .x_only_public_key()
+                .0
+        }
+    }
+}
+
+/// A CSV-timelocked exit from the output of a flow step: if the operator
+/// never proceeds, `key` alone can spend the output `blocks` blocks after it
+/// confirmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefundLeaf {
+    key: XOnlyPublicKey,
+    blocks: u16,
+}
+
+impl RefundLeaf {
+    /// Fails if `blocks` is 0: the refund would race the flow from the
+    /// moment the output confirms.
+    pub fn new(key: XOnlyPublicKey, blocks: u16) -> Result<Self, String> {
+        if blocks == 0 {
+            return Err(
+                "the refund timelock needs at least 1 block".to_string()
+            );
+        }
+        Ok(Self { key, blocks })
+    }
+
+    /// The signers' aggregated key or the depositor's key.
+    pub fn key(&self) -> XOnlyPublicKey {
+        self.key
+    }
+
+    /// Relative timelock (BIP-68) in blocks.
+    pub fn blocks(&self) -> u16 {
+        self.blocks
+    }
+
+    /// `<blocks> OP_CSV OP_DROP <key> OP_CHECKSIG`
+    pub fn script(&self) -> ScriptBuf {
+        bitcoin::script::Builder::new()
+            .push_int(self.blocks.into())
+            .push_opcode(bitcoin::opcodes::all::OP_CSV)
+            .push_opcode(bitcoin::opcodes::all::OP_DROP)
+            .push_x_only_key(&self.key)
+            .push_opcode(bitcoin::opcodes::all::OP_CHECKSIG)
+            .into_script()
+    }
+
+    /// Input sequence satisfying the timelock.
+    pub fn sequence(&self) -> Sequence {
+        Sequence::from_height(self.blocks)
+    }
+}
+
+/// A UTXO deposited to the signers' funding address, see [`funding_address`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FundingUtxo {
+    pub outpoint: OutPoint,
+    /// Amount and scriptPubKey of the UTXO, as found on chain.
+    pub prevout: TxOut,
+}
+
+/// An output of the last transaction of a flow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayoutOutput {
+    /// The user withdrawal: whatever the fees and the fixed outputs leave,
+    /// paid to the receiver the flow is built for.
+    Receiver,
+    /// A fixed amount, e.g. the operator reward or a protocol fee.
+    Fixed(TxOut),
+}
+
+/// Outputs of the last transaction of a flow, in order. Presigned like the
+/// rest of the flow, so neither the operator nor the receiver can change
+/// the split.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayoutTemplate {
+    outputs: Vec<PayoutOutput>,
+}
+
+impl Default for PayoutTemplate {
+    /// Everything to the receiver.
+    fn default() -> Self {
+        Self {
+            outputs: vec![PayoutOutput::Receiver],
+        }
+    }
+}
+
+impl PayoutTemplate {
+    /// Fails unless `outputs` holds a single [`PayoutOutput::Receiver`],
+    /// every fixed output is above its dust limit and together they stay
+    /// within [`Amount::MAX_MONEY`].
+    pub fn new(outputs: Vec<PayoutOutput>) -> Result<Self, String> {
+        let receivers = outputs
+            .iter()
+            .filter(|output| **output == PayoutOutput::Receiver)
+            .count();
+        if receivers != 1 {
+            return Err(format!(
+                "a payout needs one receiver output, got {receivers}"
+            ));
+        }
+        let mut fixed = Amount::ZERO;
+        for (i, output) in outputs.iter().enumerate() {
+            let PayoutOutput::Fixed(output) = output else {
+                continue;
+            };
+            if output.value < output.script_pubkey.minimal_non_dust() {
+                return Err(format!(
+                    "payout output {i} of {} is below the dust limit",
+                    output.value
+                ));
+            }
+            fixed = fixed
+                .checked_add(output.value)
+                .filter(|fixed| *fixed <= Amount::MAX_MONEY)
+                .ok_or_else(|| {
+                    format!("payout outputs exceed {}", Amount::MAX_MONEY)
+                })?;
+        }
+        Ok(Self { outputs })
+    }
+
+    pub fn outputs(&self) -> &[PayoutOutput] {
+        &self.outputs
+    }
+
+    /// Total of the fixed outputs.
+    pub fn fixed_sat(&self) -> u64 {
+        self.outputs
+            .iter()
+            .map(|output| match output {
+                PayoutOutput::Receiver => 0,
+                PayoutOutput::Fixed(output) => output.value.to_sat(),
+            })
+            .sum()
+    }
+
+    /// Smallest value paying the fixed outputs and a non-dust withdrawal to
+    /// `receiver`.
+    pub fn min_value(&self, receiver: &ScriptBuf) -> u64 {
+        self.fixed_sat() + receiver.minimal_non_dust().to_sat()
+    }
+
+    /// The outputs splitting `value`, the receiver taking the rest.
+    pub fn resolve(
+        &self,
+        receiver: &ScriptBuf,
+        value: u64,
+    ) -> anyhow::Result<Vec<TxOut>> {
+        anyhow::ensure!(
+            value >= self.min_value(receiver),
+            "{value} sat cannot pay {} sat of fixed outputs and a non-dust \
+             withdrawal",
+            self.fixed_sat()
+        );
+        let withdrawal = Amount::from_sat(value - self.fixed_sat());
+        Ok(self
+            .outputs
+            .iter()
+            .map(|output| match output {
+                PayoutOutput::Receiver => TxOut {
+                    value: withdrawal,
+                    script_pubkey: receiver.clone(),
+                },
+                PayoutOutput::Fixed(output) => output.clone(),
+            })
+            .collect())
+    }
+}
+
 // --------------------------------------------------------------------
 // Transaction Creation Functions
 // --------------------------------------------------------------------
@@ -47,97 +306,381 @@ pub fn create_f1_tx(
         b_bits,
     );
 
-    // ── wrap in a Taproot tree & derive its address ─────────────────────
-    let x_only_pk = secp256k1::XOnlyPublicKey::from(*pk_signer);
-    let spend_info = TaprootBuilder::new()
-        .add_leaf(0, lock.clone())
-        .expect("valid leaf")
-        .finalize(secp, x_only_pk)
-        .unwrap();
+    let (tx_f1, spend_info, funding_script, funding_spend_info, msg) =
+        create_lock_tx_from_funding(
+            secp,
+            pk_signer,
+            network,
+            funding_outpoint,
+            funding_value_sat,
+            &lock,
+            &InternalKey::Signers,
+            None,
+            false,
+            fee_rate,
+        )?;
 
-    let tr_addr = Address::p2tr_tweaked(spend_info.output_key(), *network);
+    Ok((
+        tx_f1,
+        lock,
+        spend_info,
+        funding_script,
+        funding_spend_info,
+        msg,
+    ))
+}
+
+/// Creates the first transaction of a flow, spending the funding UTXO to the
+/// Taproot address of `lock` (next to `refund` if given), keyed by
+/// `internal_key`. With `anchor`, a pay-to-anchor output follows the lock,
+/// see [`create_anchor_child_tx`].
+///
+/// The UTXO is assumed to pay `funding_value_sat` to [`funding_address`].
+///
+/// Returns the transaction, the spend info of its output, the funding leaf
+/// script with its spend info (needed by [`finalize_f1_tx`]) and the sighash
+/// the signers have to sign.
+#[allow(clippy::too_many_arguments)]
+pub fn create_lock_tx_from_funding(
+    secp: &Secp256k1<secp256k1::All>,
+    pk_signer: &PublicKey,
+    network: &Network,
+    funding_outpoint: &OutPoint,
+    funding_value_sat: &u64,
+    lock: &ScriptBuf,
+    internal_key: &InternalKey,
+    refund: Option<&RefundLeaf>,
+    anchor: bool,
+    fee_rate: &u64,
+) -> anyhow::Result<(
+    Transaction,
+    TaprootSpendInfo,
+    ScriptBuf,
+    TaprootSpendInfo,
+    Message,
+)> {
+    let funding = FundingUtxo {
+        outpoint: *funding_outpoint,
+        prevout: TxOut {
+            value: Amount::from_sat(*funding_value_sat),
+            script_pubkey: funding_address(secp, pk_signer, *network)
+                .script_pubkey(),
+        },
+    };
+    let (tx, spend_info, funding_script, funding_spend_info, sighashes) =
+        create_lock_tx_from_utxos(
+            secp,
+            pk_signer,
+            network,
+            &[funding],
+            lock,
+            internal_key,
+            refund,
+            anchor,
+            fee_rate,
+        )?;
+    Ok((
+        tx,
+        spend_info,
+        funding_script,
+        funding_spend_info,
+        sighashes[0],
+    ))
+}
 
-    let fee_f1 = estimate_fee_vbytes(155, *fee_rate); // ~1 input + 1 output
-    let f1_output_value =
-        funding_value_sat.checked_sub(fee_f1).unwrap_or_else(|| {
-            panic!("function {funding_value_sat} too small for fee {fee_f1}")
-        });
+/// Same as [`create_lock_tx_from_funding`], spending every UTXO of `funding`
+/// with one input each.
+///
+/// Fails if a UTXO does not pay to [`funding_address`], or if together they
+/// cannot pay the fee. Returns one sighash per input, in input order.
+///
+/// Like every transaction spent by a presigned one, it is signed with the
+/// default sighash type: under ANYONECANPAY anyone could add an input and
+/// change the txid the next transaction commits to.
+#[allow(clippy::too_many_arguments)]
+pub fn create_lock_tx_from_utxos(
+    secp: &Secp256k1<secp256k1::All>,
+    pk_signer: &PublicKey,
+    network: &Network,
+    funding: &[FundingUtxo],
+    lock: &ScriptBuf,
+    internal_key: &InternalKey,
+    refund: Option<&RefundLeaf>,
+    anchor: bool,
+    fee_rate: &u64,
+) -> anyhow::Result<(
+    Transaction,
+    TaprootSpendInfo,
+    ScriptBuf,
+    TaprootSpendInfo,
+    Vec<Message>,
+)> {
+    anyhow::ensure!(!funding.is_empty(), "no funding UTXO");
+    let (funding_script, funding_spend_info) =
+        funding_spend_info(secp, pk_signer);
+    let funding_script_pubkey =
+        Address::p2tr_tweaked(funding_spend_info.output_key(), *network)
+            .script_pubkey();
+    if let Some(utxo) = funding
+        .iter()
+        .find(|utxo| utxo.prevout.script_pubkey != funding_script_pubkey)
+    {
+        anyhow::bail!(
+            "funding UTXO {} does not pay to the signers' funding address",
+            utxo.outpoint
+        );
+    }
+
+    // ── wrap in a Taproot tree & derive its address ─────────────────────
+    let spend_info =
+        lock_spend_info_with(secp, pk_signer, lock, internal_key, refund);
+    let tr_addr = Address::p2tr_tweaked(spend_info.output_key(), *network);
 
     let mut tx_f1 = Transaction {
         version: Version::TWO,
         lock_time: absolute::LockTime::ZERO,
-        input: vec![TxIn {
-            previous_output: *funding_outpoint,
-            script_sig: ScriptBuf::new(),
-            sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
-            witness: Witness::new(),
-        }],
+        input: funding
+            .iter()
+            .map(|utxo| TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
+                witness: Witness::new(),
+            })
+            .collect(),
         output: vec![TxOut {
-            value: Amount::from_sat(f1_output_value),
+            value: Amount::ZERO,
             script_pubkey: tr_addr.script_pubkey(),
         }],
     };
 
-    let xonly_pk = XOnlyPublicKey::from(*pk_signer);
-    let funding_script = get_funding_script(&x_only_pk);
+    let max_witness = max_spend_witness(
+        &[SCHNORR_SIG_LEN],
+        &funding_script,
+        &funding_spend_info,
+    );
+    let anchor_sat = if anchor {
+        add_anchor(&mut tx_f1, &max_witness)
+    } else {
+        0
+    };
+    let fee_f1 = fee_with_witness(&tx_f1, &max_witness, *fee_rate);
+    let funding_value_sat = funding
+        .iter()
+        .map(|utxo| utxo.prevout.value.to_sat())
+        .sum::<u64>();
+    let f1_output_value = funding_value_sat
+        .checked_sub(fee_f1 + anchor_sat)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "funding of {funding_value_sat} sat too small for fee {fee_f1}"
+            )
+        })?;
+    tx_f1.output[0].value = Amount::from_sat(f1_output_value);
 
     let leaf_hash =
         TapLeafHash::from_script(&funding_script, LeafVersion::TapScript);
-
-    // Build the tree with a single leaf
-    let funding_spend_info = TaprootBuilder::new()
-        .add_leaf(0, funding_script.clone())?
-        .finalize(secp, xonly_pk)
-        .unwrap();
-
-    // The scriptPubKey for this Taproot output and the address (for funding):
-    let funding_address =
-        Address::p2tr_tweaked(funding_spend_info.output_key(), *network);
-
+    let prevouts = funding
+        .iter()
+        .map(|utxo| utxo.prevout.clone())
+        .collect::<Vec<_>>();
     let mut cache = SighashCache::new(&mut tx_f1);
-    let sighash = cache.taproot_script_spend_signature_hash(
-        0,
-        &Prevouts::All(&[TxOut {
-            value: Amount::from_sat(*funding_value_sat),
-            script_pubkey: funding_address.script_pubkey(),
-        }]),
-        leaf_hash,
-        TapSighashType::Default,
-    )?;
-
-    let msg = Message::from_digest_slice(&sighash[..])?;
+    let sighashes = (0..funding.len())
+        .map(|i| {
+            let sighash = cache.taproot_script_spend_signature_hash(
+                i,
+                &Prevouts::All(&prevouts),
+                leaf_hash,
+                TapSighashType::Default,
+            )?;
+            Ok(Message::from_digest_slice(&sighash[..])?)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
     Ok((
         tx_f1,
-        lock,
         spend_info,
         funding_script,
         funding_spend_info,
-        msg,
+        sighashes,
     ))
 }
 
+/// Wraps `lock` as the single leaf of a Taproot tree keyed by the signers.
+pub fn lock_spend_info(
+    secp: &Secp256k1<secp256k1::All>,
+    pk_signer: &PublicKey,
+    lock: &ScriptBuf,
+) -> TaprootSpendInfo {
+    lock_spend_info_with(secp, pk_signer, lock, &InternalKey::Signers, None)
+}
+
+/// Same as [`lock_spend_info`], keyed by `internal_key` and with `refund`
+/// as a second leaf next to `lock` if given.
+pub fn lock_spend_info_with(
+    secp: &Secp256k1<secp256k1::All>,
+    pk_signer: &PublicKey,
+    lock: &ScriptBuf,
+    internal_key: &InternalKey,
+    refund: Option<&RefundLeaf>,
+) -> TaprootSpendInfo {
+    let x_only_pk = internal_key.x_only_public_key(secp, pk_signer);
+    match refund {
+        None => TaprootBuilder::new().add_leaf(0, lock.clone()),
+        Some(refund) => TaprootBuilder::new()
+            .add_leaf(1, lock.clone())
+            .and_then(|builder| builder.add_leaf(1, refund.script())),
+    }
+    .expect("valid leaves")
+    .finalize(secp, x_only_pk)
+    .unwrap()
+}
+
 pub fn get_funding_script(xonly_pk: &XOnlyPublicKey) -> ScriptBuf {
     bitcoin::script::Builder::new()
         .push_x_only_key(xonly_pk)
         .push_opcode(bitcoin::opcodes::all::OP_CHECKSIG)
         .into_script()
 }
+
+/// The funding leaf script of `pk_signer` and the Taproot tree holding it.
+pub fn funding_spend_info(
+    secp: &Secp256k1<secp256k1::All>,
+    pk_signer: &PublicKey,
+) -> (ScriptBuf, TaprootSpendInfo) {
+    let xonly_pk = XOnlyPublicKey::from(*pk_signer);
+    let funding_script = get_funding_script(&xonly_pk);
+    let spend_info = TaprootBuilder::new()
+        .add_leaf(0, funding_script.clone())
+        .expect("valid leaf")
+        .finalize(secp, xonly_pk)
+        .unwrap();
+    (funding_script, spend_info)
+}
+
+/// Address the deposits spent by the flows of `pk_signer` are sent to.
+pub fn funding_address(
+    secp: &Secp256k1<secp256k1::All>,
+    pk_signer: &PublicKey,
+    network: Network,
+) -> Address {
+    let (_, spend_info) = funding_spend_info(secp, pk_signer);
+    Address::p2tr_tweaked(spend_info.output_key(), network)
+}
+
+/// Zeroed witness with elements of `sizes`, as heavy as any witness with
+/// elements of these sizes.
+pub fn placeholder_witness(sizes: impl IntoIterator<Item = usize>) -> Witness {
+    let elements = sizes.into_iter().map(|size| vec![0; size]);
+    Witness::from_slice(&elements.collect::<Vec<_>>())
+}
+
+/// Placeholder for the heaviest spend of `script` in the tree `spend_info`:
+/// elements of `stack_sizes` (bottom of the stack first, see
+/// [`CollisionHash::max_witness_sizes`] and
+/// [`SignerCheck::max_witness_sizes`]), the script and its control block.
+pub fn max_spend_witness(
+    stack_sizes: &[usize],
+    script: &ScriptBuf,
+    spend_info: &TaprootSpendInfo,
+) -> Witness {
+    let control_block = spend_info
+        .control_block(&(script.clone(), LeafVersion::TapScript))
+        .expect("script is a leaf of the tree");
+    placeholder_witness(
+        stack_sizes
+            .iter()
+            .copied()
+            .chain([script.len(), control_block.size()]),
+    )
+}
+
+/// The keyless pay-to-anchor script `OP_1 <0x4e73>`, spendable by anyone
+/// with an empty witness.
+pub fn anchor_script() -> ScriptBuf {
+    bitcoin::script::Builder::new()
+        .push_opcode(bitcoin::opcodes::OP_TRUE)
+        .push_slice([0x4e, 0x73])
+        .into_script()
+}
+
+/// Appends a [`P2A_DUST_SAT`] anchor output to `tx`, making it v3 (TRUC)
+/// if it still fits in [`TRUC_MAX_VSIZE`] with a witness as heavy as
+/// `max_witness`. Returns the value locked in the anchor.
+fn add_anchor(tx: &mut Transaction, max_witness: &Witness) -> u64 {
+    tx.output.push(TxOut {
+        value: Amount::from_sat(P2A_DUST_SAT),
+        script_pubkey: anchor_script(),
+    });
+    let mut weighed = tx.clone();
+    for input in &mut weighed.input {
+        input.witness = max_witness.clone();
+    }
+    if weighed.vsize() <= TRUC_MAX_VSIZE {
+        tx.version = Version(3);
+    }
+    P2A_DUST_SAT
+}
+
+/// Fee for `tx` at `fee_rate` once each of its inputs carries a witness as
+/// heavy as `max_witness`.
+fn fee_with_witness(
+    tx: &Transaction,
+    max_witness: &Witness,
+    fee_rate: u64,
+) -> u64 {
+    let mut tx = tx.clone();
+    for input in &mut tx.input {
+        input.witness = max_witness.clone();
+    }
+    estimate_fee_vbytes(tx.vsize(), fee_rate)
+}
+
+/// Witness encoding of a signature made with `sighash_type`: the type byte
+/// follows the signature unless it is the default.
+fn encode_signature(sig: [u8; 64], sighash_type: TapSighashType) -> Vec<u8> {
+    let mut encoded = sig.to_vec();
+    if sighash_type != TapSighashType::Default {
+        encoded.push(sighash_type as u8);
+    }
+    encoded
+}
+
 pub fn finalize_f1_tx(
     tx: &mut Transaction,
     sig: LiftedSignature,
     spend_info: &TaprootSpendInfo,
     funding_script: &ScriptBuf,
 ) {
+    finalize_funding_inputs(tx, &[sig], spend_info, funding_script).unwrap()
+}
+
+/// Attach the witness of every funding input of `tx`, see
+/// [`create_lock_tx_from_utxos`]. `sigs` follow the input order.
+pub fn finalize_funding_inputs(
+    tx: &mut Transaction,
+    sigs: &[LiftedSignature],
+    spend_info: &TaprootSpendInfo,
+    funding_script: &ScriptBuf,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        sigs.len() == tx.input.len(),
+        "{} signatures for {} funding inputs",
+        sigs.len(),
+        tx.input.len()
+    );
     let control_block = spend_info
         .control_block(&(funding_script.clone(), LeafVersion::TapScript))
-        .unwrap();
+        .ok_or_else(|| anyhow::anyhow!("the output has no funding leaf"))?;
 
-    tx.input[0].witness = Witness::from_slice(&[
-        sig.serialize().to_vec(),
-        funding_script.to_bytes(),
-        control_block.serialize(),
-    ]);
+    for (input, sig) in tx.input.iter_mut().zip(sigs) {
+        input.witness = Witness::from_slice(&[
+            sig.serialize().to_vec(),
+            funding_script.to_bytes(),
+            control_block.serialize(),
+        ]);
+    }
+    Ok(())
 }
 
 /// Creates and signs tx_f2, spending the F1 output to the F2 Taproot address.
@@ -159,27 +702,65 @@ pub fn create_f2_tx(
         flow_id_prefix,
         b_bits,
     );
-    let x_only_pk = secp256k1::XOnlyPublicKey::from(*pk_signer);
-    let spend_info = TaprootBuilder::new()
-        .add_leaf(0, f2_lock.clone())
-        .expect("valid leaf")
-        .finalize(secp, x_only_pk)
-        .unwrap();
-    let tr_addr = Address::p2tr_tweaked(spend_info.output_key(), *network);
+    let mut stack_sizes = Blake3Hash::default()
+        .max_witness_sizes(&InputLayout::default(), b_bits);
+    stack_sizes.push(SCHNORR_SIG_LEN);
+    let max_witness = max_spend_witness(
+        &stack_sizes,
+        f1_lock,
+        &lock_spend_info(secp, pk_signer, f1_lock),
+    );
+    let (tx_f2, spend_info, msg) = create_lock_tx(
+        secp,
+        pk_signer,
+        network,
+        f1_tx,
+        f1_output_value,
+        f1_lock,
+        &max_witness,
+        &f2_lock,
+        &InternalKey::Signers,
+        None,
+        false,
+        fee_rate,
+    )?;
+    Ok((tx_f2, f2_lock, spend_info, msg))
+}
 
-    // Now the tx vsize is about 17093.
-    let fee_f2 = estimate_fee_vbytes(17093, *fee_rate); // 1 input P2TR + 1 output
-    let f2_output_value =
-        f1_output_value.checked_sub(fee_f2).unwrap_or_else(|| {
-            panic!("f1 output {f1_output_value} too small for f2 fee {fee_f2}")
-        });
+/// Creates an intermediate flow transaction, spending the `prev_lock` output
+/// of `prev_tx` to the Taproot address of `next_lock` (next to `refund` if
+/// given), keyed by `internal_key`.
+///
+/// The fee pays for the transaction once its input carries a witness as
+/// heavy as `max_witness`, see [`max_spend_witness`]. With `anchor`, a
+/// pay-to-anchor output follows the lock, see [`create_anchor_child_tx`].
+/// The signers sign with the default sighash type, see
+/// [`create_lock_tx_from_utxos`].
+#[allow(clippy::too_many_arguments)]
+pub fn create_lock_tx(
+    secp: &Secp256k1<secp256k1::All>,
+    pk_signer: &PublicKey,
+    network: &Network,
+    prev_tx: &Transaction,
+    prev_output_value: &u64,
+    prev_lock: &ScriptBuf,
+    max_witness: &Witness,
+    next_lock: &ScriptBuf,
+    internal_key: &InternalKey,
+    refund: Option<&RefundLeaf>,
+    anchor: bool,
+    fee_rate: &u64,
+) -> anyhow::Result<(Transaction, TaprootSpendInfo, Message)> {
+    let spend_info =
+        lock_spend_info_with(secp, pk_signer, next_lock, internal_key, refund);
+    let tr_addr = Address::p2tr_tweaked(spend_info.output_key(), *network);
 
-    let mut tx_f2 = Transaction {
+    let mut tx = Transaction {
         version: Version::TWO,
         lock_time: absolute::LockTime::ZERO,
         input: vec![TxIn {
             previous_output: OutPoint {
-                txid: f1_tx.compute_txid(),
+                txid: prev_tx.compute_txid(),
                 vout: 0,
             },
             script_sig: ScriptBuf::new(),
@@ -187,24 +768,39 @@ pub fn create_f2_tx(
             witness: Witness::new(),
         }],
         output: vec![TxOut {
-            value: Amount::from_sat(f2_output_value),
+            value: Amount::ZERO,
             script_pubkey: tr_addr.script_pubkey(),
         }],
     };
 
+    let anchor_sat = if anchor {
+        add_anchor(&mut tx, max_witness)
+    } else {
+        0
+    };
+    let fee = fee_with_witness(&tx, max_witness, *fee_rate);
+    let output_value = prev_output_value
+        .checked_sub(fee + anchor_sat)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "prev output {prev_output_value} too small for fee {fee}"
+            )
+        })?;
+    tx.output[0].value = Amount::from_sat(output_value);
+
     // Build the witness stack for the P2TR spend
-    let leaf_hash = TapLeafHash::from_script(f1_lock, LeafVersion::TapScript);
+    let leaf_hash = TapLeafHash::from_script(prev_lock, LeafVersion::TapScript);
 
-    let mut cache = SighashCache::new(&mut tx_f2);
+    let mut cache = SighashCache::new(&mut tx);
     let sighash = cache.taproot_script_spend_signature_hash(
         0,
-        &Prevouts::All(&[f1_tx.output[0].clone()]),
+        &Prevouts::All(&[prev_tx.output[0].clone()]),
         leaf_hash,
         TapSighashType::Default,
     )?;
 
     let msg = Message::from_digest_slice(&sighash[..])?;
-    Ok((tx_f2, f2_lock, spend_info, msg))
+    Ok((tx, spend_info, msg))
 }
 
 pub fn finalize_lock_tx(
@@ -214,26 +810,135 @@ pub fn finalize_lock_tx(
     lock: &ScriptBuf,
     x: &u32,
     nonce: &u64,
+) -> anyhow::Result<()> {
+    // B only matters for hashes whose witness depends on it
+    finalize_lock_tx_with_hash(
+        tx,
+        sig,
+        spend_info,
+        lock,
+        &Blake3Hash::default(),
+        0,
+        &Input::try_from(*x).map_err(anyhow::Error::msg)?,
+        nonce,
+    )
+}
+
+/// Same as [`finalize_lock_tx`] for a lock built with `hash` and any input.
+#[allow(clippy::too_many_arguments)]
+pub fn finalize_lock_tx_with_hash(
+    tx: &mut Transaction,
+    sig: LiftedSignature,
+    spend_info: &TaprootSpendInfo,
+    lock: &ScriptBuf,
+    hash: &dyn CollisionHash,
+    b_bits: usize,
+    x: &Input,
+    nonce: &u64,
+) -> anyhow::Result<()> {
+    finalize_lock_tx_with_signatures(
+        tx,
+        &StepSignatures::Aggregated(sig),
+        TapSighashType::Default,
+        spend_info,
+        lock,
+        hash,
+        b_bits,
+        x,
+        nonce,
+    )
+}
+
+/// Signatures satisfying the [`SignerCheck`] of a lock.
+#[derive(Debug, Clone)]
+pub enum StepSignatures {
+    /// The MuSig2 signature for [`SignerCheck::Aggregated`].
+    Aggregated(LiftedSignature),
+    /// One entry per key of [`SignerCheck::Threshold`], in key order, `None`
+    /// for the signers that did not sign.
+    Threshold(Vec<Option<secp256k1::schnorr::Signature>>),
+}
+
+impl StepSignatures {
+    /// Fails if the signatures cannot satisfy `signers`.
+    pub fn check(&self, signers: &SignerCheck) -> anyhow::Result<()> {
+        match (self, signers) {
+            (StepSignatures::Aggregated(_), SignerCheck::Aggregated(_)) => {
+                Ok(())
+            }
+            (
+                StepSignatures::Threshold(sigs),
+                SignerCheck::Threshold { keys, threshold },
+            ) => {
+                anyhow::ensure!(
+                    sigs.len() == keys.len(),
+                    "expected {} signature slots, got {}",
+                    keys.len(),
+                    sigs.len()
+                );
+                let signed = sigs.iter().flatten().count();
+                anyhow::ensure!(
+                    signed >= *threshold,
+                    "{signed} signatures, {threshold} required"
+                );
+                Ok(())
+            }
+            _ => anyhow::bail!("signatures do not match the signer check"),
+        }
+    }
+
+    /// Witness elements of signatures made with `sighash_type`, ordered for
+    /// the script to consume the signature of the first key first.
+    fn witness_elements(&self, sighash_type: TapSighashType) -> Vec<Vec<u8>> {
+        match self {
+            StepSignatures::Aggregated(sig) => {
+                vec![encode_signature(sig.serialize(), sighash_type)]
+            }
+            // An empty element makes OP_CHECKSIG(ADD) count no signature
+            StepSignatures::Threshold(sigs) => sigs
+                .iter()
+                .rev()
+                .map(|sig| {
+                    sig.map(|sig| {
+                        encode_signature(sig.serialize(), sighash_type)
+                    })
+                    .unwrap_or_default()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Same as [`finalize_lock_tx_with_hash`] for a lock with any [`SignerCheck`]
+/// and signatures made with `sighash_type`.
+#[allow(clippy::too_many_arguments)]
+pub fn finalize_lock_tx_with_signatures(
+    tx: &mut Transaction,
+    sigs: &StepSignatures,
+    sighash_type: TapSighashType,
+    spend_info: &TaprootSpendInfo,
+    lock: &ScriptBuf,
+    hash: &dyn CollisionHash,
+    b_bits: usize,
+    x: &Input,
+    nonce: &u64,
 ) -> anyhow::Result<()> {
     // Assemble witness
     let control_block = spend_info
         .control_block(&(lock.clone(), LeafVersion::TapScript))
-        .unwrap();
+        .ok_or_else(|| {
+            anyhow::anyhow!("the output has no leaf for the lock")
+        })?;
 
     // Encode input_value || nonce
-    let message = [
-        x.to_le_bytes(),
-        nonce.to_le_bytes()[0..4].try_into()?,
-        nonce.to_le_bytes()[4..8].try_into()?,
-    ]
-    .concat();
-
     let mut witness = Witness::new();
-    for limb in blake3_message_to_limbs(&message, 4) {
-        witness.push(encode_scriptnum(limb.into()));
+    for element in hash.witness(x, *nonce, b_bits) {
+        witness.push(element);
     }
 
-    witness.push(sig.serialize());
+    for element in sigs.witness_elements(sighash_type) {
+        witness.push(element);
+    }
     witness.push(lock.to_bytes());
     witness.push(control_block.serialize());
 
@@ -242,19 +947,50 @@ pub fn finalize_lock_tx(
 }
 
 /// Creates and signs the spending transaction, spending the F2 output to the receiver.
+///
+/// Like [`create_lock_tx`], the fee pays for a witness as heavy as
+/// `max_witness`, `anchor` adds a pay-to-anchor output and the signers sign
+/// with `sighash_type`.
 #[allow(clippy::too_many_arguments)]
 pub fn create_spending_tx(
     f2_tx: &Transaction,
     f2_output_value: &u64,
     receiver_addr: &Address,
     f2_lock: &ScriptBuf,
+    max_witness: &Witness,
+    sighash_type: TapSighashType,
+    anchor: bool,
     fee_rate: &u64,
 ) -> anyhow::Result<(Transaction, Message)> {
-    let fee_spending_tx = estimate_fee_vbytes(17082, *fee_rate); // 1 input P2TR + 1 output
-    let spending_output_value = f2_output_value
-        .checked_sub(fee_spending_tx)
-        .unwrap_or_else(|| panic!("f2 output {f2_output_value} too small for spending tx {fee_spending_tx}"));
+    create_payout_tx(
+        f2_tx,
+        f2_output_value,
+        &PayoutTemplate::default(),
+        receiver_addr,
+        f2_lock,
+        max_witness,
+        sighash_type,
+        anchor,
+        fee_rate,
+    )
+}
 
+/// Same as [`create_spending_tx`], paying the outputs of `payout` (followed
+/// by the anchor if any) with `receiver_addr` taking the withdrawal.
+#[allow(clippy::too_many_arguments)]
+pub fn create_payout_tx(
+    f2_tx: &Transaction,
+    f2_output_value: &u64,
+    payout: &PayoutTemplate,
+    receiver_addr: &Address,
+    f2_lock: &ScriptBuf,
+    max_witness: &Witness,
+    sighash_type: TapSighashType,
+    anchor: bool,
+    fee_rate: &u64,
+) -> anyhow::Result<(Transaction, Message)> {
+    let receiver = receiver_addr.script_pubkey();
+    // Amounts do not change the weight, the fee is known before the split
     let mut spending_tx = Transaction {
         version: Version::TWO,
         lock_time: absolute::LockTime::ZERO,
@@ -267,12 +1003,27 @@ pub fn create_spending_tx(
             sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
             witness: Witness::new(),
         }],
-        output: vec![TxOut {
-            value: Amount::from_sat(spending_output_value),
-            script_pubkey: receiver_addr.script_pubkey(),
-        }],
+        output: payout.resolve(&receiver, Amount::MAX_MONEY.to_sat())?,
     };
 
+    let anchor_sat = if anchor {
+        add_anchor(&mut spending_tx, max_witness)
+    } else {
+        0
+    };
+    let fee_spending_tx =
+        fee_with_witness(&spending_tx, max_witness, *fee_rate);
+    let payout_value = f2_output_value
+        .checked_sub(fee_spending_tx + anchor_sat)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "f2 output {f2_output_value} too small for spending tx fee \
+                 {fee_spending_tx}"
+            )
+        })?;
+    let outputs = payout.resolve(&receiver, payout_value)?;
+    spending_tx.output.splice(..outputs.len(), outputs);
+
     // Build the witness stack for the P2TR spend
     let leaf_hash = TapLeafHash::from_script(f2_lock, LeafVersion::TapScript);
 
@@ -282,14 +1033,233 @@ pub fn create_spending_tx(
             0,
             &Prevouts::All(&[f2_tx.output[0].clone()]),
             leaf_hash,
-            TapSighashType::Default,
+            sighash_type,
         )
-        .unwrap();
+        .map_err(|err| anyhow::anyhow!("spending tx sighash: {err}"))?;
 
     let msg = Message::from_digest_slice(&sighash[..])?;
     Ok((spending_tx, msg))
 }
 
+/// Creates the refund of output 0 of `prev_tx` through its `refund` leaf,
+/// paying `refund_addr`. Valid once the timelock has passed.
+///
+/// Returns the transaction and the sighash `refund.key()` has to sign.
+pub fn create_refund_tx(
+    prev_tx: &Transaction,
+    refund: &RefundLeaf,
+    refund_addr: &Address,
+    fee_rate: &u64,
+) -> anyhow::Result<(Transaction, Message)> {
+    let prev_output = &prev_tx.output[0];
+    let mut refund_tx = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: prev_tx.compute_txid(),
+                vout: 0,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: refund.sequence(),
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: refund_addr.script_pubkey(),
+        }],
+    };
+
+    let max_witness = placeholder_witness([
+        SCHNORR_SIG_LEN,
+        refund.script().len(),
+        REFUND_CONTROL_BLOCK_LEN,
+    ]);
+    let fee = fee_with_witness(&refund_tx, &max_witness, *fee_rate);
+    let refund_value = prev_output.value.to_sat().checked_sub(fee);
+    let refund_value = refund_value.ok_or_else(|| {
+        anyhow::anyhow!(
+            "output of {} too small for fee {fee}",
+            prev_output.value
+        )
+    })?;
+    refund_tx.output[0].value = Amount::from_sat(refund_value);
+
+    let leaf_hash =
+        TapLeafHash::from_script(&refund.script(), LeafVersion::TapScript);
+    let mut cache = SighashCache::new(&mut refund_tx);
+    let sighash = cache.taproot_script_spend_signature_hash(
+        0,
+        &Prevouts::All(std::slice::from_ref(prev_output)),
+        leaf_hash,
+        TapSighashType::Default,
+    )?;
+
+    let msg = Message::from_digest_slice(&sighash[..])?;
+    Ok((refund_tx, msg))
+}
+
+/// Creates the CPFP child of `parent`, spending its anchor output and the
+/// `fee_outpoint` UTXO (paying `fee_prevout`) to `change_addr`.
+///
+/// The child pays enough for the package of `parent` (whose own fee is
+/// `parent_fee`) and the child to reach `package_fee_rate`, and at least
+/// `package_fee_rate` for itself. `fee_max_witness` must be as heavy as the
+/// witness of the fee input, e.g. `placeholder_witness([64])` for a P2TR key
+/// spend. The fee input is left unsigned, the anchor input needs no
+/// witness.
+pub fn create_anchor_child_tx(
+    parent: &Transaction,
+    parent_fee: u64,
+    fee_outpoint: &OutPoint,
+    fee_prevout: &TxOut,
+    fee_max_witness: &Witness,
+    change_addr: &Address,
+    package_fee_rate: u64,
+) -> anyhow::Result<Transaction> {
+    let anchor_script = anchor_script();
+    let (vout, anchor) = parent
+        .output
+        .iter()
+        .enumerate()
+        .find(|(_, output)| output.script_pubkey == anchor_script)
+        .ok_or_else(|| anyhow::anyhow!("the parent has no anchor output"))?;
+
+    let mut child = Transaction {
+        // A v3 parent only accepts a v3 child
+        version: parent.version,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![
+            TxIn {
+                previous_output: *fee_outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            },
+            TxIn {
+                previous_output: OutPoint {
+                    txid: parent.compute_txid(),
+                    vout: vout as u32,
+                },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            },
+        ],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: change_addr.script_pubkey(),
+        }],
+    };
+
+    let child_vsize = {
+        let mut weighed = child.clone();
+        weighed.input[0].witness = fee_max_witness.clone();
+        weighed.vsize()
+    };
+    anyhow::ensure!(
+        parent.version != Version(3) || child_vsize <= TRUC_CHILD_MAX_VSIZE,
+        "child of {child_vsize} vbytes exceeds the TRUC limit of \
+         {TRUC_CHILD_MAX_VSIZE}"
+    );
+    let package_fee =
+        estimate_fee_vbytes(parent.vsize() + child_vsize, package_fee_rate);
+    let fee = package_fee
+        .saturating_sub(parent_fee)
+        .max(estimate_fee_vbytes(child_vsize, package_fee_rate));
+
+    let available = fee_prevout.value + anchor.value;
+    let change = available.to_sat().checked_sub(fee).ok_or_else(|| {
+        anyhow::anyhow!("fee input of {available} too small for fee {fee}")
+    })?;
+    child.output[0].value = Amount::from_sat(change);
+    Ok(child)
+}
+
+/// Adds the `fee_outpoint` UTXO (paying `fee_prevout`) as the last input
+/// of `tx`, whose input 0 was presigned with an ANYONECANPAY `sighash_type`
+/// and pays a fee of `presigned_fee`. The fee input is left unsigned,
+/// `fee_max_witness` must be as heavy as its witness.
+///
+/// With `SINGLE|ANYONECANPAY`, a change output to `change_addr` returns
+/// what is left once `tx` pays `fee_rate` (dust goes to the fee). With
+/// `ALL|ANYONECANPAY` the outputs are frozen and the whole fee input goes
+/// to the fee.
+///
+/// Adding an input changes the txid of `tx`, so it only works on a
+/// transaction that no presigned transaction spends.
+#[allow(clippy::too_many_arguments)]
+pub fn add_fee_input(
+    tx: &mut Transaction,
+    presigned_fee: u64,
+    sighash_type: TapSighashType,
+    fee_outpoint: &OutPoint,
+    fee_prevout: &TxOut,
+    fee_max_witness: &Witness,
+    change_addr: &Address,
+    fee_rate: u64,
+) -> anyhow::Result<()> {
+    let mut bumped = tx.clone();
+    bumped.input.push(TxIn {
+        previous_output: *fee_outpoint,
+        script_sig: ScriptBuf::new(),
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        witness: fee_max_witness.clone(),
+    });
+    match sighash_type {
+        TapSighashType::AllPlusAnyoneCanPay => {}
+        TapSighashType::SinglePlusAnyoneCanPay => {
+            bumped.output.push(TxOut {
+                value: Amount::ZERO,
+                script_pubkey: change_addr.script_pubkey(),
+            });
+        }
+        _ => anyhow::bail!("{sighash_type} does not let other inputs join"),
+    }
+
+    let available = presigned_fee + fee_prevout.value.to_sat();
+    let fee = estimate_fee_vbytes(bumped.vsize(), fee_rate);
+    let left = available.checked_sub(fee).ok_or_else(|| {
+        anyhow::anyhow!(
+            "fee input of {} too small for fee {fee}",
+            fee_prevout.value
+        )
+    })?;
+    if sighash_type == TapSighashType::SinglePlusAnyoneCanPay {
+        let change = bumped.output.last_mut().unwrap();
+        if left < change.script_pubkey.minimal_non_dust().to_sat() {
+            bumped.output.pop();
+        } else {
+            change.value = Amount::from_sat(left);
+        }
+    }
+
+    bumped.input.last_mut().unwrap().witness = Witness::new();
+    *tx = bumped;
+    Ok(())
+}
+
+/// Attach the refund witness `[sig, refund script, control block]`,
+/// `spend_info` being the tree of the refunded output.
+pub fn finalize_refund_tx(
+    tx: &mut Transaction,
+    sig: &secp256k1::schnorr::Signature,
+    spend_info: &TaprootSpendInfo,
+    refund: &RefundLeaf,
+) -> anyhow::Result<()> {
+    let script = refund.script();
+    let control_block = spend_info
+        .control_block(&(script.clone(), LeafVersion::TapScript))
+        .ok_or_else(|| anyhow::anyhow!("the output has no refund leaf"))?;
+
+    tx.input[0].witness = Witness::from_slice(&[
+        sig.serialize().to_vec(),
+        script.to_bytes(),
+        control_block.serialize(),
+    ]);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -369,6 +1339,18 @@ mod tests {
         }
     }
 
+    /// Heaviest witness of a spend of the `u32` BLAKE3 `lock`.
+    fn legacy_max_witness(
+        lock: &ScriptBuf,
+        spend_info: &TaprootSpendInfo,
+        b_bits: usize,
+    ) -> Witness {
+        let mut stack_sizes = Blake3Hash::default()
+            .max_witness_sizes(&InputLayout::default(), b_bits);
+        stack_sizes.push(SCHNORR_SIG_LEN);
+        max_spend_witness(&stack_sizes, lock, spend_info)
+    }
+
     #[derive(Debug, Clone)]
     struct TxFixture {
         tx: Transaction,
@@ -489,6 +1471,7 @@ mod tests {
         let TxContext {
             sk_signers,
             fee_rate,
+            b,
             x,
             nonce,
             receiver_addr,
@@ -507,6 +1490,9 @@ mod tests {
             &tx_f2.output[0].value.to_sat(),
             receiver_addr,
             f2_lock,
+            &legacy_max_witness(f2_lock, f2_spend_info, *b),
+            TapSighashType::Default,
+            false,
             fee_rate,
         )
         .unwrap();
@@ -553,6 +1539,7 @@ mod tests {
         let TxContext {
             sk_signers,
             fee_rate,
+            b,
             x,
             nonce,
             receiver_addr,
@@ -573,6 +1560,9 @@ mod tests {
             &tx_f2.output[0].value.to_sat(),
             receiver_addr,
             &f2_lock,
+            &legacy_max_witness(&f2_lock, &f2_spend_info, *b),
+            TapSighashType::Default,
+            false,
             fee_rate,
         )?;
         let final_sig = simulate_musig2(sk_signers, &message).unwrap();
@@ -605,4 +1595,199 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_anchor_truc_limit() {
+        let secp = Secp256k1::new();
+        let keypair =
+            secp256k1::Keypair::from_seckey_slice(&secp, &[7; 32]).unwrap();
+        let lock = get_funding_script(&keypair.x_only_public_key().0);
+        let prev_tx = Transaction {
+            version: Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let create = |witness_len| {
+            create_lock_tx(
+                &secp,
+                &keypair.public_key(),
+                &Network::Regtest,
+                &prev_tx,
+                &100_000,
+                &lock,
+                &placeholder_witness([witness_len]),
+                &lock,
+                &InternalKey::Signers,
+                None,
+                true,
+                &1,
+            )
+            .unwrap()
+            .0
+        };
+
+        // Too heavy for TRUC, like a BLAKE3 step: the anchor stays on v2
+        let tx = create(40_000);
+        assert_eq!(tx.version, Version::TWO);
+        assert!(tx.vsize() < 100_000 - tx.output[0].value.to_sat() as usize);
+        let tx = create(100);
+        assert_eq!(tx.version, Version(3));
+        assert_eq!(
+            tx.output[1].script_pubkey.as_bytes(),
+            [0x51, 0x02, 0x4e, 0x73]
+        );
+        let mut signed = tx.clone();
+        signed.input[0].witness = placeholder_witness([100]);
+        assert_eq!(
+            tx.output[0].value.to_sat(),
+            100_000 - P2A_DUST_SAT - signed.vsize() as u64
+        );
+    }
+
+    #[test]
+    fn test_nums_internal_key() {
+        let secp = Secp256k1::new();
+        // BIP-341: H = lift_x(SHA256(G)), G uncompressed
+        let generator = secp256k1::constants::GENERATOR_X
+            .iter()
+            .chain(&secp256k1::constants::GENERATOR_Y);
+        let g_uncompressed = [4].iter().chain(generator).copied();
+        let digest = bitcoin::hashes::sha256::Hash::hash(
+            &g_uncompressed.collect::<Vec<u8>>(),
+        );
+        assert_eq!(digest.to_byte_array(), NUMS_X);
+
+        let (_, pk_signer) = generate_keys::<1>()[0];
+        let pk_signer: PublicKey = inner_from(pk_signer);
+        let key = |internal_key: InternalKey| {
+            internal_key.x_only_public_key(&secp, &pk_signer)
+        };
+        assert_eq!(key(InternalKey::Signers), pk_signer.x_only_public_key().0);
+        assert!(InternalKey::is_unspendable(
+            &secp,
+            &key(InternalKey::Nums),
+            None
+        ));
+
+        let InternalKey::TweakedNums(tweak) = InternalKey::random_nums() else {
+            unreachable!()
+        };
+        let tweaked = key(InternalKey::TweakedNums(tweak));
+        assert_ne!(tweaked, key(InternalKey::Nums));
+        assert!(InternalKey::is_unspendable(&secp, &tweaked, Some(&tweak)));
+        assert!(!InternalKey::is_unspendable(&secp, &tweaked, None));
+        let other = Scalar::from_be_bytes([1; 32]).unwrap();
+        assert!(!InternalKey::is_unspendable(&secp, &tweaked, Some(&other)));
+        assert!(!InternalKey::is_unspendable(
+            &secp,
+            &key(InternalKey::Signers),
+            None
+        ));
+    }
+
+    #[test]
+    fn test_finalize_foreign_lock() {
+        let secp = Secp256k1::new();
+        let (_, pk_signer) = generate_keys::<1>()[0];
+        let pk_signer: PublicKey = inner_from(pk_signer);
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, ScriptBuf::from_bytes(vec![0x51]))
+            .unwrap()
+            .finalize(&secp, pk_signer.x_only_public_key().0)
+            .unwrap();
+        let mut tx = Transaction {
+            version: Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![],
+        };
+
+        let result = finalize_lock_tx_with_signatures(
+            &mut tx,
+            &StepSignatures::Threshold(vec![]),
+            TapSighashType::Default,
+            &spend_info,
+            &ScriptBuf::from_bytes(vec![0x52]),
+            &Blake3Hash::default(),
+            16,
+            &Input::try_from(1).unwrap(),
+            &0,
+        );
+        assert!(result.is_err());
+        assert!(tx.input[0].witness.is_empty());
+    }
+
+    #[test]
+    fn test_payout_template() {
+        let receiver =
+            Address::from_str("bcrt1qz3fps2lxvrp5rqj8ucsqrzjx2c3md9gawqr3l6")
+                .unwrap()
+                .assume_checked()
+                .script_pubkey();
+        let fixed = |sat| {
+            PayoutOutput::Fixed(TxOut {
+                value: Amount::from_sat(sat),
+                script_pubkey: receiver.clone(),
+            })
+        };
+
+        assert!(PayoutTemplate::new(vec![fixed(1_000)]).is_err());
+        assert!(
+            PayoutTemplate::new(vec![
+                PayoutOutput::Receiver,
+                PayoutOutput::Receiver
+            ])
+            .is_err()
+        );
+        // P2WPKH outputs are dust below 294 sat
+        assert!(
+            PayoutTemplate::new(vec![PayoutOutput::Receiver, fixed(293)])
+                .is_err()
+        );
+        let max = Amount::MAX_MONEY.to_sat();
+        assert!(
+            PayoutTemplate::new(vec![PayoutOutput::Receiver, fixed(max)])
+                .is_ok()
+        );
+        assert!(
+            PayoutTemplate::new(vec![
+                PayoutOutput::Receiver,
+                fixed(max),
+                fixed(294)
+            ])
+            .is_err()
+        );
+        assert!(
+            PayoutTemplate::new(vec![
+                PayoutOutput::Receiver,
+                fixed(u64::MAX),
+                fixed(u64::MAX)
+            ])
+            .is_err()
+        );
+
+        let payout = PayoutTemplate::new(vec![
+            fixed(5_000),
+            PayoutOutput::Receiver,
+            fixed(294),
+        ])
+        .unwrap();
+        assert_eq!(payout.fixed_sat(), 5_294);
+        assert_eq!(payout.min_value(&receiver), 5_588);
+        let values = |value| {
+            payout
+                .resolve(&receiver, value)
+                .unwrap()
+                .iter()
+                .map(|output| output.value.to_sat())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(5_588), vec![5_000, 294, 294]);
+        assert_eq!(values(100_000), vec![5_000, 94_706, 294]);
+        assert!(payout.resolve(&receiver, 5_587).is_err());
+    }
 }
@@ -212,7 +212,7 @@ impl NonceSearchProgress {
         }
     }
 
-    pub fn success(&self, flow_id: u32, nonce: u64) {
+    pub fn success(&self, flow_id: u128, nonce: u64) {
         let elapsed = self.start_time.elapsed();
         let hash_rate = if elapsed.as_secs() > 0 {
             nonce as f64 / elapsed.as_secs_f64()